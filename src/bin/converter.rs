@@ -2,7 +2,6 @@ use std::env;
 use std::fs::File;
 use std::io::BufWriter;
 //use std::time::Instant;
-use image::ColorType;
 
 extern crate imagepipe;
 extern crate rawloader;
@@ -52,7 +51,11 @@ fn main() {
   println!("crops are {:?}", image.crops);
 */
 
-  let decoded = match imagepipe::simple_decode_8bit(file, 0, 0) {
+  let mut pipeline = match imagepipe::Pipeline::new_from_file(file) {
+    Ok(pipeline) => pipeline,
+    Err(e) => error(&e),
+  };
+  let decoded = match pipeline.output_8bit(None) {
     Ok(img) => img,
     Err(e) => error(&e),
   };
@@ -63,10 +66,9 @@ fn main() {
       error(format!("Error: {}", e).as_ref())
     }
   };
-  let mut f = BufWriter::new(uf);
+  let f = BufWriter::new(uf);
 
-  let mut jpg_encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut f, 90);
-  jpg_encoder
-    .encode(&decoded.data, decoded.width as u32, decoded.height as u32, ColorType::Rgb8)
-    .expect("Encoding image in JPEG format failed.");
+  if let Err(e) = decoded.write_jpeg(&pipeline.output_metadata(), 90, f) {
+    error(&e);
+  }
 }
@@ -1,5 +1,6 @@
 extern crate rayon;
 use self::rayon::prelude::*;
+use crate::scaling::Resampling;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct OpBuffer {
@@ -64,10 +65,19 @@ impl OpBuffer {
     topright: (isize, isize),
     bottomleft: (isize, isize),
     width: usize,
-    height: usize) -> OpBuffer {
-
-    let newdata = crate::scaling::transform_buffer(&self.data, self.width, self.height,
-      topleft, topright, bottomleft, width, height, self.colors, None);
+    height: usize,
+    resampling: Resampling) -> OpBuffer {
+
+    let geometry = crate::scaling::TransformGeometry {
+      width: self.width,
+      height: self.height,
+      topleft,
+      topright,
+      bottomleft,
+      nwidth: width,
+      nheight: height,
+    };
+    let newdata = crate::scaling::transform_buffer(&self.data, geometry, self.colors, None, resampling);
 
     Self {
       width,
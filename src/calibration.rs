@@ -0,0 +1,208 @@
+use crate::hasher::*;
+use rawloader::{RawImage, RawImageData, CFA};
+use std::sync::Arc;
+
+/// A dark frame or flat field matched to the image being processed
+///
+/// Dark frames are stored as the value above the black level for each pixel
+/// and flat fields as the gain of each pixel relative to the average of its
+/// color, both in the uncropped layout of the raw data.
+#[derive(Debug, Clone)]
+pub struct CalibrationFrame {
+  pub data: Arc<Vec<f32>>,
+  pub hash: BufHash,
+}
+
+impl CalibrationFrame {
+  fn new(data: Vec<f32>) -> CalibrationFrame {
+    let mut hasher = BufHasher::new();
+    hasher.from_serialize(&data);
+    CalibrationFrame {
+      data: Arc::new(data),
+      hash: hasher.result(),
+    }
+  }
+}
+
+/// Calibration frames applied to the raw data before it's normalized
+#[derive(Debug, Clone, Default)]
+pub struct Calibration {
+  pub dark: Option<CalibrationFrame>,
+  pub flat: Option<CalibrationFrame>,
+}
+
+// Layout of the raw data needed to know the color of each value
+struct Layout<'a> {
+  width: usize,
+  cpp: usize,
+  cfa: &'a CFA,
+  blacklevels: [f32;4],
+}
+
+impl<'a> Layout<'a> {
+  fn new(img: &'a RawImage, blacklevels: [f32;4]) -> Layout<'a> {
+    Layout {
+      width: img.width,
+      cpp: img.cpp,
+      cfa: &img.cfa,
+      blacklevels,
+    }
+  }
+
+  // Calibration frames are separate files so they use their own black levels
+  fn from_raw(img: &'a RawImage) -> Layout<'a> {
+    let b = img.blacklevels;
+    Self::new(img, [b[0] as f32, b[1] as f32, b[2] as f32, b[3] as f32])
+  }
+
+  #[inline(always)]
+  fn color(&self, pos: usize) -> usize {
+    if self.cpp > 1 {
+      pos % self.cpp
+    } else if self.cfa.is_valid() {
+      self.cfa.color_at(pos / self.width, pos % self.width)
+    } else {
+      0
+    }
+  }
+}
+
+fn raw_values(data: &RawImageData) -> Vec<f32> {
+  match data {
+    RawImageData::Integer(data) => data.iter().map(|v| *v as f32).collect(),
+    RawImageData::Float(data) => data.clone(),
+  }
+}
+
+fn check_match(img: &RawImage, frame: &RawImage, name: &str) -> Result<(), String> {
+  if img.width != frame.width || img.height != frame.height || img.cpp != frame.cpp {
+    return Err(format!("imagepipe: {} is {}x{}x{} but the image is {}x{}x{}", name,
+      frame.width, frame.height, frame.cpp, img.width, img.height, img.cpp))
+  }
+  if img.cfa.to_string() != frame.cfa.to_string() {
+    return Err(format!("imagepipe: {} has CFA {} but the image has {}", name, frame.cfa, img.cfa))
+  }
+  Ok(())
+}
+
+fn dark_values(mut values: Vec<f32>, layout: &Layout) -> Vec<f32> {
+  for (pos, v) in values.iter_mut().enumerate() {
+    *v = (*v - layout.blacklevels[layout.color(pos)]).max(0.0);
+  }
+  values
+}
+
+fn flat_gains(mut values: Vec<f32>, layout: &Layout) -> Vec<f32> {
+  let mut sums = [0.0f64; 4];
+  let mut counts = [0u64; 4];
+  for (pos, v) in values.iter_mut().enumerate() {
+    let color = layout.color(pos);
+    *v = (*v - layout.blacklevels[color]).max(0.0);
+    sums[color] += *v as f64;
+    counts[color] += 1;
+  }
+  for (pos, v) in values.iter_mut().enumerate() {
+    let color = layout.color(pos);
+    let mean = (sums[color] / counts[color] as f64) as f32;
+    // Don't blow up pixels that got almost no light in the flat
+    *v = if mean > 0.0 {(*v / mean).max(0.01)} else {1.0};
+  }
+  values
+}
+
+fn calibrate(values: &mut [f32], layout: &Layout, dark: Option<&[f32]>, flat: Option<&[f32]>) {
+  for (pos, v) in values.iter_mut().enumerate() {
+    let black = layout.blacklevels[layout.color(pos)];
+    let mut signal = *v - black;
+    if let Some(dark) = dark {
+      signal -= dark[pos];
+    }
+    if let Some(flat) = flat {
+      signal /= flat[pos];
+    }
+    // Keep the result in raw units so the normal levels still apply
+    *v = signal + black;
+  }
+}
+
+impl Calibration {
+  pub fn is_empty(&self) -> bool {
+    self.dark.is_none() && self.flat.is_none()
+  }
+
+  /// Use a dark frame taken with the same camera settings as the image
+  pub fn set_dark_frame(&mut self, img: &RawImage, dark: &RawImage) -> Result<(), String> {
+    check_match(img, dark, "dark frame")?;
+    let values = dark_values(raw_values(&dark.data), &Layout::from_raw(dark));
+    self.dark = Some(CalibrationFrame::new(values));
+    Ok(())
+  }
+
+  /// Use a flat field taken with the same camera and optics as the image
+  pub fn set_flat_field(&mut self, img: &RawImage, flat: &RawImage) -> Result<(), String> {
+    check_match(img, flat, "flat field")?;
+    let values = flat_gains(raw_values(&flat.data), &Layout::from_raw(flat));
+    self.flat = Some(CalibrationFrame::new(values));
+    Ok(())
+  }
+
+  pub(crate) fn hash(&self, hasher: &mut BufHasher) {
+    if !self.is_empty() {
+      let dark = self.dark.as_ref().map(|f| f.hash);
+      let flat = self.flat.as_ref().map(|f| f.hash);
+      hasher.from_serialize(&(dark, flat));
+    }
+  }
+
+  /// Calibrated raw data for the image, or None if there's nothing to do
+  ///
+  /// The black levels are the ones the image is normalized with, which may
+  /// have been measured instead of coming from the camera.
+  pub(crate) fn apply(&self, img: &RawImage, blacklevels: [f32;4]) -> Option<RawImageData> {
+    if self.is_empty() {
+      return None
+    }
+    let mut values = raw_values(&img.data);
+    let dark = self.dark.as_ref().map(|f| &f.data[..]);
+    let flat = self.flat.as_ref().map(|f| &f.data[..]);
+    calibrate(&mut values, &Layout::new(img, blacklevels), dark, flat);
+    Some(RawImageData::Float(values))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn dark_and_flat() {
+    let cfa = CFA::new("RGGB");
+    let layout = Layout {
+      width: 2,
+      cpp: 1,
+      cfa: &cfa,
+      blacklevels: [10.0, 20.0, 30.0, 0.0],
+    };
+    let dark = dark_values(vec![12.0, 20.0, 25.0, 30.0, 10.0, 24.0, 20.0, 30.0], &layout);
+    assert_eq!(dark, vec![2.0, 0.0, 5.0, 0.0, 0.0, 4.0, 0.0, 0.0]);
+
+    let flat = flat_gains(vec![135.0, 220.0, 220.0, 230.0, 85.0, 220.0, 220.0, 230.0], &layout);
+    assert_eq!(flat, vec![1.25, 1.0, 1.0, 1.0, 0.75, 1.0, 1.0, 1.0]);
+
+    let mut values = vec![62.0, 60.0, 65.0, 70.0, 70.0, 64.0, 60.0, 70.0];
+    calibrate(&mut values, &layout, Some(&dark), Some(&flat));
+    assert_eq!(values, vec![50.0, 60.0, 60.0, 70.0, 90.0, 60.0, 60.0, 70.0]);
+  }
+
+  #[test]
+  fn hash_changes() {
+    let mut calibration = Calibration::default();
+    let mut hasher = BufHasher::new();
+    calibration.hash(&mut hasher);
+    let empty = hasher.result();
+    calibration.dark = Some(CalibrationFrame::new(vec![1.0]));
+    let mut hasher = BufHasher::new();
+    calibration.hash(&mut hasher);
+    assert_ne!(hasher.result(), empty);
+  }
+}
@@ -0,0 +1,106 @@
+use crate::buffer::*;
+use rayon::prelude::*;
+
+/// Per pixel flags marking which output pixels get clipped
+///
+/// The data is a Vec<u8> with width*height elements, one per pixel of the final
+/// image, each an OR of the flags below. A pixel can be both out of gamut and
+/// clipping in the highlights of some channels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClippingMask {
+  pub width: usize,
+  pub height: usize,
+  pub data: Vec<u8>,
+}
+
+impl ClippingMask {
+  /// All channels are at or below black
+  pub const SHADOWS: u8 = 1 << 0;
+  /// The red channel is at or above white
+  pub const HIGHLIGHTS_RED: u8 = 1 << 1;
+  /// The green channel is at or above white
+  pub const HIGHLIGHTS_GREEN: u8 = 1 << 2;
+  /// The blue channel is at or above white
+  pub const HIGHLIGHTS_BLUE: u8 = 1 << 3;
+  /// The color can't be represented in the output color space
+  pub const OUT_OF_GAMUT: u8 = 1 << 4;
+
+  /// Calculate the mask from a linear RGB buffer that hasn't been clamped yet
+  pub fn from_linear(buf: &OpBuffer) -> ClippingMask {
+    Self::from_linear_white(buf, 1.0)
+  }
+
+  /// Calculate the mask for an output that clips highlights at a given linear
+  /// value, such as the HDR encodings that go above 1.0
+  pub fn from_linear_white(buf: &OpBuffer, white: f32) -> ClippingMask {
+    assert_eq!(buf.colors, 3);
+    let mut data = vec![0u8; buf.width*buf.height];
+    data.par_chunks_mut(buf.width).enumerate().for_each(|(row, line)| {
+      let from = row*buf.width*3;
+      let inb = &buf.data[from..from+buf.width*3];
+      for (o, pix) in line.iter_mut().zip(inb.chunks_exact(3)) {
+        *o = Self::flags(pix, white);
+      }
+    });
+
+    ClippingMask {
+      width: buf.width,
+      height: buf.height,
+      data,
+    }
+  }
+
+  #[inline(always)]
+  fn flags(pix: &[f32], white: f32) -> u8 {
+    let max = pix[0].max(pix[1]).max(pix[2]);
+    let min = pix[0].min(pix[1]).min(pix[2]);
+
+    let mut flags = 0;
+    if max <= 0.0 {
+      flags |= Self::SHADOWS;
+    } else if min < 0.0 {
+      // There's light in the pixel but some channel would need to be negative
+      flags |= Self::OUT_OF_GAMUT;
+    }
+    if pix[0] >= white { flags |= Self::HIGHLIGHTS_RED; }
+    if pix[1] >= white { flags |= Self::HIGHLIGHTS_GREEN; }
+    if pix[2] >= white { flags |= Self::HIGHLIGHTS_BLUE; }
+    flags
+  }
+
+  /// Check if any of the given flags are set for the pixel at x/y
+  pub fn is_set(&self, x: usize, y: usize, flags: u8) -> bool {
+    self.data[y*self.width+x] & flags != 0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn flags() {
+    let mut buf = OpBuffer::new(5, 1, 3, false);
+    buf.data.copy_from_slice(&[
+      0.5, 0.5, 0.5,
+      0.0, 0.0, -0.1,
+      1.0, 0.5, 0.2,
+      1.2, 1.0, 1.5,
+      0.8, -0.2, 0.3,
+    ]);
+    let mask = ClippingMask::from_linear(&buf);
+    assert_eq!(mask.data, vec![
+      0,
+      ClippingMask::SHADOWS,
+      ClippingMask::HIGHLIGHTS_RED,
+      ClippingMask::HIGHLIGHTS_RED | ClippingMask::HIGHLIGHTS_GREEN | ClippingMask::HIGHLIGHTS_BLUE,
+      ClippingMask::OUT_OF_GAMUT,
+    ]);
+    assert!(mask.is_set(4, 0, ClippingMask::OUT_OF_GAMUT | ClippingMask::SHADOWS));
+    assert!(!mask.is_set(0, 0, 0xff));
+
+    let mask = ClippingMask::from_linear_white(&buf, 1.2);
+    assert_eq!(mask.data[2], 0);
+    assert_eq!(mask.data[3], ClippingMask::HIGHLIGHTS_RED | ClippingMask::HIGHLIGHTS_BLUE);
+  }
+}
@@ -14,6 +14,12 @@ lazy_static! {
     [SRGB_D65_33[1][0], SRGB_D65_33[1][1], SRGB_D65_33[1][2], 0.0],
     [SRGB_D65_33[2][0], SRGB_D65_33[2][1], SRGB_D65_33[2][2], 0.0],
   ];
+  pub static ref REC2020_D65_33: [[f32;3];3] = [
+    [0.636958,  0.1446169, 0.168881],
+    [0.2627002, 0.6779981, 0.0593017],
+    [0.0000000, 0.0280727, 1.0609851],
+  ];
+  pub static ref XYZ_REC2020_33: [[f32;3];3] = inverse(*REC2020_D65_33);
 }
 
 // FIXME: when float math is allowed in const fn get rid of lazy_static!
@@ -46,6 +52,17 @@ pub fn camera_to_lab(mul: [f32;4], cmatrix: [[f32;4];3], pixin: &[f32]) -> (f32,
     let b = (pixin[2] * mul[2]).min(1.0);
     let e = (pixin[3] * mul[3]).min(1.0);
 
+    camera_to_lab_unclipped([1.0, 1.0, 1.0, 1.0], cmatrix, &[r, g, b, e])
+}
+
+/// Same as camera_to_lab but keeping values above 1.0 for HDR sources
+#[inline(always)]
+pub fn camera_to_lab_unclipped(mul: [f32;4], cmatrix: [[f32;4];3], pixin: &[f32]) -> (f32, f32, f32) {
+    let r = pixin[0] * mul[0];
+    let g = pixin[1] * mul[1];
+    let b = pixin[2] * mul[2];
+    let e = pixin[3] * mul[3];
+
     // Calculate XYZ by applying the camera matrix
     let x = r * cmatrix[0][0] + g * cmatrix[0][1] + b * cmatrix[0][2] + e * cmatrix[0][3];
     let y = r * cmatrix[1][0] + g * cmatrix[1][1] + b * cmatrix[1][2] + e * cmatrix[1][3];
@@ -152,6 +169,36 @@ pub fn apply_srgb_gamma(v: f32) -> f32 {
   SRGB_GAMMA_TRANSFORM.lookup(v)
 }
 
+// Peak luminance of the PQ curve in cd/m^2
+pub static PQ_MAX_NITS: f32 = 10000.0;
+
+/// Apply the Rec.2100 PQ curve (SMPTE ST 2084) to a luminance relative to
+/// PQ_MAX_NITS, so 1.0 is 10000 cd/m^2
+pub fn apply_pq(v: f32) -> f32 {
+  let m1 = 2610.0 / 16384.0;
+  let m2 = 2523.0 / 4096.0 * 128.0;
+  let c1 = 3424.0 / 4096.0;
+  let c2 = 2413.0 / 4096.0 * 32.0;
+  let c3 = 2392.0 / 4096.0 * 32.0;
+
+  let ym1 = v.clamp(0.0, 1.0).powf(m1);
+  ((c1 + c2 * ym1) / (1.0 + c3 * ym1)).powf(m2)
+}
+
+/// Apply the Rec.2100 HLG curve to a scene value where 1.0 is the peak
+pub fn apply_hlg(v: f32) -> f32 {
+  let a = 0.17883277;
+  let b = 1.0 - 4.0 * a;
+  let c = 0.5 - a * (4.0f32 * a).ln();
+
+  let v = v.clamp(0.0, 1.0);
+  if v <= 1.0 / 12.0 {
+    (3.0 * v).sqrt()
+  } else {
+    a * (12.0 * v - b).ln() + c
+  }
+}
+
 #[inline(always)]
 pub fn xyz_to_lab(x: f32, y: f32, z: f32) -> (f32,f32,f32) {
   let (xw, yw, zw) = *SRGB_D65_XYZ_WHITE;
@@ -401,6 +448,34 @@ mod tests {
     }
   }
 
+  #[test]
+  fn rec2020_white() {
+    let white = *SRGB_D65_XYZ_WHITE;
+    let m = *REC2020_D65_33;
+    for (row, expected) in m.iter().zip([white.0, white.1, white.2].iter()) {
+      let sum: f32 = row.iter().sum();
+      assert!((sum - expected).abs() < 0.001, "Got {} instead of {}", sum, expected);
+    }
+  }
+
+  #[test]
+  fn pq_reference_values() {
+    assert!(apply_pq(0.0) < 0.000001);
+    assert!((apply_pq(1.0) - 1.0).abs() < 0.0001);
+    // 100 cd/m^2 is roughly half of the PQ range
+    assert!((apply_pq(100.0 / PQ_MAX_NITS) - 0.5081).abs() < 0.001);
+    // and 1000 cd/m^2 roughly three quarters
+    assert!((apply_pq(1000.0 / PQ_MAX_NITS) - 0.7518).abs() < 0.001);
+  }
+
+  #[test]
+  fn hlg_reference_values() {
+    assert_eq!(apply_hlg(0.0), 0.0);
+    assert!((apply_hlg(1.0 / 12.0) - 0.5).abs() < 0.0001);
+    assert!((apply_hlg(1.0) - 1.0).abs() < 0.0001);
+    assert_eq!(apply_hlg(2.0), apply_hlg(1.0));
+  }
+
   use num_traits::ops::saturating::Saturating;
   use std::fmt::Debug;
   use std::cmp::PartialOrd;
@@ -0,0 +1,148 @@
+use crate::buffer::*;
+use rayon::prelude::*;
+use std::cmp;
+
+/// A histogram of one channel of an `OpBuffer` with values in the 0.0-1.0 range
+///
+/// Values outside of the range get counted in the first or last bin. Counting is
+/// done with integers so the result is always the same no matter how the work was
+/// split between threads, which keeps anything derived from it cacheable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+  pub bins: Vec<u32>,
+  pub count: u64,
+}
+
+#[inline(always)]
+pub(crate) fn bin_for(v: f32, nbins: usize) -> usize {
+  if v.is_nan() || v <= 0.0 {
+    0
+  } else {
+    cmp::min((v * nbins as f32) as usize, nbins-1)
+  }
+}
+
+pub(crate) fn add_bins(mut a: Vec<u32>, b: Vec<u32>) -> Vec<u32> {
+  for (x, y) in a.iter_mut().zip(b.iter()) {
+    *x += *y;
+  }
+  a
+}
+
+impl Histogram {
+  pub fn new(nbins: usize) -> Histogram {
+    Histogram {
+      bins: vec![0; nbins],
+      count: 0,
+    }
+  }
+
+  /// Calculate the histogram of a single channel of the buffer
+  ///
+  /// Asking for zero bins gives a single bin.
+  pub fn from_channel(buf: &OpBuffer, channel: usize, nbins: usize) -> Histogram {
+    assert!(channel < buf.colors);
+    let nbins = cmp::max(nbins, 1);
+    if buf.width == 0 || buf.height == 0 {
+      return Histogram::new(nbins)
+    }
+    let colors = buf.colors;
+    let bins = buf.data.par_chunks(buf.width*colors).map(|line| {
+      let mut bins = vec![0; nbins];
+      for pix in line.chunks_exact(colors) {
+        bins[bin_for(pix[channel], nbins)] += 1;
+      }
+      bins
+    }).reduce(|| vec![0; nbins], add_bins);
+
+    Histogram {
+      bins,
+      count: (buf.width*buf.height) as u64,
+    }
+  }
+
+  /// Calculate the histogram of the L channel of a Lab buffer
+  pub fn from_lab(buf: &OpBuffer, nbins: usize) -> Histogram {
+    Self::from_channel(buf, 0, nbins)
+  }
+
+  /// Find the value below which the given fraction (0.0-1.0) of the pixels fall
+  pub fn percentile(&self, fraction: f32) -> f32 {
+    if self.count == 0 {
+      return 0.0
+    }
+    let nbins = self.bins.len();
+    let target = (self.count as f64 * fraction as f64).ceil() as u64;
+    let mut sum = 0u64;
+    for (i, count) in self.bins.iter().enumerate() {
+      sum += *count as u64;
+      if sum >= target {
+        // Return the middle of the bin
+        return (i as f32 + 0.5) / nbins as f32
+      }
+    }
+    1.0
+  }
+
+  pub fn mean(&self) -> f32 {
+    if self.count == 0 {
+      return 0.0
+    }
+    let nbins = self.bins.len() as f64;
+    let sum: f64 = self.bins.iter().enumerate().map(|(i, count)| {
+      (i as f64 + 0.5) / nbins * (*count as f64)
+    }).sum();
+    (sum / self.count as f64) as f32
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lab_ramp(width: usize) -> OpBuffer {
+    let mut buf = OpBuffer::new(width, 10, 3, false);
+    buf.mutate_lines(&(|line: &mut [f32], _| {
+      for (col, pix) in line.chunks_exact_mut(3).enumerate() {
+        pix[0] = col as f32 / width as f32;
+        pix[1] = 0.5;
+        pix[2] = 0.5;
+      }
+    }));
+    buf
+  }
+
+  #[test]
+  fn counts_all_pixels() {
+    let hist = Histogram::from_lab(&lab_ramp(256), 256);
+    assert_eq!(hist.count, 2560);
+    assert_eq!(hist.bins.iter().map(|v| *v as u64).sum::<u64>(), 2560);
+    assert!(hist.bins.iter().all(|v| *v == 10));
+  }
+
+  #[test]
+  fn out_of_range_saturates() {
+    let mut buf = OpBuffer::new(2, 1, 3, false);
+    buf.data[0] = -0.5;
+    buf.data[3] = 1.5;
+    let hist = Histogram::from_lab(&buf, 16);
+    assert_eq!(hist.bins[0], 1);
+    assert_eq!(hist.bins[15], 1);
+  }
+
+  #[test]
+  fn empty_and_zero_bins() {
+    let hist = Histogram::from_lab(&OpBuffer::new(0, 0, 3, false), 16);
+    assert_eq!((hist.count, hist.bins.len()), (0, 16));
+    let hist = Histogram::from_lab(&lab_ramp(16), 0);
+    assert_eq!(hist.bins, vec![160]);
+  }
+
+  #[test]
+  fn percentiles() {
+    let hist = Histogram::from_lab(&lab_ramp(1000), 100);
+    assert!((hist.percentile(0.5) - 0.5).abs() < 0.02);
+    assert!((hist.percentile(0.1) - 0.1).abs() < 0.02);
+    assert!((hist.mean() - 0.5).abs() < 0.02);
+  }
+}
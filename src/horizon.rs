@@ -0,0 +1,210 @@
+use crate::buffer::*;
+use std::cmp;
+
+// Work on at most this many pixels on the longest side
+static MAX_SIDE: usize = 1000;
+// Resolution of the angle search in degrees
+static ANGLE_STEP: f32 = 0.1;
+
+// L channel of a Lab buffer averaged down to at most MAX_SIDE on each side
+fn luminance(buf: &OpBuffer) -> (Vec<f32>, usize, usize) {
+  let factor = cmp::max(cmp::max(buf.width, buf.height).div_ceil(MAX_SIDE), 1);
+  let width = buf.width / factor;
+  let height = buf.height / factor;
+  let mut out = vec![0.0; width*height];
+  for row in 0..height {
+    for col in 0..width {
+      let mut sum = 0.0;
+      for y in row*factor..(row+1)*factor {
+        for x in col*factor..(col+1)*factor {
+          sum += buf.data[(y*buf.width+x)*buf.colors];
+        }
+      }
+      out[row*width+col] = sum / (factor*factor) as f32;
+    }
+  }
+  (out, width, height)
+}
+
+// Votes for lines at each angle bin and distance from the origin, for the
+// lines close to horizontal and to vertical separately
+struct Accumulator {
+  angles: usize,
+  distances: usize,
+  offset: f32,
+  votes: [Vec<u32>;2],
+}
+
+impl Accumulator {
+  fn new(angles: usize, width: usize, height: usize) -> Accumulator {
+    let diagonal = ((width*width + height*height) as f32).sqrt().ceil();
+    let distances = 2 * diagonal as usize + 1;
+    Accumulator {
+      angles,
+      distances,
+      offset: diagonal,
+      votes: [vec![0; angles*distances], vec![0; angles*distances]],
+    }
+  }
+
+  fn angle(&self, bin: f32) -> f32 {
+    (bin - (self.angles / 2) as f32) * ANGLE_STEP
+  }
+
+  // Edge pixels vote for every angle as the gradients along aliased or noisy
+  // edges say little about the angle of the line itself
+  fn vote(&mut self, family: usize, x: f32, y: f32) {
+    for bin in 0..self.angles {
+      let (sin, cos) = self.angle(bin as f32).to_radians().sin_cos();
+      let distance = if family == 0 {
+        // Normal of a line at this angle from the horizontal
+        -x*sin + y*cos
+      } else {
+        // Normal of a line at this angle from the vertical
+        x*cos + y*sin
+      };
+      let distance = (distance + self.offset).round() as usize;
+      self.votes[family][bin * self.distances + distance] += 1;
+    }
+  }
+
+  // Angle of the line with the most votes, refined between bins by fitting a
+  // parabola to the best line of the neighboring angles
+  fn peak(&self) -> (u32, f32) {
+    let mut best = (0, 0, 0, 0);
+    for (family, votes) in self.votes.iter().enumerate() {
+      for (pos, &count) in votes.iter().enumerate() {
+        if count > best.0 {
+          best = (count, family, pos / self.distances, pos % self.distances);
+        }
+      }
+    }
+    let (count, family, bin, distance) = best;
+    if bin == 0 || bin == self.angles-1 {
+      return (count, self.angle(bin as f32))
+    }
+    let strongest = |bin: usize| -> f32 {
+      let from = distance.saturating_sub(3);
+      let to = cmp::min(distance + 3, self.distances - 1);
+      let line = &self.votes[family][bin*self.distances..(bin+1)*self.distances];
+      line[from..=to].iter().cloned().max().unwrap_or(0) as f32
+    };
+    let (prev, current, next) = (strongest(bin-1), count as f32, strongest(bin+1));
+    let denominator = prev - 2.0*current + next;
+    let delta = if denominator.abs() > 0.0 {
+      (0.5 * (prev - next) / denominator).clamp(-0.5, 0.5)
+    } else {
+      0.0
+    };
+    (count, self.angle(bin as f32 + delta))
+  }
+}
+
+/// Estimate the rotation that levels the dominant lines of an image
+///
+/// Uses a Hough transform on the edges of the L channel of a Lab buffer to find
+/// the strongest straight line within `max_angle` degrees of horizontal or
+/// vertical. Returns the clockwise rotation in degrees that makes that line
+/// level or upright, or None if there's no clear line in the image.
+pub fn straighten_angle(buf: &OpBuffer, max_angle: f32) -> Option<f32> {
+  let (lum, width, height) = luminance(buf);
+  if width < 3 || height < 3 {
+    return None
+  }
+
+  // Sobel gradients, leaving out the border
+  let mut gradients = Vec::with_capacity((width-2)*(height-2));
+  let mut max_magnitude = 0.0f32;
+  for row in 1..height-1 {
+    for col in 1..width-1 {
+      let p = |dx: usize, dy: usize| lum[(row+dy-1)*width + col+dx-1];
+      let gx = (p(2,0) + 2.0*p(2,1) + p(2,2)) - (p(0,0) + 2.0*p(0,1) + p(0,2));
+      let gy = (p(0,2) + 2.0*p(1,2) + p(2,2)) - (p(0,0) + 2.0*p(1,0) + p(2,0));
+      let magnitude = (gx*gx + gy*gy).sqrt();
+      max_magnitude = max_magnitude.max(magnitude);
+      gradients.push((col, row, gx, gy, magnitude));
+    }
+  }
+  let threshold = (max_magnitude * 0.25).max(0.02);
+
+  let max_angle = max_angle.clamp(ANGLE_STEP, 45.0);
+  let angles = 2 * (max_angle / ANGLE_STEP).round() as usize + 1;
+  let mut accumulator = Accumulator::new(angles, width, height);
+  for &(col, row, gx, gy, magnitude) in gradients.iter() {
+    if magnitude < threshold {
+      continue
+    }
+    // Edges of lines close to horizontal have mostly vertical gradients
+    let family = if gy.abs() >= gx.abs() {0} else {1};
+    accumulator.vote(family, col as f32, row as f32);
+  }
+
+  let (count, angle) = accumulator.peak();
+  // Require a line across at least a tenth of the image
+  if (count as usize) < cmp::min(width, height) / 10 || count < 10 {
+    return None
+  }
+  Some(-angle)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // An image split by a line through the center at the given angle in degrees,
+  // clockwise from horizontal, with an antialiased edge
+  fn horizon(width: usize, height: usize, angle: f32) -> OpBuffer {
+    let mut buf = OpBuffer::new(width, height, 3, false);
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    for row in 0..height {
+      for col in 0..width {
+        let (x, y) = (col as f32 - cx, row as f32 - cy);
+        let distance = -x*sin + y*cos;
+        let pos = (row*width+col)*3;
+        buf.data[pos] = 0.2 + 0.6 * (distance + 0.5).clamp(0.0, 1.0);
+        buf.data[pos+1] = 0.5;
+        buf.data[pos+2] = 0.5;
+      }
+    }
+    buf
+  }
+
+  #[test]
+  fn tilted_horizons() {
+    for angle in [-7.0, -3.0, -0.5, 0.0, 1.5, 4.2] {
+      let buf = horizon(600, 400, angle);
+      let result = straighten_angle(&buf, 10.0).unwrap();
+      assert!((result + angle).abs() < 0.2, "found {} for {}", result, angle);
+    }
+  }
+
+  #[test]
+  fn tilted_verticals() {
+    // A vertical leaning by the angle is a horizontal rotated by it plus 90
+    for angle in [-2.5, 3.0] {
+      let buf = horizon(400, 600, angle + 90.0);
+      let result = straighten_angle(&buf, 10.0).unwrap();
+      assert!((result + angle).abs() < 0.2, "found {} for {}", result, angle);
+    }
+  }
+
+  #[test]
+  fn downsampled_and_out_of_range() {
+    let buf = horizon(2100, 1300, 2.0);
+    let result = straighten_angle(&buf, 10.0).unwrap();
+    assert!((result + 2.0).abs() < 0.2, "found {}", result);
+    // Lines beyond the maximum angle are not considered
+    let buf = horizon(600, 400, 20.0);
+    assert_eq!(straighten_angle(&buf, 10.0), None);
+  }
+
+  #[test]
+  fn flat_image() {
+    let mut buf = OpBuffer::new(300, 200, 3, false);
+    for v in buf.data.iter_mut() {
+      *v = 0.5;
+    }
+    assert_eq!(straighten_angle(&buf, 10.0), None);
+  }
+}
@@ -14,7 +14,24 @@ pub use self::pipeline::*;
 pub use self::ops::*;
 pub mod color_conversions;
 mod scaling;
-pub use self::ops::curves::SplineFunc;
+pub use self::scaling::{FitMode, Resampling, Upscaling};
+mod histogram;
+pub use self::histogram::Histogram;
+mod horizon;
+pub use self::horizon::straighten_angle;
+mod scopes;
+pub use self::scopes::*;
+mod clipping;
+pub use self::clipping::ClippingMask;
+mod masks;
+mod calibration;
+mod metadata;
+pub use self::metadata::Metadata;
+mod preview;
+pub use self::preview::{PreviewMode, thumbnail_8bit};
+pub use self::calibration::{Calibration, CalibrationFrame};
+pub use self::masks::{GeometricMask, MaskRange, ParametricMask};
+pub use self::ops::curves::{SplineFunc, AutoLevels};
 
 use std::path::Path;
 
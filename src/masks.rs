@@ -0,0 +1,258 @@
+use crate::buffer::OpBuffer;
+
+/// A mask defined by its shape in the image
+///
+/// Points are given in normalized coordinates of the image before rotation and
+/// crop (0.0-1.0 from left to right and top to bottom) so the mask stays on the
+/// same part of the image no matter how it's framed. Use
+/// `Pipeline::mask_from_output()` to get one from coordinates on the output.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum GeometricMask {
+  /// Full effect on the start side of the line through start, fading out until
+  /// the line through end, with both lines perpendicular to start-end
+  Linear {
+    start: (f32, f32),
+    end: (f32, f32),
+  },
+  /// Full effect inside an ellipse, fading out over feather times its size
+  Radial {
+    center: (f32, f32),
+    /// End of one of the semi-axes of the ellipse
+    axis: (f32, f32),
+    /// Length of the other semi-axis relative to the first one, in pixels
+    ratio: f32,
+    feather: f32,
+  },
+}
+
+#[inline(always)]
+fn smoothstep(v: f32) -> f32 {
+  let v = v.clamp(0.0, 1.0);
+  v * v * (3.0 - 2.0 * v)
+}
+
+impl GeometricMask {
+  /// Get the same mask with all its points passed through a function
+  pub fn map<F>(&self, mut func: F) -> GeometricMask
+    where F: FnMut(f32, f32) -> (f32, f32) {
+    match *self {
+      GeometricMask::Linear{start, end} => GeometricMask::Linear {
+        start: func(start.0, start.1),
+        end: func(end.0, end.1),
+      },
+      GeometricMask::Radial{center, axis, ratio, feather} => GeometricMask::Radial {
+        center: func(center.0, center.1),
+        axis: func(axis.0, axis.1),
+        ratio,
+        feather,
+      },
+    }
+  }
+
+  /// Value of the mask at a point, from 0.0 for no effect to 1.0 for full effect
+  ///
+  /// The point needs to be in the same coordinates as the mask, which should be
+  /// pixels so distances are the same in both directions.
+  pub fn value(&self, x: f32, y: f32) -> f32 {
+    match *self {
+      GeometricMask::Linear{start, end} => {
+        let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+        let len2 = dx*dx + dy*dy;
+        if len2 <= 0.0 {
+          return 0.0
+        }
+        let pos = ((x - start.0) * dx + (y - start.1) * dy) / len2;
+        1.0 - smoothstep(pos)
+      },
+      GeometricMask::Radial{center, axis, ratio, feather} => {
+        let (ux, uy) = (axis.0 - center.0, axis.1 - center.1);
+        let radius = (ux*ux + uy*uy).sqrt();
+        if radius <= 0.0 || ratio <= 0.0 {
+          return 0.0
+        }
+        let (ux, uy) = (ux / radius, uy / radius);
+        let (dx, dy) = (x - center.0, y - center.1);
+        let s = (dx * ux + dy * uy) / radius;
+        let t = (dy * ux - dx * uy) / (radius * ratio);
+        let dist = (s*s + t*t).sqrt();
+        if feather <= 0.0 {
+          if dist <= 1.0 {1.0} else {0.0}
+        } else {
+          1.0 - smoothstep((dist - 1.0) / feather)
+        }
+      },
+    }
+  }
+}
+
+/// A smooth range of values, full inside min-max and fading out over feather
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MaskRange {
+  pub min: f32,
+  pub max: f32,
+  pub feather: f32,
+}
+
+impl MaskRange {
+  pub fn new(min: f32, max: f32, feather: f32) -> MaskRange {
+    MaskRange { min, max, feather }
+  }
+
+  fn fade(&self, distance: f32) -> f32 {
+    if distance <= 0.0 {
+      1.0
+    } else if self.feather <= 0.0 {
+      0.0
+    } else {
+      1.0 - smoothstep(distance / self.feather)
+    }
+  }
+
+  fn value(&self, v: f32) -> f32 {
+    self.fade((self.min - v).max(v - self.max))
+  }
+
+  // Hue is circular so the range can wrap around, such as 330 to 30
+  fn hue_value(&self, h: f32) -> f32 {
+    let width = (self.max - self.min).rem_euclid(360.0);
+    let pos = (h - self.min).rem_euclid(360.0);
+    if pos <= width {
+      1.0
+    } else {
+      self.fade((pos - width).min(360.0 - pos))
+    }
+  }
+}
+
+/// A mask defined by the values of the pixels
+///
+/// Ranges are in Lab units, 0 to 100 for lightness, 0 to around 130 for
+/// chroma and 0 to 360 degrees for hue with 0 as red and 90 as yellow. Ranges
+/// that are None don't restrict the mask. It's calculated from the Lab buffer
+/// generated by the to_lab op so it doesn't change with the edits themselves.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ParametricMask {
+  pub lightness: Option<MaskRange>,
+  pub chroma: Option<MaskRange>,
+  pub hue: Option<MaskRange>,
+  pub invert: bool,
+}
+
+impl ParametricMask {
+  /// Value of the mask for a pixel of a Lab buffer
+  pub fn value(&self, pix: &[f32]) -> f32 {
+    let l = pix[0] * 100.0;
+    let a = pix[1] * 255.0 - 127.0;
+    let b = pix[2] * 255.0 - 127.0;
+
+    let mut value = 1.0;
+    if let Some(ref range) = self.lightness {
+      value *= range.value(l);
+    }
+    if let Some(ref range) = self.chroma {
+      value *= range.value(a.hypot(b));
+    }
+    if let Some(ref range) = self.hue {
+      value *= range.hue_value(b.atan2(a).to_degrees().rem_euclid(360.0));
+    }
+    if self.invert {1.0 - value} else {value}
+  }
+
+  /// Mix the output of an op with its input with weights from the mask
+  pub fn blend(&self, lab: &OpBuffer, input: &OpBuffer, output: &OpBuffer) -> OpBuffer {
+    assert_eq!((lab.width, lab.height), (output.width, output.height));
+    assert_eq!(input.data.len(), output.data.len());
+    let colors = output.colors;
+    output.mutate_lines_copying(&(|line: &mut [f32], row| {
+      let labline = &lab.data[row*lab.width*lab.colors..];
+      let inline = &input.data[row*input.width*colors..];
+      for (col, pix) in line.chunks_exact_mut(colors).enumerate() {
+        let weight = self.value(&labline[col*lab.colors..]);
+        for (c, v) in pix.iter_mut().enumerate() {
+          let orig = inline[col*colors+c];
+          *v = orig + (*v - orig) * weight;
+        }
+      }
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lab(l: f32, c: f32, h: f32) -> [f32;3] {
+    let h = h.to_radians();
+    [l / 100.0, (c * h.cos() + 127.0) / 255.0, (c * h.sin() + 127.0) / 255.0]
+  }
+
+  #[test]
+  fn lightness_range() {
+    let mask = ParametricMask {
+      lightness: Some(MaskRange::new(0.0, 30.0, 20.0)),
+      ..ParametricMask::default()
+    };
+    assert_eq!(mask.value(&lab(10.0, 0.0, 0.0)), 1.0);
+    assert!((mask.value(&lab(40.0, 0.0, 0.0)) - 0.5).abs() < 0.01);
+    assert_eq!(mask.value(&lab(60.0, 0.0, 0.0)), 0.0);
+  }
+
+  #[test]
+  fn hue_wraps() {
+    let mask = ParametricMask {
+      hue: Some(MaskRange::new(330.0, 30.0, 10.0)),
+      chroma: Some(MaskRange::new(10.0, 200.0, 0.0)),
+      ..ParametricMask::default()
+    };
+    assert_eq!(mask.value(&lab(50.0, 40.0, 0.0)), 1.0);
+    assert_eq!(mask.value(&lab(50.0, 40.0, 340.0)), 1.0);
+    assert!(mask.value(&lab(50.0, 40.0, 35.0)) > 0.0);
+    assert_eq!(mask.value(&lab(50.0, 40.0, 180.0)), 0.0);
+    // Gray has no chroma
+    assert_eq!(mask.value(&lab(50.0, 0.0, 0.0)), 0.0);
+  }
+
+  #[test]
+  fn blend() {
+    let mut lab = OpBuffer::new(2, 1, 3, false);
+    lab.data.copy_from_slice(&[0.1, 0.5, 0.5, 0.9, 0.5, 0.5]);
+    let input = OpBuffer::new(2, 1, 3, false);
+    let mut output = OpBuffer::new(2, 1, 3, false);
+    output.data.copy_from_slice(&[1.0; 6]);
+    let mask = ParametricMask {
+      lightness: Some(MaskRange::new(0.0, 50.0, 0.0)),
+      ..ParametricMask::default()
+    };
+    let blended = mask.blend(&lab, &input, &output);
+    assert_eq!(blended.data, vec![1.0, 1.0, 1.0, 0.0, 0.0, 0.0]);
+  }
+
+  #[test]
+  fn linear() {
+    let mask = GeometricMask::Linear{start: (0.0, 10.0), end: (0.0, 20.0)};
+    assert_eq!(mask.value(5.0, 0.0), 1.0);
+    assert_eq!(mask.value(-5.0, 10.0), 1.0);
+    assert!((mask.value(3.0, 15.0) - 0.5).abs() < 0.001);
+    assert_eq!(mask.value(5.0, 20.0), 0.0);
+    assert_eq!(mask.value(5.0, 100.0), 0.0);
+  }
+
+  #[test]
+  fn radial() {
+    let mask = GeometricMask::Radial{center: (50.0, 50.0), axis: (70.0, 50.0), ratio: 0.5, feather: 0.5};
+    assert_eq!(mask.value(50.0, 50.0), 1.0);
+    assert_eq!(mask.value(69.0, 50.0), 1.0);
+    assert_eq!(mask.value(50.0, 59.0), 1.0);
+    // Outside the ellipse but inside the feather on the short axis
+    assert!(mask.value(50.0, 62.0) > 0.0 && mask.value(50.0, 62.0) < 1.0);
+    assert_eq!(mask.value(50.0, 66.0), 0.0);
+    assert_eq!(mask.value(81.0, 50.0), 0.0);
+  }
+
+  #[test]
+  fn map_points() {
+    let mask = GeometricMask::Linear{start: (0.1, 0.2), end: (0.3, 0.4)};
+    let mapped = mask.map(|x, y| (x * 10.0, y * 100.0));
+    assert_eq!(mapped, GeometricMask::Linear{start: (1.0, 20.0), end: (3.0, 40.0)});
+  }
+}
@@ -0,0 +1,449 @@
+use crate::pipeline::{ImageSource, SRGBImage, SRGBImage16};
+use exif::{Context, Field, In, Tag, Value};
+use image::ColorType;
+use image::codecs::jpeg::JpegEncoder;
+use rawloader::Orientation;
+use std::io::{Cursor, Seek, Write};
+
+// Signature of the APP1 segment holding XMP in JPEG files
+static XMP_JPEG_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+// Keyword of the iTXt chunk holding XMP in PNG files
+static XMP_PNG_KEYWORD: &str = "XML:com.adobe.xmp";
+// TIFF tag holding XMP in TIFF files and TIFF based raws
+static XMP_TIFF_TAG: u16 = 700;
+
+/// Metadata of the source image, passed through to the encoded outputs
+///
+/// The summary fields are read from the EXIF for display. The outputs get
+/// the descriptive EXIF and GPS fields of the source as they were, leaving
+/// out the ones about the layout of the file and the maker notes.
+#[derive(Debug, Clone)]
+pub struct Metadata {
+  pub make: Option<String>,
+  pub model: Option<String>,
+  pub lens: Option<String>,
+  /// Exposure time in seconds
+  pub exposure_time: Option<f32>,
+  pub aperture: Option<f32>,
+  pub iso: Option<u32>,
+  /// Focal length in mm
+  pub focal_length: Option<f32>,
+  /// Date and time the image was taken as written in the EXIF
+  pub datetime: Option<String>,
+  /// Latitude and longitude in degrees, positive to the north and east
+  pub gps: Option<(f64, f64)>,
+  pub orientation: Orientation,
+  /// XMP packet as found in the file
+  pub xmp: Option<Vec<u8>>,
+  fields: Vec<Field>,
+}
+
+impl Default for Metadata {
+  fn default() -> Self {
+    Self {
+      make: None,
+      model: None,
+      lens: None,
+      exposure_time: None,
+      aperture: None,
+      iso: None,
+      focal_length: None,
+      datetime: None,
+      gps: None,
+      orientation: Orientation::Normal,
+      xmp: None,
+      fields: Vec::new(),
+    }
+  }
+}
+
+fn ascii(field: Option<&Field>) -> Option<String> {
+  match field.map(|f| &f.value) {
+    Some(Value::Ascii(v)) if !v.is_empty() => {
+      let s = String::from_utf8_lossy(&v[0]).trim().to_string();
+      if s.is_empty() {None} else {Some(s)}
+    },
+    _ => None,
+  }
+}
+
+fn rational(field: Option<&Field>) -> Option<f64> {
+  match field.map(|f| &f.value) {
+    Some(Value::Rational(v)) if !v.is_empty() && v[0].denom != 0 => Some(v[0].to_f64()),
+    _ => None,
+  }
+}
+
+fn degrees(value: Option<&Field>, reference: Option<&Field>, negative: &str) -> Option<f64> {
+  let dms = match value.map(|f| &f.value) {
+    Some(Value::Rational(v)) if v.len() == 3 && v.iter().all(|r| r.denom != 0) => v,
+    _ => return None,
+  };
+  let degrees = dms[0].to_f64() + dms[1].to_f64() / 60.0 + dms[2].to_f64() / 3600.0;
+  if ascii(reference).as_deref() == Some(negative) {Some(-degrees)} else {Some(degrees)}
+}
+
+// Fields that describe the image and not the layout of the file they're in
+fn passthrough(field: &Field) -> bool {
+  if field.ifd_num != In::PRIMARY {
+    return false
+  }
+  if let Value::Unknown(..) = field.value {
+    return false
+  }
+  match field.tag.context() {
+    Context::Tiff => [
+      Tag::Make, Tag::Model, Tag::Software, Tag::DateTime, Tag::Artist,
+      Tag::Copyright, Tag::ImageDescription,
+    ].contains(&field.tag),
+    Context::Exif => ![
+      Tag::MakerNote, Tag::PixelXDimension, Tag::PixelYDimension, Tag::InteropIFDPointer,
+    ].contains(&field.tag),
+    Context::Gps => true,
+    _ => false,
+  }
+}
+
+fn orientation(exif: &exif::Exif) -> Option<Orientation> {
+  match exif.get_field(Tag::Orientation, In::PRIMARY).and_then(|f| f.value.get_uint(0)) {
+    Some(value @ 1..=8) => Some(Orientation::from_u16(value as u16)),
+    _ => None,
+  }
+}
+
+// XMP packet in the segments of a JPEG file
+fn jpeg_xmp(data: &[u8]) -> Option<Vec<u8>> {
+  let mut pos = 2;
+  while pos + 4 <= data.len() && data[pos] == 0xff {
+    let marker = data[pos+1];
+    // Image data starts here so there are no more segments
+    if marker == 0xda || marker == 0xd9 {
+      break
+    }
+    let len = ((data[pos+2] as usize) << 8) | data[pos+3] as usize;
+    let segment = data.get(pos+4..pos+2+len)?;
+    if marker == 0xe1 && segment.starts_with(XMP_JPEG_HEADER) {
+      return Some(segment[XMP_JPEG_HEADER.len()..].to_vec())
+    }
+    pos += 2 + len;
+  }
+  None
+}
+
+// XMP packet in the uncompressed iTXt chunk of a PNG file
+fn png_xmp(data: &[u8]) -> Option<Vec<u8>> {
+  let mut pos = 8;
+  while pos + 8 <= data.len() {
+    let len = u32::from_be_bytes([data[pos], data[pos+1], data[pos+2], data[pos+3]]) as usize;
+    let chunk = data.get(pos+8..pos+8+len)?;
+    if &data[pos+4..pos+8] == b"iTXt" && chunk.starts_with(XMP_PNG_KEYWORD.as_bytes()) {
+      // Keyword, compression flag and method, language and translated keyword
+      let rest = chunk.get(XMP_PNG_KEYWORD.len()+1..)?;
+      if rest.first() != Some(&0) {
+        return None
+      }
+      let mut text = rest.get(2..)?;
+      for _ in 0..2 {
+        let end = text.iter().position(|&b| b == 0)?;
+        text = &text[end+1..];
+      }
+      return Some(text.to_vec())
+    }
+    pos += 12 + len;
+  }
+  None
+}
+
+impl Metadata {
+  /// Metadata known from the source itself, which is just make and model
+  /// for raws and nothing for other images
+  pub(crate) fn from_source(img: &ImageSource) -> Metadata {
+    match img {
+      ImageSource::Raw(raw) => {
+        let mut metadata = Metadata {
+          orientation: raw.orientation,
+          ..Default::default()
+        };
+        for (tag, value) in [(Tag::Make, &raw.make), (Tag::Model, &raw.model)] {
+          if !value.is_empty() {
+            metadata.fields.push(Field {
+              tag,
+              ifd_num: In::PRIMARY,
+              value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+            });
+          }
+        }
+        metadata.summarize();
+        metadata
+      },
+      ImageSource::Other(_) => Metadata::default(),
+    }
+  }
+
+  /// Read the EXIF and XMP of the file contents the source was decoded from
+  ///
+  /// Raws keep the orientation rawloader found while other images use the
+  /// one in the EXIF, or normal if there isn't one.
+  pub(crate) fn from_data(data: &[u8], img: &ImageSource) -> Metadata {
+    let mut metadata = Self::from_source(img);
+    if let Ok(exif) = exif::Reader::new().read_from_container(&mut Cursor::new(data)) {
+      if let (ImageSource::Other(_), Some(orientation)) = (img, orientation(&exif)) {
+        metadata.orientation = orientation;
+      }
+      if let Some(field) = exif.get_field(Tag(Context::Tiff, XMP_TIFF_TAG), In::PRIMARY) {
+        if let Value::Byte(ref v) | Value::Undefined(ref v, _) = field.value {
+          metadata.xmp = Some(v.clone());
+        }
+      }
+      let fields: Vec<Field> = exif.fields().filter(|f| passthrough(f)).cloned().collect();
+      if !fields.is_empty() {
+        metadata.fields = fields;
+      }
+    }
+    if metadata.xmp.is_none() {
+      metadata.xmp = if data.starts_with(&[0xff, 0xd8]) {
+        jpeg_xmp(data)
+      } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_xmp(data)
+      } else {
+        None
+      };
+    }
+    metadata.summarize();
+    metadata
+  }
+
+  /// The same metadata for an image that already had the orientation applied
+  pub fn with_normal_orientation(&self) -> Metadata {
+    Metadata {
+      orientation: Orientation::Normal,
+      ..self.clone()
+    }
+  }
+
+  fn field(&self, tag: Tag) -> Option<&Field> {
+    self.fields.iter().find(|f| f.tag == tag)
+  }
+
+  fn summarize(&mut self) {
+    self.make = ascii(self.field(Tag::Make));
+    self.model = ascii(self.field(Tag::Model));
+    self.lens = ascii(self.field(Tag::LensModel));
+    self.exposure_time = rational(self.field(Tag::ExposureTime)).map(|v| v as f32);
+    self.aperture = rational(self.field(Tag::FNumber)).map(|v| v as f32);
+    self.iso = self.field(Tag::PhotographicSensitivity).and_then(|f| f.value.get_uint(0));
+    self.focal_length = rational(self.field(Tag::FocalLength)).map(|v| v as f32);
+    self.datetime = ascii(self.field(Tag::DateTimeOriginal)).or_else(|| ascii(self.field(Tag::DateTime)));
+    let latitude = degrees(self.field(Tag::GPSLatitude), self.field(Tag::GPSLatitudeRef), "S");
+    let longitude = degrees(self.field(Tag::GPSLongitude), self.field(Tag::GPSLongitudeRef), "W");
+    self.gps = latitude.zip(longitude);
+  }
+
+  // Fields to write to an output, with the orientation and any extra ones
+  fn output_fields(&self, extra: Vec<Field>) -> Vec<Field> {
+    let mut fields = extra;
+    fields.extend(self.fields.iter().cloned());
+    if self.orientation != Orientation::Unknown {
+      fields.push(Field {
+        tag: Tag::Orientation,
+        ifd_num: In::PRIMARY,
+        value: Value::Short(vec![self.orientation.to_u16()]),
+      });
+    }
+    fields
+  }
+
+  fn write_fields<W: Write + Seek>(fields: &[Field], strips: Option<&[&[u8]]>, w: &mut W) -> Result<(), String> {
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields.iter() {
+      writer.push_field(field);
+    }
+    if let Some(strips) = strips {
+      writer.set_strips(strips, In::PRIMARY);
+    }
+    writer.write(w, false).map_err(|e| format!("imagepipe: couldn't write EXIF: {}", e))
+  }
+
+  /// The EXIF to embed in an output as a TIFF structure
+  pub fn exif(&self) -> Result<Vec<u8>, String> {
+    let mut out = Cursor::new(Vec::new());
+    Self::write_fields(&self.output_fields(Vec::new()), None, &mut out)?;
+    Ok(out.into_inner())
+  }
+}
+
+// Add the EXIF and XMP segments right after the start of a JPEG file and its
+// JFIF segment if there is one
+fn jpeg_with_metadata(jpeg: Vec<u8>, metadata: &Metadata) -> Result<Vec<u8>, String> {
+  let mut pos = 2;
+  if jpeg.get(2..4) == Some(&[0xff, 0xe0]) {
+    pos += 2 + (((jpeg[4] as usize) << 8) | jpeg[5] as usize);
+  }
+  let mut segments = Vec::new();
+  let exif = metadata.exif()?;
+  let xmp = metadata.xmp.as_deref().unwrap_or(&[]);
+  for (header, data) in [(&b"Exif\0\0"[..], &exif[..]), (XMP_JPEG_HEADER, xmp)] {
+    if data.is_empty() {
+      continue
+    }
+    let len = 2 + header.len() + data.len();
+    if len > 0xffff {
+      return Err("imagepipe: metadata is too large for a JPEG segment".to_string())
+    }
+    segments.extend_from_slice(&[0xff, 0xe1, (len >> 8) as u8, len as u8]);
+    segments.extend_from_slice(header);
+    segments.extend_from_slice(data);
+  }
+  let mut out = Vec::with_capacity(jpeg.len() + segments.len());
+  out.extend_from_slice(&jpeg[..pos]);
+  out.extend_from_slice(&segments);
+  out.extend_from_slice(&jpeg[pos..]);
+  Ok(out)
+}
+
+fn encode_png<W: Write>(w: W, width: usize, height: usize, depth: png::BitDepth, data: &[u8],
+                        metadata: &Metadata) -> Result<(), String> {
+  let err = |e: png::EncodingError| format!("imagepipe: couldn't encode PNG: {}", e);
+  let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+  encoder.set_color(png::ColorType::Rgb);
+  encoder.set_depth(depth);
+  if let Some(ref xmp) = metadata.xmp {
+    encoder.add_itxt_chunk(XMP_PNG_KEYWORD.to_string(), String::from_utf8_lossy(xmp).into_owned()).map_err(err)?;
+  }
+  let mut writer = encoder.write_header().map_err(err)?;
+  writer.write_chunk(png::chunk::ChunkType(*b"eXIf"), &metadata.exif()?).map_err(err)?;
+  writer.write_image_data(data).map_err(err)
+}
+
+// Uncompressed RGB TIFF with the EXIF in its first IFD, data is big endian
+fn encode_tiff<W: Write + Seek>(w: &mut W, width: usize, height: usize, bits: u16, data: &[u8],
+                                metadata: &Metadata) -> Result<(), String> {
+  let short = |tag, v: Vec<u16>| Field {tag, ifd_num: In::PRIMARY, value: Value::Short(v)};
+  let long = |tag, v: u32| Field {tag, ifd_num: In::PRIMARY, value: Value::Long(vec![v])};
+  let linesize = width * 3 * bits as usize / 8;
+  let rows = std::cmp::max(1, 65536 / std::cmp::max(linesize, 1));
+  let mut extra = vec![
+    long(Tag::ImageWidth, width as u32),
+    long(Tag::ImageLength, height as u32),
+    short(Tag::BitsPerSample, vec![bits; 3]),
+    short(Tag::Compression, vec![1]),
+    short(Tag::PhotometricInterpretation, vec![2]),
+    short(Tag::SamplesPerPixel, vec![3]),
+    long(Tag::RowsPerStrip, rows as u32),
+    short(Tag::PlanarConfiguration, vec![1]),
+  ];
+  if let Some(ref xmp) = metadata.xmp {
+    extra.push(Field {
+      tag: Tag(Context::Tiff, XMP_TIFF_TAG),
+      ifd_num: In::PRIMARY,
+      value: Value::Byte(xmp.clone()),
+    });
+  }
+  let strips: Vec<&[u8]> = data.chunks(rows * linesize).collect();
+  Metadata::write_fields(&metadata.output_fields(extra), Some(&strips), w)
+}
+
+impl SRGBImage {
+  /// Encode as JPEG with the given metadata
+  pub fn write_jpeg<W: Write>(&self, metadata: &Metadata, quality: u8, mut w: W) -> Result<(), String> {
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality)
+      .encode(&self.data, self.width as u32, self.height as u32, ColorType::Rgb8)
+      .map_err(|e| format!("imagepipe: couldn't encode JPEG: {}", e))?;
+    let jpeg = jpeg_with_metadata(jpeg, metadata)?;
+    w.write_all(&jpeg).map_err(|e| format!("imagepipe: couldn't write JPEG: {}", e))
+  }
+
+  /// Encode as PNG with the given metadata
+  pub fn write_png<W: Write>(&self, metadata: &Metadata, w: W) -> Result<(), String> {
+    encode_png(w, self.width, self.height, png::BitDepth::Eight, &self.data, metadata)
+  }
+
+  /// Encode as an uncompressed TIFF with the given metadata
+  pub fn write_tiff<W: Write + Seek>(&self, metadata: &Metadata, w: &mut W) -> Result<(), String> {
+    encode_tiff(w, self.width, self.height, 8, &self.data, metadata)
+  }
+}
+
+impl SRGBImage16 {
+  /// Encode as 16 bit PNG with the given metadata
+  pub fn write_png<W: Write>(&self, metadata: &Metadata, w: W) -> Result<(), String> {
+    let data: Vec<u8> = self.data.iter().flat_map(|v| v.to_be_bytes()).collect();
+    encode_png(w, self.width, self.height, png::BitDepth::Sixteen, &data, metadata)
+  }
+
+  /// Encode as an uncompressed 16 bit TIFF with the given metadata
+  pub fn write_tiff<W: Write + Seek>(&self, metadata: &Metadata, w: &mut W) -> Result<(), String> {
+    let data: Vec<u8> = self.data.iter().flat_map(|v| v.to_be_bytes()).collect();
+    encode_tiff(w, self.width, self.height, 16, &data, metadata)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn find_xmp() {
+    let xmp = b"<x:xmpmeta/>";
+    let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe0, 0x00, 0x04, 0x00, 0x00];
+    let len = 2 + XMP_JPEG_HEADER.len() + xmp.len();
+    jpeg.extend_from_slice(&[0xff, 0xe1, (len >> 8) as u8, len as u8]);
+    jpeg.extend_from_slice(XMP_JPEG_HEADER);
+    jpeg.extend_from_slice(xmp);
+    jpeg.extend_from_slice(&[0xff, 0xda]);
+    assert_eq!(jpeg_xmp(&jpeg), Some(xmp.to_vec()));
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, 1, 1);
+    encoder.add_itxt_chunk(XMP_PNG_KEYWORD.to_string(), "<x:xmpmeta/>".to_string()).unwrap();
+    encoder.write_header().unwrap().write_image_data(&[0]).unwrap();
+    assert_eq!(png_xmp(&png), Some(xmp.to_vec()));
+  }
+
+  #[test]
+  fn truncated_png_xmp() {
+    // An iTXt chunk that ends right after the compression flag
+    let mut chunk = XMP_PNG_KEYWORD.as_bytes().to_vec();
+    chunk.extend_from_slice(&[0, 0]);
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+    png.extend_from_slice(b"iTXt");
+    png.extend_from_slice(&chunk);
+    png.extend_from_slice(&[0; 4]);
+    assert_eq!(png_xmp(&png), None);
+  }
+
+  #[test]
+  fn summary_fields() {
+    let field = |tag, value| Field {tag, ifd_num: In::PRIMARY, value};
+    let rationals = |v: &[(u32, u32)]| Value::Rational(v.iter().map(|&(n, d)| (n, d).into()).collect());
+    let mut metadata = Metadata {
+      fields: vec![
+        field(Tag::Make, Value::Ascii(vec![b"Canon".to_vec()])),
+        field(Tag::ExposureTime, rationals(&[(1, 250)])),
+        field(Tag::FNumber, rationals(&[(28, 10)])),
+        field(Tag::PhotographicSensitivity, Value::Short(vec![400])),
+        field(Tag::GPSLatitude, rationals(&[(38, 1), (30, 1), (36, 1)])),
+        field(Tag::GPSLatitudeRef, Value::Ascii(vec![b"N".to_vec()])),
+        field(Tag::GPSLongitude, rationals(&[(9, 1), (15, 1), (0, 1)])),
+        field(Tag::GPSLongitudeRef, Value::Ascii(vec![b"W".to_vec()])),
+      ],
+      ..Metadata::default()
+    };
+    metadata.summarize();
+    assert_eq!(metadata.make.as_deref(), Some("Canon"));
+    assert_eq!(metadata.model, None);
+    assert_eq!(metadata.exposure_time, Some(0.004));
+    assert_eq!(metadata.aperture, Some(2.8));
+    assert_eq!(metadata.iso, Some(400));
+    let (lat, lon) = metadata.gps.unwrap();
+    assert!((lat - 38.51).abs() < 1e-6 && (lon + 9.25).abs() < 1e-6);
+
+    // The layout of the source file and the maker notes are left behind
+    assert!(passthrough(&metadata.fields[0]));
+    assert!(!passthrough(&field(Tag::ImageWidth, Value::Long(vec![100]))));
+    assert!(!passthrough(&field(Tag::MakerNote, Value::Undefined(vec![0], 0))));
+    assert!(!passthrough(&Field {tag: Tag::Make, ifd_num: In::THUMBNAIL, value: Value::Ascii(vec![])}));
+  }
+}
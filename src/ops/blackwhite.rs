@@ -0,0 +1,235 @@
+use crate::opbasics::*;
+use crate::color_conversions::*;
+
+// Chroma in Lab units the toning reaches at full saturation
+static MAX_TONE_CHROMA: f32 = 40.0;
+
+/// Which channels the mixer weights apply to
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum MixerChannels {
+  /// Linear sRGB
+  #[default]
+  RGB,
+  /// White balanced camera channels, the same as RGB for non-raw images
+  Camera,
+}
+
+/// Classic color filters used in black and white photography
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ColorFilter {
+  Neutral,
+  Red,
+  Orange,
+  Yellow,
+  Green,
+  Blue,
+}
+
+impl ColorFilter {
+  pub fn weights(&self) -> [f32;3] {
+    match self {
+      ColorFilter::Neutral => [0.2126, 0.7152, 0.0722],
+      ColorFilter::Red     => [0.80, 0.20, 0.00],
+      ColorFilter::Orange  => [0.60, 0.35, 0.05],
+      ColorFilter::Yellow  => [0.45, 0.50, 0.05],
+      ColorFilter::Green   => [0.15, 0.75, 0.10],
+      ColorFilter::Blue    => [0.05, 0.25, 0.70],
+    }
+  }
+}
+
+/// Tint the shadows and highlights of the black and white image
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SplitToning {
+  /// Hue of the shadows in degrees, in Lab (0 is red, 90 is yellow)
+  pub shadow_hue: f32,
+  /// Strength of the shadow tint from 0.0 to 1.0
+  pub shadow_saturation: f32,
+  pub highlight_hue: f32,
+  pub highlight_saturation: f32,
+  /// Moves the split between shadows and highlights, from -1.0 (mostly
+  /// shadows) to 1.0 (mostly highlights)
+  pub balance: f32,
+}
+
+impl SplitToning {
+  fn tint(&self, l: f32) -> (f32, f32) {
+    let pivot = (0.5 - self.balance * 0.5).clamp(0.05, 0.95);
+    let shadows = (1.0 - l / pivot).clamp(0.0, 1.0);
+    let highlights = ((l - pivot) / (1.0 - pivot)).clamp(0.0, 1.0);
+    // Keep pure black and white untinted
+    let fade = (4.0 * l * (1.0 - l)).clamp(0.0, 1.0);
+
+    let mut a = 0.0;
+    let mut b = 0.0;
+    for (weight, hue, saturation) in [
+      (shadows, self.shadow_hue, self.shadow_saturation),
+      (highlights, self.highlight_hue, self.highlight_saturation),
+    ].iter() {
+      let chroma = weight * saturation.clamp(0.0, 1.0) * MAX_TONE_CHROMA * fade;
+      let hue = hue.to_radians();
+      a += chroma * hue.cos();
+      b += chroma * hue.sin();
+    }
+    ((a + 127.0) / 255.0, (b + 127.0) / 255.0)
+  }
+}
+
+/// Black and white conversion with a channel mixer
+///
+/// Works on the Lab buffer after the base curve. The gray value is a weighted
+/// sum of the linear channels, normalized so white stays white, that then
+/// optionally gets split toned.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct OpBlackWhite {
+  pub enabled: bool,
+  pub channels: MixerChannels,
+  pub weights: [f32;3],
+  pub toning: SplitToning,
+  /// XYZ to white balanced camera channels
+  xyz_to_cam: [[f32;3];3],
+}
+
+impl Default for OpBlackWhite {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      channels: MixerChannels::RGB,
+      weights: ColorFilter::Neutral.weights(),
+      toning: SplitToning::default(),
+      xyz_to_cam: *XYZ_D65_33,
+    }
+  }
+}
+
+impl OpBlackWhite {
+  pub fn new(img: &ImageSource) -> OpBlackWhite {
+    let xyz_to_cam = match img {
+      ImageSource::Raw(img) => {
+        // Scale each channel so D65 white has the same value in all of them
+        let (xw, yw, zw) = *SRGB_D65_XYZ_WHITE;
+        let mut out = [[0.0; 3]; 3];
+        for (orow, row) in out.iter_mut().zip(img.xyz_to_cam.iter()) {
+          let white = row[0]*xw + row[1]*yw + row[2]*zw;
+          if white.is_normal() {
+            for c in 0..3 {
+              orow[c] = row[c] / white;
+            }
+          }
+        }
+        out
+      },
+      ImageSource::Other(_) => *XYZ_D65_33,
+    };
+
+    OpBlackWhite {
+      xyz_to_cam,
+      ..Self::default()
+    }
+  }
+
+  pub fn set_filter(&mut self, filter: ColorFilter) {
+    self.weights = filter.weights();
+  }
+}
+
+impl<'a> ImageOp<'a> for OpBlackWhite {
+  fn name(&self) -> &str {"blackwhite"}
+  fn run(&self, _pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    if !self.enabled {
+      return buf
+    }
+
+    let matrix = match self.channels {
+      MixerChannels::RGB => *XYZ_D65_33,
+      MixerChannels::Camera => self.xyz_to_cam,
+    };
+    let sum: f32 = self.weights.iter().sum();
+    let weights = if sum.abs() > 0.001 {
+      [self.weights[0] / sum, self.weights[1] / sum, self.weights[2] / sum]
+    } else {
+      self.weights
+    };
+    let (xw, yw, zw) = *SRGB_D65_XYZ_WHITE;
+
+    Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], _| {
+      for pix in line.chunks_exact_mut(3) {
+        let (r, g, b) = lab_to_rgb(matrix, pix);
+        let gray = (r * weights[0] + g * weights[1] + b * weights[2]).max(0.0);
+        let (l, _, _) = xyz_to_lab(gray * xw, gray * yw, gray * zw);
+        let (a, b) = self.toning.tint(l);
+        pix[0] = l;
+        pix[1] = a;
+        pix[2] = b;
+      }
+    })))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lab_buffer(rgb: [f32;3]) -> Arc<OpBuffer> {
+    let mut buf = OpBuffer::new(4, 4, 3, false);
+    let (l, a, b) = camera_to_lab([1.0, 1.0, 1.0, 0.0], *SRGB_D65_43, &[rgb[0], rgb[1], rgb[2], 0.0]);
+    for pix in buf.data.chunks_exact_mut(3) {
+      pix[0] = l;
+      pix[1] = a;
+      pix[2] = b;
+    }
+    Arc::new(buf)
+  }
+
+  fn run(op: &OpBlackWhite, rgb: [f32;3]) -> [f32;3] {
+    let globals = PipelineGlobals::mock(4, 4);
+    let out = op.run(&globals, lab_buffer(rgb));
+    [out.data[0], out.data[1], out.data[2]]
+  }
+
+  #[test]
+  fn gray_stays_gray() {
+    let mut op = OpBlackWhite{enabled: true, ..OpBlackWhite::default()};
+    for filter in [ColorFilter::Neutral, ColorFilter::Red, ColorFilter::Blue].iter() {
+      op.set_filter(*filter);
+      let input = lab_buffer([0.2, 0.2, 0.2]);
+      let out = run(&op, [0.2, 0.2, 0.2]);
+      assert!((out[0] - input.data[0]).abs() < 0.01);
+      assert!((out[1] - 127.0/255.0).abs() < 0.0001);
+      assert!((out[2] - 127.0/255.0).abs() < 0.0001);
+    }
+  }
+
+  #[test]
+  fn filters_change_tones() {
+    let mut op = OpBlackWhite{enabled: true, ..OpBlackWhite::default()};
+    op.set_filter(ColorFilter::Red);
+    let red_red = run(&op, [0.8, 0.1, 0.1])[0];
+    let red_blue = run(&op, [0.1, 0.1, 0.8])[0];
+    op.set_filter(ColorFilter::Blue);
+    let blue_red = run(&op, [0.8, 0.1, 0.1])[0];
+    let blue_blue = run(&op, [0.1, 0.1, 0.8])[0];
+    assert!(red_red > blue_red);
+    assert!(blue_blue > red_blue);
+  }
+
+  #[test]
+  fn split_toning() {
+    let mut op = OpBlackWhite{enabled: true, ..OpBlackWhite::default()};
+    op.toning.shadow_hue = 270.0;
+    op.toning.shadow_saturation = 1.0;
+    op.toning.highlight_hue = 90.0;
+    op.toning.highlight_saturation = 1.0;
+    // Blue shadows and yellow highlights
+    assert!(run(&op, [0.02, 0.02, 0.02])[2] < 127.0/255.0);
+    assert!(run(&op, [0.6, 0.6, 0.6])[2] > 127.0/255.0);
+  }
+
+  #[test]
+  fn disabled_is_noop() {
+    let op = OpBlackWhite::default();
+    let globals = PipelineGlobals::mock(4, 4);
+    let buf = lab_buffer([0.8, 0.1, 0.1]);
+    assert!(Arc::ptr_eq(&buf, &op.run(&globals, buf.clone())));
+  }
+}
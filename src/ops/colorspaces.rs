@@ -86,7 +86,7 @@ impl OpToLab {
 
 impl<'a> ImageOp<'a> for OpToLab {
   fn name(&self) -> &str {"to_lab"}
-  fn run(&self, _pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
+  fn run(&self, pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
     let cmatrix = if buf.monochrome {
       // Monochrome means we don't need color conversion so it's as if the camera is itself D65 SRGB
       *SRGB_D65_43
@@ -100,9 +100,16 @@ impl<'a> ImageOp<'a> for OpToLab {
       normalize_wbs(self.wb_coeffs)
     };
 
+    // Sensor values clip at 1.0 but float images can be brighter than that
+    let clip = !pipeline.image.is_float();
+
     Arc::new(buf.process_into_new(3, &(|outb: &mut [f32], inb: &[f32]| {
       for (pixin, pixout) in inb.chunks_exact(4).zip(outb.chunks_exact_mut(3)) {
-        let (l,a,b) = camera_to_lab(mul, cmatrix, pixin);
+        let (l,a,b) = if clip {
+          camera_to_lab(mul, cmatrix, pixin)
+        } else {
+          camera_to_lab_unclipped(mul, cmatrix, pixin)
+        };
 
         pixout[0] = l;
         pixout[1] = a;
@@ -112,22 +119,41 @@ impl<'a> ImageOp<'a> for OpToLab {
   }
 }
 
+/// RGB primaries of the output
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Primaries {
+  #[default]
+  SRGB,
+  Rec2020,
+}
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct OpFromLab {
+  #[serde(default)]
+  pub primaries: Primaries,
 }
 
 impl OpFromLab {
   pub fn new(_img: &ImageSource) -> OpFromLab {
-    OpFromLab{}
+    OpFromLab{
+      primaries: Primaries::SRGB,
+    }
   }
 }
 
 impl<'a> ImageOp<'a> for OpFromLab {
   fn name(&self) -> &str {"from_lab"}
   fn run(&self, _pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    // No clipping is done here so values above 1.0 make it through to the
+    // output encoding
+    let rgbmatrix = match self.primaries {
+      Primaries::SRGB => *XYZ_D65_33,
+      Primaries::Rec2020 => *XYZ_REC2020_33,
+    };
+
     Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], _| {
       for pix in line.chunks_exact_mut(3) {
-        let (r,g,b) = lab_to_rgb(*XYZ_D65_33, pix);
+        let (r,g,b) = lab_to_rgb(rgbmatrix, pix);
 
         pix[0] = r;
         pix[1] = g;
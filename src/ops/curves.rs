@@ -1,11 +1,34 @@
 use crate::opbasics::*;
+use crate::histogram::Histogram;
 
 use std::cmp;
 
+// Fraction of pixels allowed to clip at each end when setting auto levels
+static AUTO_CLIP_FRACTION: f32 = 0.001;
+// Where the median luminance should end up after auto exposure
+static AUTO_TARGET_MEDIAN: f32 = 0.5;
+// Maximum correction in either direction applied by auto exposure
+static AUTO_MAX_EXPOSURE: f32 = 2.0;
+
+/// Exposure and black/white points derived from the luminance of an image
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AutoLevels {
+  pub exposure: f32,
+  pub blackpoint: f32,
+  pub whitepoint: f32,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OpBaseCurve {
   pub exposure: f32,
   pub points: Vec<(f32, f32)>,
+  /// Calculate levels and exposure from the image on every run
+  #[serde(default)]
+  pub auto: bool,
+  /// Keep values above the end of the curve instead of clipping them, extending
+  /// the curve with its final slope. Useful for HDR outputs
+  #[serde(default)]
+  pub scene_referred: bool,
 }
 
 impl OpBaseCurve {
@@ -16,12 +39,16 @@ impl OpBaseCurve {
           exposure: 0.0,
           // Slopes the curve to go from the linear raw to a more natural look
           points: vec![(0.50, 0.60)],
+          auto: false,
+          scene_referred: false,
         }
       },
       ImageSource::Other(_) => {
         OpBaseCurve{
           exposure: 0.0,
           points: vec![],
+          auto: false,
+          scene_referred: false,
         }
       }
     }
@@ -31,19 +58,26 @@ impl OpBaseCurve {
 impl<'a> ImageOp<'a> for OpBaseCurve {
   fn name(&self) -> &str {"basecurve"}
   fn run(&self, _pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
-    if self.points.len() == 0 && self.exposure.abs() < 0.001 {
+    if !self.auto && self.points.len() == 0 && self.exposure.abs() < 0.001 {
       return buf
     }
 
-    let mut final_points = self.points.clone();
-    for (_, to) in final_points.iter_mut() {
-      *to = *to * self.exposure.exp2();
-    }
-    let func = SplineFunc::new(&final_points);
+    let func = if self.auto {
+      let levels = self.auto_levels(&buf);
+      log::debug!("Auto levels set to {:?}", levels);
+      self.leveled(&levels).final_spline()
+    } else {
+      self.final_spline()
+    };
 
+    let scene_referred = self.scene_referred;
     Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], _| {
       for pix in line.chunks_exact_mut(3) {
-        pix[0] = func.interpolate(pix[0]);
+        pix[0] = if scene_referred {
+          func.interpolate_extended(pix[0])
+        } else {
+          func.interpolate(pix[0])
+        };
       }
     })))
   }
@@ -53,6 +87,72 @@ impl OpBaseCurve {
   pub fn get_spline(&self) -> SplineFunc {
     SplineFunc::new(&self.points)
   }
+
+  fn final_spline(&self) -> SplineFunc {
+    let mut final_points = self.points.clone();
+    for (_, to) in final_points.iter_mut() {
+      *to = *to * self.exposure.exp2();
+    }
+    SplineFunc::new(&final_points)
+  }
+
+  /// Suggest levels for a Lab buffer, such as the one generated by the to_lab op
+  ///
+  /// The black and white points clip a small fraction of the pixels at each end
+  /// and the exposure brings the median luminance to the middle of the range
+  /// after the rest of the curve is applied.
+  pub fn auto_levels(&self, buf: &OpBuffer) -> AutoLevels {
+    let hist = Histogram::from_lab(buf, 1024);
+    let blackpoint = hist.percentile(AUTO_CLIP_FRACTION);
+    let whitepoint = hist.percentile(1.0 - AUTO_CLIP_FRACTION);
+    if whitepoint - blackpoint < 0.01 {
+      // Flat image, there's nothing sensible to stretch
+      return AutoLevels {
+        exposure: 0.0,
+        blackpoint: 0.0,
+        whitepoint: 1.0,
+      }
+    }
+
+    let leveled = self.leveled(&AutoLevels {
+      exposure: 0.0,
+      blackpoint,
+      whitepoint,
+    });
+    let median = leveled.final_spline().interpolate(hist.percentile(0.5));
+    let exposure = if median > 0.0 {
+      (AUTO_TARGET_MEDIAN / median).log2().max(-AUTO_MAX_EXPOSURE).min(AUTO_MAX_EXPOSURE)
+    } else {
+      0.0
+    };
+
+    AutoLevels {
+      exposure,
+      blackpoint,
+      whitepoint,
+    }
+  }
+
+  /// Get a copy of the op with the given levels baked into the curve points and
+  /// exposure, with auto turned off
+  pub fn leveled(&self, levels: &AutoLevels) -> OpBaseCurve {
+    let (black, white) = (levels.blackpoint, levels.whitepoint);
+    let mut points = vec![(black, 0.0)];
+    for (from, to) in self.points.iter() {
+      let from = black + from * (white - black);
+      if from > black && from < white {
+        points.push((from, *to));
+      }
+    }
+    points.push((white, 1.0));
+
+    OpBaseCurve {
+      exposure: self.exposure + levels.exposure,
+      points,
+      auto: false,
+      scene_referred: self.scene_referred,
+    }
+  }
 }
 
 
@@ -155,6 +255,17 @@ impl SplineFunc {
 
     self.points[i].1 + self.c1s[i]*diff + self.c2s[i]*diff*diff + self.c3s[i]*diff*diff*diff
   }
+
+  /// Same as interpolate() but values beyond the last point continue on a line
+  /// with the slope of the curve at that point instead of saturating
+  pub fn interpolate_extended(&self, val: f32) -> f32 {
+    let (endx, endy) = self.points[self.points.len()-1];
+    if val > endx {
+      endy + self.c1s[self.c1s.len()-1] * (val - endx)
+    } else {
+      self.interpolate(val)
+    }
+  }
 }
 
 #[cfg(test)]
@@ -186,4 +297,47 @@ mod tests {
     let spline = SplineFunc::new(&[(1.0,0.8)]);
     assert_eq!(spline.interpolate(1.0), 0.8);
   }
+
+  #[test]
+  fn extended_continues() {
+    let spline = SplineFunc::new(&[(0.5,0.6)]);
+    assert_eq!(spline.interpolate_extended(1.0), 1.0);
+    assert!(spline.interpolate_extended(2.0) > 1.5);
+    assert_eq!(spline.interpolate_extended(0.5), spline.interpolate(0.5));
+  }
+
+  fn dim_lab_buffer() -> OpBuffer {
+    // A ramp of luminance between 0.1 and 0.4
+    let mut buf = OpBuffer::new(300, 10, 3, false);
+    buf.mutate_lines(&(|line: &mut [f32], _| {
+      for (col, pix) in line.chunks_exact_mut(3).enumerate() {
+        pix[0] = 0.1 + col as f32 / 1000.0;
+        pix[1] = 0.5;
+        pix[2] = 0.5;
+      }
+    }));
+    buf
+  }
+
+  #[test]
+  fn auto_levels_stretch() {
+    let op = OpBaseCurve { exposure: 0.0, points: vec![], auto: true, scene_referred: false };
+    let levels = op.auto_levels(&dim_lab_buffer());
+    assert!((levels.blackpoint - 0.1).abs() < 0.005, "blackpoint was {}", levels.blackpoint);
+    assert!((levels.whitepoint - 0.4).abs() < 0.005, "whitepoint was {}", levels.whitepoint);
+    assert!(levels.exposure.abs() < 0.05, "exposure was {}", levels.exposure);
+  }
+
+  #[test]
+  fn auto_levels_deterministic() {
+    let buf = Arc::new(dim_lab_buffer());
+    let op = OpBaseCurve { exposure: 0.0, points: vec![], auto: true, scene_referred: false };
+    let globals = PipelineGlobals::mock(300, 10);
+    let out1 = op.run(&globals, buf.clone());
+    let out2 = op.run(&globals, buf.clone());
+    assert_eq!(out1, out2);
+    // Darkest and brightest pixels get taken to the ends of the range
+    assert!(out1.data[0] < 0.01);
+    assert!(out1.data[299*3] > 0.95);
+  }
 }
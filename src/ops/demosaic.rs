@@ -27,7 +27,8 @@ impl<'a> ImageOp<'a> for OpDemosaic {
   fn run(&self, pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
     let nwidth = pipeline.settings.demosaic_width;
     let nheight = pipeline.settings.demosaic_height;
-    let scale = crate::scaling::calculate_scale(buf.width, buf.height, nwidth, nheight);
+    let upscaling = pipeline.settings.upscaling;
+    let scale = crate::scaling::calculate_scale(buf.width, buf.height, nwidth, nheight, upscaling.enabled());
 
     let cfa = CFA::new(&self.cfa);
     let minscale = match cfa.width {
@@ -38,28 +39,48 @@ impl<'a> ImageOp<'a> for OpDemosaic {
       _  => 2.0,  // default
     };
 
-    if scale <= 1.0 && buf.colors == 4 {
+    if scale < 1.0 {
+      // We've been asked to scale up so do a full demosaic first if needed
+      let fullsize = if buf.colors == 4 {buf} else {Arc::new(full(cfa, &buf))};
+      Arc::new(crate::scaling::scale_up_opbuf(&fullsize, nwidth, nheight, upscaling))
+    } else if scale <= 1.0 && buf.colors == 4 {
       // We want full size and the image is already 4 color, pass it through
       buf
     } else if buf.colors == 4 {
       // Scale down a 4 colour image
-      Arc::new(crate::scaling::scale_down_opbuf(&buf, nwidth, nheight))
+      Arc::new(crate::scaling::scale_down_opbuf(&buf, nwidth, nheight, pipeline.settings.resampling))
     } else if scale >= minscale {
       // We're scaling down enough that each pixel has all four colors under it so do the
       // demosaic and scale down in one go
-      Arc::new(crate::scaling::scaled_demosaic(cfa, &buf, nwidth, nheight))
+      Arc::new(crate::scaling::scaled_demosaic(cfa, &buf, nwidth, nheight, pipeline.settings.resampling))
     } else {
       // We're in a close to full scale output that needs full demosaic and possibly
       // minimal scale down
       let fullsize = full(cfa, &buf);
       if scale > 1.0 {
-        Arc::new(crate::scaling::scale_down_opbuf(&fullsize, nwidth, nheight))
+        Arc::new(crate::scaling::scale_down_opbuf(&fullsize, nwidth, nheight, pipeline.settings.resampling))
       } else {
         Arc::new(fullsize)
       }
     }
   }
 
+  fn run_alpha(&self, pipeline: &PipelineGlobals, alpha: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    // Alpha is never mosaiced so it just needs the same scaling as the image
+    let nwidth = pipeline.settings.demosaic_width;
+    let nheight = pipeline.settings.demosaic_height;
+    let upscaling = pipeline.settings.upscaling;
+    let scale = crate::scaling::calculate_scale(alpha.width, alpha.height, nwidth, nheight, upscaling.enabled());
+
+    if scale < 1.0 {
+      Arc::new(crate::scaling::scale_up_opbuf(&alpha, nwidth, nheight, upscaling))
+    } else if scale > 1.0 {
+      Arc::new(crate::scaling::scale_down_opbuf(&alpha, nwidth, nheight, pipeline.settings.resampling))
+    } else {
+      alpha
+    }
+  }
+
   // We don't transform_reverse as image sizing is relative to the scaling done
   // at the demosaic step, so whatever scale down is needed can be achieved here
 }
@@ -0,0 +1,162 @@
+use crate::opbasics::*;
+use crate::color_conversions::*;
+use crate::ops::curves::SplineFunc;
+
+// Smallest value taken into the log encoding, anything below is black
+static MIN_VALUE: f32 = 1.0 / 1000000.0;
+
+/// Scene referred tone mapping with a filmic look
+///
+/// Values are log encoded over a range of EVs around middle grey and then go
+/// through an S shaped curve that is linear around middle grey and rolls off
+/// smoothly into the shadows and highlights. Works on linear RGB so it needs
+/// values that haven't been clipped at 1.0, set the base curve to be scene
+/// referred to get those.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct OpFilmic {
+  pub enabled: bool,
+  /// Scene value that is considered middle grey
+  pub grey: f32,
+  /// How many EV below middle grey become black
+  pub ev_below: f32,
+  /// How many EV above middle grey become white
+  pub ev_above: f32,
+  /// Slope of the curve around middle grey
+  pub contrast: f32,
+  /// Fraction of the range on each side of middle grey where the curve is linear
+  pub latitude: f32,
+  /// Map the maximum of the RGB channels and scale all of them by the same
+  /// amount, instead of mapping each channel separately which desaturates
+  /// highlights
+  pub preserve_chroma: bool,
+}
+
+impl Default for OpFilmic {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      grey: 0.1845,
+      ev_below: 7.0,
+      ev_above: 4.5,
+      contrast: 1.2,
+      latitude: 0.3,
+      preserve_chroma: true,
+    }
+  }
+}
+
+impl OpFilmic {
+  pub fn new(_img: &ImageSource) -> OpFilmic {
+    Self::default()
+  }
+
+  fn spline(&self) -> SplineFunc {
+    // Middle grey in the log encoding and on the display
+    let grey_log = self.ev_below / (self.ev_below + self.ev_above);
+    let grey_display = apply_srgb_gamma(self.grey);
+
+    let latitude = self.latitude.clamp(0.01, 0.99);
+    let toe = grey_log * (1.0 - latitude);
+    let shoulder = grey_log + (1.0 - grey_log) * latitude;
+    let toe_display = (grey_display + self.contrast * (toe - grey_log)).max(0.001);
+    let shoulder_display = (grey_display + self.contrast * (shoulder - grey_log)).min(0.999);
+
+    SplineFunc::new(&[
+      (0.0, 0.0),
+      (toe, toe_display),
+      (grey_log, grey_display),
+      (shoulder, shoulder_display),
+      (1.0, 1.0),
+    ])
+  }
+
+  #[inline(always)]
+  fn log_encode(&self, v: f32) -> f32 {
+    let ev = (v.max(MIN_VALUE) / self.grey).log2();
+    ((ev + self.ev_below) / (self.ev_below + self.ev_above)).clamp(0.0, 1.0)
+  }
+
+  // Map a linear scene value into a linear display value
+  #[inline(always)]
+  fn map(&self, spline: &SplineFunc, v: f32) -> f32 {
+    expand_srgb_gamma(spline.interpolate(self.log_encode(v)))
+  }
+}
+
+impl<'a> ImageOp<'a> for OpFilmic {
+  fn name(&self) -> &str {"filmic"}
+  fn run(&self, _pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    if !self.enabled {
+      return buf
+    }
+
+    let spline = self.spline();
+    Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], _| {
+      for pix in line.chunks_exact_mut(3) {
+        if self.preserve_chroma {
+          let max = pix[0].max(pix[1]).max(pix[2]);
+          if max <= MIN_VALUE {
+            pix[0] = 0.0;
+            pix[1] = 0.0;
+            pix[2] = 0.0;
+          } else {
+            let ratio = self.map(&spline, max) / max;
+            pix[0] *= ratio;
+            pix[1] *= ratio;
+            pix[2] *= ratio;
+          }
+        } else {
+          pix[0] = self.map(&spline, pix[0]);
+          pix[1] = self.map(&spline, pix[1]);
+          pix[2] = self.map(&spline, pix[2]);
+        }
+      }
+    })))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn run_rgb(op: &OpFilmic, values: &[f32]) -> Vec<f32> {
+    let mut buf = OpBuffer::new(values.len() / 3, 1, 3, false);
+    buf.data.copy_from_slice(values);
+    let globals = PipelineGlobals::mock(10, 10);
+    op.run(&globals, Arc::new(buf)).data.clone()
+  }
+
+  #[test]
+  fn grey_stays_put() {
+    let op = OpFilmic{enabled: true, ..OpFilmic::default()};
+    let out = run_rgb(&op, &[op.grey, op.grey, op.grey]);
+    for v in out {
+      assert!((v - op.grey).abs() < 0.002, "Got {} instead of {}", v, op.grey);
+    }
+  }
+
+  #[test]
+  fn highlights_roll_off() {
+    let op = OpFilmic{enabled: true, ..OpFilmic::default()};
+    let values = [0.5, 0.5, 0.5, 1.0, 1.0, 1.0, 4.0, 4.0, 4.0, 1000.0, 1000.0, 1000.0];
+    let out = run_rgb(&op, &values);
+    // Monotonic, with everything brighter than 1.0 still under white
+    for i in 1..4 {
+      assert!(out[i*3] > out[(i-1)*3]);
+    }
+    assert!(out[6] < 1.0);
+    assert!((out[9] - 1.0).abs() < 0.001);
+  }
+
+  #[test]
+  fn preserves_chroma() {
+    let mut op = OpFilmic{enabled: true, ..OpFilmic::default()};
+    let out = run_rgb(&op, &[4.0, 2.0, 1.0]);
+    assert!((out[0] / out[1] - 2.0).abs() < 0.001);
+    assert!((out[1] / out[2] - 2.0).abs() < 0.001);
+
+    op.preserve_chroma = false;
+    let out = run_rgb(&op, &[4.0, 2.0, 1.0]);
+    assert!(out[0] / out[1] < 2.0);
+  }
+}
@@ -0,0 +1,256 @@
+use crate::opbasics::*;
+use crate::scaling::FitMode;
+
+/// Fit of the final image into the size set by maxwidth and maxheight
+///
+/// This is an output setting and not an edit to the image so it's set up by the
+/// pipeline from its settings before each run instead of being saved with the
+/// other ops. Contain is done by the normal scaling so it's a noop here.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OpFit {
+  mode: FitMode,
+  maxwidth: usize,
+  maxheight: usize,
+  upscale: bool,
+  // Full size of the input for the current run
+  input_size: (usize, usize),
+}
+
+impl OpFit {
+  pub fn new(_img: &ImageSource) -> OpFit {
+    Self::default()
+  }
+
+  pub(crate) fn set_target(&mut self, settings: &PipelineSettings) {
+    self.mode = settings.fit;
+    self.maxwidth = settings.maxwidth;
+    self.maxheight = settings.maxheight;
+    self.upscale = settings.upscaling.enabled();
+  }
+
+  fn active(&self) -> bool {
+    self.mode != FitMode::Contain && self.maxwidth > 0 && self.maxheight > 0
+  }
+
+  // Size of the final image for an input of a given size
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    let (maxwidth, maxheight) = (self.maxwidth, self.maxheight);
+    if self.upscale || (width >= maxwidth && height >= maxheight) {
+      return (maxwidth, maxheight)
+    }
+    match self.mode {
+      FitMode::Cover{..} => {
+        // Largest size with the same aspect ratio we can get without scaling up
+        let scale = (width as f32 / maxwidth as f32).min(height as f32 / maxheight as f32);
+        let owidth = ((maxwidth as f32 * scale).round() as usize).clamp(1, width);
+        let oheight = ((maxheight as f32 * scale).round() as usize).clamp(1, height);
+        (owidth, oheight)
+      },
+      FitMode::Stretch => (cmp::min(maxwidth, width), cmp::min(maxheight, height)),
+      FitMode::Contain | FitMode::Pad{..} => (maxwidth, maxheight),
+    }
+  }
+
+  // Size the input needs to be scaled to before it's fit into the output
+  fn scaled_size(&self, owidth: usize, oheight: usize) -> (usize, usize) {
+    let (width, height) = self.input_size;
+    let xscale = owidth as f32 / width as f32;
+    let yscale = oheight as f32 / height as f32;
+    let pad = matches!(self.mode, FitMode::Pad{..});
+    let scale = if pad {xscale.min(yscale)} else {xscale.max(yscale)};
+    let scale = if self.upscale {scale} else {scale.min(1.0)};
+    let swidth = ((width as f32 * scale).round() as usize).max(1);
+    let sheight = ((height as f32 * scale).round() as usize).max(1);
+    if pad {
+      (cmp::min(swidth, owidth), cmp::min(sheight, oheight))
+    } else {
+      (cmp::max(swidth, owidth), cmp::max(sheight, oheight))
+    }
+  }
+
+  // Offset and scale that take pixels of the output back to pixels of an input
+  // of a given size
+  fn reverse_geometry(&self, width: usize, height: usize) -> ((f32, f32), (f32, f32)) {
+    if !self.active() {
+      return ((0.0, 0.0), (1.0, 1.0))
+    }
+    let (owidth, oheight) = self.output_size(width, height);
+    let (owidth, oheight) = (owidth as f32, oheight as f32);
+    let (width, height) = (width as f32, height as f32);
+    match self.mode {
+      FitMode::Contain => ((0.0, 0.0), (1.0, 1.0)),
+      FitMode::Stretch => ((0.0, 0.0), (width / owidth, height / oheight)),
+      FitMode::Cover{gravity} => {
+        let scale = (width / owidth).min(height / oheight);
+        let x = (width - owidth * scale).max(0.0) * gravity.0.clamp(0.0, 1.0);
+        let y = (height - oheight * scale).max(0.0) * gravity.1.clamp(0.0, 1.0);
+        ((x, y), (scale, scale))
+      },
+      FitMode::Pad{..} => {
+        let scale = (owidth / width).min(oheight / height);
+        let x = (owidth - width * scale).max(0.0) / 2.0;
+        let y = (oheight - height * scale).max(0.0) / 2.0;
+        ((-x / scale, -y / scale), (1.0 / scale, 1.0 / scale))
+      },
+    }
+  }
+
+  /// Map a point in normalized coordinates of the output back to an input of
+  /// a given size
+  pub fn map_reverse(&self, x: f32, y: f32, width: usize, height: usize) -> (f32, f32) {
+    let ((offx, offy), (scalex, scaley)) = self.reverse_geometry(width, height);
+    let (owidth, oheight) = if self.active() {self.output_size(width, height)} else {(width, height)};
+    let x = (offx + x * owidth as f32 * scalex) / width as f32;
+    let y = (offy + y * oheight as f32 * scaley) / height as f32;
+    (x, y)
+  }
+
+  /// Size of a pixel of the output in pixels of an input of a given size
+  pub fn pixel_reverse(&self, width: usize, height: usize) -> f32 {
+    let (_, (scalex, scaley)) = self.reverse_geometry(width, height);
+    scalex.max(scaley)
+  }
+}
+
+impl<'a> ImageOp<'a> for OpFit {
+  fn name(&self) -> &str {"fit"}
+  fn run(&self, pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    if !self.active() {
+      return buf
+    }
+
+    let (owidth, oheight) = self.output_size(self.input_size.0, self.input_size.1);
+    let (width, height) = (buf.width, buf.height);
+    let resampling = pipeline.settings.resampling;
+    let scale_to = |buf: &OpBuffer, x: usize, y: usize, w: usize, h: usize, nw: usize, nh: usize| {
+      let (x, y) = (x as isize, y as isize);
+      buf.transform((x, y), (x + w as isize - 1, y), (x, y + h as isize - 1), nw, nh, resampling)
+    };
+
+    Arc::new(match self.mode {
+      FitMode::Contain => return buf,
+      FitMode::Stretch => scale_to(&buf, 0, 0, width, height, owidth, oheight),
+      FitMode::Cover{gravity} => {
+        // Crop the largest area with the aspect ratio of the output
+        let scale = (width as f32 / owidth as f32).min(height as f32 / oheight as f32);
+        let cwidth = ((owidth as f32 * scale).round() as usize).clamp(1, width);
+        let cheight = ((oheight as f32 * scale).round() as usize).clamp(1, height);
+        let x = ((width - cwidth) as f32 * gravity.0.clamp(0.0, 1.0)).round() as usize;
+        let y = ((height - cheight) as f32 * gravity.1.clamp(0.0, 1.0)).round() as usize;
+        scale_to(&buf, x, y, cwidth, cheight, owidth, oheight)
+      },
+      FitMode::Pad{color} => {
+        let scale = (owidth as f32 / width as f32).min(oheight as f32 / height as f32);
+        let iwidth = ((width as f32 * scale).round() as usize).clamp(1, owidth);
+        let iheight = ((height as f32 * scale).round() as usize).clamp(1, oheight);
+        let image = if (iwidth, iheight) != (width, height) {
+          Arc::new(scale_to(&buf, 0, 0, width, height, iwidth, iheight))
+        } else {
+          buf
+        };
+        let color = if pipeline.settings.linear {color.map(expand_srgb_gamma)} else {color};
+        let gray = color[0] == color[1] && color[1] == color[2];
+        let colors = image.colors;
+        let (x, y) = ((owidth - iwidth) / 2, (oheight - iheight) / 2);
+        let mut out = OpBuffer::new(owidth, oheight, colors, image.monochrome && gray);
+        out.mutate_lines(&(|line: &mut [f32], row| {
+          for (col, pix) in line.chunks_exact_mut(colors).enumerate() {
+            if col >= x && col < x + iwidth && row >= y && row < y + iheight {
+              let pos = ((row - y) * iwidth + (col - x)) * colors;
+              pix.copy_from_slice(&image.data[pos..pos+colors]);
+            } else {
+              for (c, v) in pix.iter_mut().enumerate() {
+                *v = if c < 3 {color[c]} else {0.0};
+              }
+            }
+          }
+        }));
+        out
+      },
+    })
+  }
+
+  fn run_alpha(&self, pipeline: &PipelineGlobals, alpha: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    match self.mode {
+      // The padding is filled with a color so it's opaque
+      FitMode::Pad{..} => OpFit{mode: FitMode::Pad{color: [1.0; 3]}, ..*self}.run(pipeline, alpha),
+      _ => self.run(pipeline, alpha),
+    }
+  }
+
+  fn transform_forward(&mut self, width: usize, height: usize) -> (usize, usize) {
+    self.input_size = (width, height);
+    if self.active() {
+      self.output_size(width, height)
+    } else {
+      (width, height)
+    }
+  }
+
+  fn transform_reverse(&mut self, width: usize, height: usize) -> (usize, usize) {
+    if self.active() {
+      self.scaled_size(width, height)
+    } else {
+      (width, height)
+    }
+  }
+
+  fn reset(&mut self) {
+    self.input_size = (0, 0);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Left half black and right half white
+  fn halves(width: usize, height: usize) -> Arc<OpBuffer> {
+    let mut buf = OpBuffer::new(width, height, 3, false);
+    buf.mutate_lines(&(|line: &mut [f32], _row| {
+      for (col, pix) in line.chunks_exact_mut(3).enumerate() {
+        let v = if col < width / 2 {0.0} else {1.0};
+        pix.copy_from_slice(&[v, v, v]);
+      }
+    }));
+    Arc::new(buf)
+  }
+
+  // Run the op on an image of a given full size, scaled like the pipeline would
+  fn fit(mode: FitMode, maxwidth: usize, maxheight: usize, size: (usize, usize)) -> Arc<OpBuffer> {
+    let mut globals = PipelineGlobals::mock(size.0 as u32, size.1 as u32);
+    globals.settings.fit = mode;
+    globals.settings.maxwidth = maxwidth;
+    globals.settings.maxheight = maxheight;
+    let mut op = OpFit::default();
+    op.set_target(&globals.settings);
+    let (owidth, oheight) = op.transform_forward(size.0, size.1);
+    let (width, height) = op.transform_reverse(owidth, oheight);
+    op.run(&globals, halves(width, height))
+  }
+
+  #[test]
+  fn cover_gravity() {
+    let out = fit(FitMode::Cover{gravity: (0.0, 0.5)}, 10, 10, (40, 20));
+    assert_eq!((out.width, out.height), (10, 10));
+    assert!(out.data.iter().all(|v| *v == 0.0));
+    let out = fit(FitMode::Cover{gravity: (1.0, 0.5)}, 10, 10, (40, 20));
+    assert!(out.data.iter().all(|v| *v == 1.0));
+  }
+
+  #[test]
+  fn pad_and_stretch() {
+    let out = fit(FitMode::Pad{color: [1.0, 0.0, 0.0]}, 20, 20, (20, 10));
+    assert_eq!((out.width, out.height), (20, 20));
+    assert_eq!(&out.data[0..3], &[1.0, 0.0, 0.0]);
+    assert_eq!(&out.data[(10*20)*3..(10*20)*3+3], &[0.0, 0.0, 0.0]);
+    assert_eq!(&out.data[(10*20+19)*3..(10*20+19)*3+3], &[1.0, 1.0, 1.0]);
+    assert!(!out.monochrome);
+
+    let out = fit(FitMode::Stretch, 10, 10, (20, 10));
+    assert_eq!((out.width, out.height), (10, 10));
+    // Contain is done by the normal scaling
+    let out = fit(FitMode::Contain, 10, 10, (20, 10));
+    assert_eq!((out.width, out.height), (20, 10));
+  }
+}
@@ -1,13 +1,49 @@
 use crate::opbasics::*;
 use crate::color_conversions::*;
 
+/// Transfer function used to encode the output
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TransferFunction {
+  /// Standard sRGB gamma, clipping everything outside of 0.0-1.0
+  #[default]
+  SRGB,
+  /// Rec.2100 perceptual quantizer, encoding absolute luminance
+  PQ,
+  /// Rec.2100 hybrid log-gamma, encoding luminance relative to the peak
+  HLG,
+}
+
+fn default_reference_white() -> f32 { 203.0 }
+fn default_peak_luminance() -> f32 { 1000.0 }
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct OpGamma {
+  #[serde(default)]
+  pub transfer: TransferFunction,
+  /// Luminance in cd/m^2 that a value of 1.0 maps to in the HDR encodings
+  #[serde(default = "default_reference_white")]
+  pub reference_white: f32,
+  /// Maximum luminance in cd/m^2 of the HDR encodings, anything above is clipped
+  #[serde(default = "default_peak_luminance")]
+  pub peak_luminance: f32,
 }
 
 impl<'a> OpGamma {
   pub fn new(_img: &ImageSource) -> OpGamma {
-    OpGamma{}
+    OpGamma{
+      transfer: TransferFunction::SRGB,
+      reference_white: default_reference_white(),
+      peak_luminance: default_peak_luminance(),
+    }
+  }
+
+  /// The linear value above which the output encoding clips
+  pub fn white(&self) -> f32 {
+    match self.transfer {
+      TransferFunction::SRGB => 1.0,
+      TransferFunction::PQ => self.peak_luminance.min(PQ_MAX_NITS) / self.reference_white,
+      TransferFunction::HLG => self.peak_luminance / self.reference_white,
+    }
   }
 }
 
@@ -15,13 +51,88 @@ impl<'a> ImageOp<'a> for OpGamma {
   fn name(&self) -> &str {"gamma"}
   fn run(&self, pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
     if pipeline.settings.linear {
-      buf
-    } else {
-      Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], _| {
-        for pix in line.chunks_exact_mut(1) {
-          pix[0] = apply_srgb_gamma(pix[0].max(0.0).min(1.0));
-        }
-      })))
+      return buf
+    }
+
+    match self.transfer {
+      TransferFunction::SRGB => {
+        Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], _| {
+          for pix in line.chunks_exact_mut(1) {
+            pix[0] = apply_srgb_gamma(pix[0].max(0.0).min(1.0));
+          }
+        })))
+      },
+      TransferFunction::PQ => {
+        let scale = self.reference_white / PQ_MAX_NITS;
+        let max = self.peak_luminance.min(PQ_MAX_NITS) / PQ_MAX_NITS;
+        Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], _| {
+          for pix in line.chunks_exact_mut(1) {
+            pix[0] = apply_pq((pix[0] * scale).min(max));
+          }
+        })))
+      },
+      TransferFunction::HLG => {
+        let scale = self.reference_white / self.peak_luminance;
+        Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], _| {
+          for pix in line.chunks_exact_mut(1) {
+            pix[0] = apply_hlg(pix[0] * scale);
+          }
+        })))
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn run_values(op: &OpGamma, values: &[f32]) -> Vec<f32> {
+    let mut buf = OpBuffer::new(values.len(), 1, 1, false);
+    buf.data.copy_from_slice(values);
+    let globals = PipelineGlobals::mock(10, 10);
+    op.run(&globals, Arc::new(buf)).data.clone()
+  }
+
+  #[test]
+  fn sdr_clips() {
+    let op = OpGamma::new(&PipelineGlobals::mock(10, 10).image);
+    let out = run_values(&op, &[0.0, 1.0, 4.0]);
+    assert!(out[0].abs() < 0.0001);
+    assert!((out[1] - 1.0).abs() < 0.0001);
+    assert_eq!(out[1], out[2]);
+  }
+
+  #[test]
+  fn pq_keeps_highlights() {
+    let mut op = OpGamma::new(&PipelineGlobals::mock(10, 10).image);
+    op.transfer = TransferFunction::PQ;
+    let out = run_values(&op, &[1.0, 4.0, 100.0]);
+    assert!((out[0] - apply_pq(203.0 / PQ_MAX_NITS)).abs() < 0.0001);
+    assert!(out[1] > out[0]);
+    // Anything above peak luminance gets clipped there
+    assert!((out[2] - apply_pq(1000.0 / PQ_MAX_NITS)).abs() < 0.0001);
+  }
+
+  #[test]
+  fn white_is_where_encoding_clips() {
+    let mut op = OpGamma::new(&PipelineGlobals::mock(10, 10).image);
+    assert_eq!(op.white(), 1.0);
+    for transfer in [TransferFunction::PQ, TransferFunction::HLG] {
+      op.transfer = transfer;
+      let white = op.white();
+      let out = run_values(&op, &[white * 0.9, white, white * 1.1]);
+      assert!(out[0] < out[1], "{:?} clips below {}", transfer, white);
+      assert!((out[1] - out[2]).abs() < 0.0001, "{:?} doesn't clip at {}", transfer, white);
     }
   }
+
+  #[test]
+  fn hlg_keeps_highlights() {
+    let mut op = OpGamma::new(&PipelineGlobals::mock(10, 10).image);
+    op.transfer = TransferFunction::HLG;
+    let out = run_values(&op, &[1.0, 2.0, 100.0]);
+    assert!(out[1] > out[0]);
+    assert!((out[2] - 1.0).abs() < 0.0001);
+  }
 }
@@ -43,6 +43,65 @@ impl OpGoFloat {
       }
     }
   }
+
+  /// Set the black levels from the optically masked areas of the sensor
+  ///
+  /// These are usually cropped away but measuring them gives the actual black
+  /// level of this image instead of the fixed one for the camera. Returns false
+  /// and keeps the existing levels if the image has no masked areas.
+  pub fn set_masked_blacklevels(&mut self, img: &ImageSource) -> bool {
+    let img = match img {
+      ImageSource::Raw(img) => img,
+      ImageSource::Other(_) => return false,
+    };
+    let levels = match img.data {
+      RawImageData::Integer(ref data) => {
+        let data: Vec<f32> = data.iter().map(|v| *v as f32).collect();
+        masked_blacklevels(img, &data, self.blacklevels)
+      },
+      RawImageData::Float(ref data) => masked_blacklevels(img, data, self.blacklevels),
+    };
+    match levels {
+      Some(levels) => {
+        self.blacklevels = levels;
+        true
+      },
+      None => false,
+    }
+  }
+}
+
+// Average of each color in the masked areas, with colors that aren't found
+// in them keeping their current level
+fn masked_blacklevels(img: &RawImage, data: &[f32], current: [f32;4]) -> Option<[f32;4]> {
+  area_averages(data, img.width, img.height, img.cpp, &img.cfa, &img.blackareas, current)
+}
+
+fn area_averages(data: &[f32], width: usize, height: usize, cpp: usize, cfa: &CFA,
+                 areas: &[(usize, usize, usize, usize)], current: [f32;4]) -> Option<[f32;4]> {
+  let mut sums = [0.0f64; 4];
+  let mut counts = [0u64; 4];
+  for &(x, y, w, h) in areas.iter() {
+    for row in y..cmp::min(y+h, height) {
+      for col in x..cmp::min(x+w, width) {
+        for c in 0..cpp {
+          let color = if cpp > 1 {c} else {cfa.color_at(row, col)};
+          sums[color] += data[(row*width+col)*cpp+c] as f64;
+          counts[color] += 1;
+        }
+      }
+    }
+  }
+  if counts.iter().all(|&c| c == 0) {
+    return None
+  }
+  let mut levels = current;
+  for (i, level) in levels.iter_mut().enumerate() {
+    if counts[i] > 0 {
+      *level = (sums[i] / counts[i] as f64) as f32;
+    }
+  }
+  Some(levels)
 }
 
 impl<'a> ImageOp<'a> for OpGoFloat {
@@ -53,7 +112,11 @@ impl<'a> ImageOp<'a> for OpGoFloat {
 
     match &pipeline.image {
       ImageSource::Raw(img) => {
-        self.run_raw(img)
+        if let Some(data) = pipeline.calibration.apply(img, self.blacklevels) {
+          self.run_raw(img, &data)
+        } else {
+          self.run_raw(img, &img.data)
+        }
       },
       ImageSource::Other(img) => {
         self.run_other(img)
@@ -61,6 +124,21 @@ impl<'a> ImageOp<'a> for OpGoFloat {
     }
   }
 
+  // Start the alpha plane from the source, ignoring the empty input
+  fn run_alpha(&self, pipeline: &PipelineGlobals, _alpha: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    match &pipeline.image {
+      ImageSource::Raw(img) => {
+        let (_, _, width, height) = self.size_image(img.width, img.height);
+        let mut out = OpBuffer::new(width, height, 1, true);
+        out.data.fill(1.0);
+        Arc::new(out)
+      },
+      ImageSource::Other(img) => {
+        self.run_other_alpha(img)
+      }
+    }
+  }
+
   fn transform_forward(&mut self, width: usize, height: usize) -> (usize, usize) {
     let (_, _, width, height) = self.size_image(width, height);
     (width, height)
@@ -81,7 +159,7 @@ impl OpGoFloat {
     (x, y, width, height)
   }
 
-  fn run_raw(&self, img: &RawImage) -> Arc<OpBuffer> {
+  fn run_raw(&self, img: &RawImage, data: &RawImageData) -> Arc<OpBuffer> {
     // Calculate the levels
     let mins = self.blacklevels;
     let ranges = self.whitelevels.iter().enumerate().map(|(i, &x)| {
@@ -92,7 +170,7 @@ impl OpGoFloat {
     let oheight = img.height;
     let (x, y, width, height) = self.size_image(owidth, oheight);
 
-    Arc::new(match img.data {
+    Arc::new(match *data {
       RawImageData::Integer(ref data) => {
         if img.cpp == 1 && !self.is_cfa {
           // We're in a monochrome image so turn it into RGB
@@ -120,10 +198,12 @@ impl OpGoFloat {
           }));
           out
         } else {
+          // Levels can be different for each color so use the ones for the CFA position
           let mut out = OpBuffer::new(width, height, img.cpp, false);
           out.mutate_lines(&(|line: &mut [f32], row| {
-            for (o, i) in line.chunks_exact_mut(1).zip(data[owidth*(row+y)+x..].chunks_exact(1)) {
-              o[0] = ((i[0] as f32 - mins[0]) / ranges[0]).min(1.0);
+            for (col, (o, i)) in line.chunks_exact_mut(1).zip(data[owidth*(row+y)+x..].chunks_exact(1)).enumerate() {
+              let color = img.cfa.color_at(row+y, col+x);
+              o[0] = ((i[0] as f32 - mins[color]) / ranges[color]).min(1.0);
             }
           }));
           out
@@ -156,10 +236,12 @@ impl OpGoFloat {
           }));
           out
         } else {
+          // Levels can be different for each color so use the ones for the CFA position
           let mut out = OpBuffer::new(width, height, img.cpp, false);
           out.mutate_lines(&(|line: &mut [f32], row| {
-            for (o, i) in line.chunks_exact_mut(1).zip(data[owidth*(row+y)+x..].chunks_exact(1)) {
-              o[0] = ((i[0] as f32 - mins[0]) / ranges[0]).min(1.0);
+            for (col, (o, i)) in line.chunks_exact_mut(1).zip(data[owidth*(row+y)+x..].chunks_exact(1)).enumerate() {
+              let color = img.cfa.color_at(row+y, col+x);
+              o[0] = ((i[0] as f32 - mins[color]) / ranges[color]).min(1.0);
             }
           }));
           out
@@ -168,6 +250,40 @@ impl OpGoFloat {
     })
   }
 
+  fn run_other_alpha(&self, img: &OtherImage) -> Arc<OpBuffer> {
+    let owidth = img.width() as usize;
+    let oheight = img.height() as usize;
+    let (x, y, width, height) = self.size_image(owidth, oheight);
+    let mut out = OpBuffer::new(width, height, 1, true);
+    let bits_per_channel = img.color().bits_per_pixel() / img.color().channel_count() as u16;
+
+    if !img.color().has_alpha() {
+      out.data.fill(1.0);
+    } else if bits_per_channel == 32 {
+      let data = img.to_rgba32f().into_raw();
+      out.mutate_lines(&(|line: &mut [f32], row| {
+        for (o, i) in line.iter_mut().zip(data[(owidth*(row+y)+x)*4..].chunks_exact(4)) {
+          *o = i[3];
+        }
+      }));
+    } else if bits_per_channel == 8 {
+      let data = img.to_rgba8().into_raw();
+      out.mutate_lines(&(|line: &mut [f32], row| {
+        for (o, i) in line.iter_mut().zip(data[(owidth*(row+y)+x)*4..].chunks_exact(4)) {
+          *o = input8bit(i[3]);
+        }
+      }));
+    } else {
+      let data = img.to_rgba16().into_raw();
+      out.mutate_lines(&(|line: &mut [f32], row| {
+        for (o, i) in line.iter_mut().zip(data[(owidth*(row+y)+x)*4..].chunks_exact(4)) {
+          *o = input16bit(i[3]);
+        }
+      }));
+    }
+    Arc::new(out)
+  }
+
   fn run_other(&self, img: &OtherImage) -> Arc<OpBuffer> {
     let owidth = img.width() as usize;
     let oheight = img.height() as usize;
@@ -175,7 +291,18 @@ impl OpGoFloat {
     let mut out = OpBuffer::new(width, height, 4, false);
     let bits_per_channel = img.color().bits_per_pixel() / img.color().channel_count() as u16;
 
-    if bits_per_channel == 8 {
+    if bits_per_channel == 32 {
+      // Float images are linear and can go above 1.0 so they're used as they are
+      let data = img.to_rgb32f().into_raw();
+      out.mutate_lines(&(|line: &mut [f32], row| {
+        for (o, i) in line.chunks_exact_mut(4).zip(data[(owidth*(row+y)+x)*3..].chunks_exact(3)) {
+          o[0] = i[0];
+          o[1] = i[1];
+          o[2] = i[2];
+          o[3] = 0.0;
+        }
+      }));
+    } else if bits_per_channel == 8 {
       let data = img.to_rgb8().into_raw();
       out.mutate_lines(&(|line: &mut [f32], row| {
         for (o, i) in line.chunks_exact_mut(4).zip(data[(owidth*(row+y)+x)*3..].chunks_exact(3)) {
@@ -200,3 +327,33 @@ impl OpGoFloat {
     Arc::new(out)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn masked_area_averages() {
+    let cfa = CFA::new("RGGB");
+    // 6x4 image with the two left columns masked
+    let mut data = vec![1000.0; 24];
+    for row in 0..4 {
+      data[row*6] = if row % 2 == 0 {100.0 + row as f32} else {200.0};
+      data[row*6+1] = if row % 2 == 0 {200.0} else {300.0};
+    }
+    let current = [0.0, 0.0, 0.0, 50.0];
+    let levels = area_averages(&data, 6, 4, 1, &cfa, &[(0, 0, 2, 4)], current);
+    assert_eq!(levels, Some([101.0, 200.0, 300.0, 50.0]));
+    assert_eq!(area_averages(&data, 6, 4, 1, &cfa, &[], current), None);
+  }
+
+  #[test]
+  fn float_images_unclamped() {
+    let img = image::Rgb32FImage::from_pixel(16, 16, image::Rgb([4.5, 0.25, 0.0]));
+    let img = OtherImage::ImageRgb32F(img);
+    let op = OpGoFloat::new(&ImageSource::Other(img.clone()));
+    let buf = op.run_other(&img);
+    assert_eq!((buf.width, buf.height, buf.colors), (16, 16, 4));
+    assert_eq!(&buf.data[0..4], &[4.5, 0.25, 0.0, 0.0]);
+  }
+}
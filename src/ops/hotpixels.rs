@@ -0,0 +1,236 @@
+use crate::opbasics::*;
+
+use std::fs;
+use std::path::Path;
+
+/// Repair of hot and dead pixels in the raw data
+///
+/// Runs between gofloat and demosaic and compares each pixel to its nearest
+/// neighbours of the same CFA color. Pixels that stand out from all of them by
+/// more than the threshold, or that are in the bad pixel map, are replaced by
+/// the median of those neighbours.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OpHotPixels {
+  /// Find outliers automatically, otherwise only the bad pixel map is used
+  pub detect: bool,
+  /// How far a pixel needs to be above or below all its neighbours to be
+  /// considered broken, in the 0.0-1.0 range of the raw data
+  pub threshold: f32,
+  /// Known bad pixels as (column, row) in the uncropped sensor data
+  pub badpixels: Vec<(usize, usize)>,
+  pub cfa: String,
+  pub crop_top: usize,
+  pub crop_left: usize,
+}
+
+impl Default for OpHotPixels {
+  fn default() -> Self {
+    Self {
+      detect: false,
+      threshold: 0.1,
+      badpixels: Vec::new(),
+      cfa: "".to_string(),
+      crop_top: 0,
+      crop_left: 0,
+    }
+  }
+}
+
+impl OpHotPixels {
+  pub fn new(img: &ImageSource) -> OpHotPixels {
+    match img {
+      ImageSource::Raw(img) => {
+        OpHotPixels {
+          cfa: img.cropped_cfa().to_string(),
+          crop_top: img.crops[0],
+          crop_left: img.crops[3],
+          ..Self::default()
+        }
+      },
+      ImageSource::Other(_) => Self::default(),
+    }
+  }
+
+  /// Load a bad pixel map in the dcraw format
+  ///
+  /// Each line has the column and row of a pixel, optionally followed by other
+  /// values such as the time it was found, and # starts a comment.
+  pub fn load_badpixels<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+    let contents = fs::read_to_string(&path).map_err(|e| {
+      format!("imagepipe: couldn't read bad pixel file: {}", e)
+    })?;
+    self.badpixels = parse_badpixels(&contents)?;
+    Ok(())
+  }
+}
+
+fn parse_badpixels(contents: &str) -> Result<Vec<(usize, usize)>, String> {
+  let mut pixels = Vec::new();
+  for (i, line) in contents.lines().enumerate() {
+    let line = line.split('#').next().unwrap().trim();
+    if line.is_empty() {
+      continue
+    }
+    let vals: Vec<&str> = line.split_whitespace().collect();
+    if vals.len() < 2 {
+      return Err(format!("imagepipe: expected column and row in line {}", i+1))
+    }
+    let parse = |v: &str| v.parse::<usize>().map_err(|_| {
+      format!("imagepipe: invalid bad pixel position in line {}", i+1)
+    });
+    pixels.push((parse(vals[0])?, parse(vals[1])?));
+  }
+  Ok(pixels)
+}
+
+impl<'a> ImageOp<'a> for OpHotPixels {
+  fn name(&self) -> &str {"hotpixels"}
+  fn run(&self, _pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    if !self.detect && self.badpixels.is_empty() {
+      return buf
+    }
+
+    let (width, height, colors) = (buf.width, buf.height, buf.colors);
+    let cfa = CFA::new(&self.cfa);
+    // For each position in the CFA pattern find the closest offsets with the same
+    // color, for non-CFA images just use the surrounding pixels
+    let (pwidth, pheight) = if colors == 1 && cfa.is_valid() {(cfa.width, cfa.height)} else {(1, 1)};
+    let mut offsets = vec![Vec::new(); pwidth*pheight];
+    for row in 0..pheight {
+      for col in 0..pwidth {
+        let mut list: Vec<(isize, isize)> = Vec::new();
+        for dy in -2isize..=2 {
+          for dx in -2isize..=2 {
+            if (dx, dy) == (0, 0) {
+              continue
+            }
+            let same = if pwidth == 1 {
+              dx.abs() <= 1 && dy.abs() <= 1
+            } else {
+              // Offset by the pattern size so we never go below zero
+              let nrow = (row + pheight*2) as isize + dy;
+              let ncol = (col + pwidth*2) as isize + dx;
+              cfa.color_at(nrow as usize, ncol as usize) == cfa.color_at(row, col)
+            };
+            if same {
+              list.push((dx, dy));
+            }
+          }
+        }
+        list.sort_by_key(|(dx, dy)| dx*dx + dy*dy);
+        list.truncate(8);
+        offsets[row*pwidth+col] = list;
+      }
+    }
+
+    let mut bad = vec![false; width*height];
+    for (col, row) in self.badpixels.iter() {
+      if *col >= self.crop_left && *row >= self.crop_top {
+        let (x, y) = (col - self.crop_left, row - self.crop_top);
+        if x < width && y < height {
+          bad[y*width+x] = true;
+        }
+      }
+    }
+
+    let threshold = self.threshold;
+    let detect = self.detect;
+    Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], row| {
+      let mut neighbours = Vec::with_capacity(8);
+      for col in 0..width {
+        let offsets = &offsets[(row % pheight)*pwidth + (col % pwidth)];
+        for c in 0..colors {
+          neighbours.clear();
+          for (dx, dy) in offsets.iter() {
+            let (x, y) = (col as isize + dx, row as isize + dy);
+            if x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height {
+              let pos = y as usize * width + x as usize;
+              if !bad[pos] {
+                neighbours.push(buf.data[pos*colors + c]);
+              }
+            }
+          }
+          if neighbours.is_empty() {
+            continue
+          }
+
+          let value = line[col*colors + c];
+          let broken = bad[row*width+col] || (detect && {
+            let max = neighbours.iter().cloned().fold(f32::MIN, f32::max);
+            let min = neighbours.iter().cloned().fold(f32::MAX, f32::min);
+            value - max > threshold || min - value > threshold
+          });
+          if broken {
+            neighbours.sort_by(|a, b| a.partial_cmp(b).unwrap_or(cmp::Ordering::Equal));
+            let mid = neighbours.len() / 2;
+            line[col*colors + c] = if neighbours.len() % 2 == 0 {
+              (neighbours[mid-1] + neighbours[mid]) / 2.0
+            } else {
+              neighbours[mid]
+            };
+          }
+        }
+      }
+    })))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn bayer() -> (OpHotPixels, Arc<OpBuffer>) {
+    let op = OpHotPixels{cfa: "RGGB".to_string(), ..OpHotPixels::default()};
+    let mut buf = OpBuffer::new(10, 10, 1, false);
+    let cfa = CFA::new("RGGB");
+    buf.mutate_lines(&(|line: &mut [f32], row| {
+      for (col, pix) in line.iter_mut().enumerate() {
+        *pix = match cfa.color_at(row, col) {
+          0 => 0.2,
+          1 => 0.4,
+          _ => 0.1,
+        };
+      }
+    }));
+    (op, Arc::new(buf))
+  }
+
+  #[test]
+  fn detects_hot_and_dead() {
+    let (mut op, buf) = bayer();
+    let mut buf = (*buf).clone();
+    buf.data[4*10+4] = 1.0; // Red pixel
+    buf.data[5*10+4] = 0.0; // Green pixel
+    op.detect = true;
+    let out = op.run(&PipelineGlobals::mock(10, 10), Arc::new(buf));
+    assert_eq!(out.data[4*10+4], 0.2);
+    assert_eq!(out.data[5*10+4], 0.4);
+  }
+
+  #[test]
+  fn threshold_and_detect_off() {
+    let (mut op, buf) = bayer();
+    let mut buf = (*buf).clone();
+    buf.data[4*10+4] = 0.25;
+    let buf = Arc::new(buf);
+    op.detect = true;
+    let out = op.run(&PipelineGlobals::mock(10, 10), buf.clone());
+    assert_eq!(out.data[4*10+4], 0.25);
+    op.detect = false;
+    assert!(Arc::ptr_eq(&buf, &op.run(&PipelineGlobals::mock(10, 10), buf.clone())));
+  }
+
+  #[test]
+  fn bad_pixel_map() {
+    let (mut op, buf) = bayer();
+    let mut buf = (*buf).clone();
+    buf.data[3*10+6] = 0.25;
+    op.badpixels = parse_badpixels("# col row time\n7 4 1600000000\n").unwrap();
+    op.crop_left = 1;
+    op.crop_top = 1;
+    let out = op.run(&PipelineGlobals::mock(10, 10), Arc::new(buf));
+    assert_eq!(out.data[3*10+6], 0.4);
+    assert!(parse_badpixels("7\n").is_err());
+    assert!(parse_badpixels("7 x\n").is_err());
+  }
+}
@@ -0,0 +1,245 @@
+use crate::opbasics::*;
+use crate::color_conversions::*;
+use crate::masks::GeometricMask;
+use crate::ops::curves::SplineFunc;
+use crate::ops::rotatecrop::OpRotateCrop;
+use std::io::Write;
+
+/// An adjustment restricted to part of the image by a mask
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LocalAdjustment {
+  pub mask: GeometricMask,
+  /// Apply the adjustment outside the mask instead of inside
+  pub invert: bool,
+  /// Exposure change in EV
+  pub exposure: f32,
+  /// Chroma multiplier, 1.0 leaves it alone and 0.0 is black and white
+  pub saturation: f32,
+  /// Curve applied to L, empty for none
+  pub points: Vec<(f32, f32)>,
+  /// Temperature and tint of the light to correct for, the same as in
+  /// `OpToLab::set_temp()`, with a temperature of 0.0 for no change
+  pub temperature: f32,
+  pub tint: f32,
+}
+
+impl LocalAdjustment {
+  pub fn new(mask: GeometricMask) -> LocalAdjustment {
+    LocalAdjustment {
+      mask,
+      invert: false,
+      exposure: 0.0,
+      saturation: 1.0,
+      points: Vec::new(),
+      temperature: 0.0,
+      tint: 1.0,
+    }
+  }
+
+  // Multipliers for linear sRGB that do the exposure and white balance changes
+  fn multipliers(&self) -> Option<[f32;3]> {
+    let exposure = self.exposure.exp2();
+    if self.temperature <= 0.0 {
+      if self.exposure.abs() < 0.001 {
+        None
+      } else {
+        Some([exposure; 3])
+      }
+    } else {
+      let (r, g, b) = temp_tint_to_rgb(self.temperature, self.tint);
+      Some([exposure * g / r, exposure, exposure * g / b])
+    }
+  }
+}
+
+/// Exposure, saturation, curve and white balance changes restricted by masks
+///
+/// Runs on the Lab buffer after the base curve, which has already been rotated
+/// and cropped, so the masks get mapped through the rotation and crop to stay on
+/// the same part of the image.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OpLocalAdjust {
+  pub adjustments: Vec<LocalAdjustment>,
+  // Copy of the rotation and crop for the current run, set by the pipeline.
+  // It's not part of the settings but is included in the hash.
+  #[serde(skip, default = "OpRotateCrop::empty")]
+  rotatecrop: OpRotateCrop,
+}
+
+impl Default for OpLocalAdjust {
+  fn default() -> Self {
+    Self {
+      adjustments: Vec::new(),
+      rotatecrop: OpRotateCrop::empty(),
+    }
+  }
+}
+
+impl OpLocalAdjust {
+  pub fn new(_img: &ImageSource) -> OpLocalAdjust {
+    Self::default()
+  }
+
+  pub(crate) fn set_geometry(&mut self, rotatecrop: &OpRotateCrop) {
+    self.rotatecrop = *rotatecrop;
+  }
+}
+
+impl<'a> ImageOp<'a> for OpLocalAdjust {
+  fn name(&self) -> &str {"localadjust"}
+  fn hash(&self, hasher: &mut BufHasher) {
+    hasher.write_all(self.name().as_bytes()).unwrap();
+    hasher.from_serialize(self);
+    // Where the masks end up depends on the rotation and crop
+    hasher.from_serialize(&self.rotatecrop);
+  }
+  fn run(&self, _pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    if self.adjustments.is_empty() {
+      return buf
+    }
+
+    // Get the masks into pixels of this buffer
+    let (iwidth, iheight) = self.rotatecrop.input_size(buf.width, buf.height);
+    let masks: Vec<GeometricMask> = self.adjustments.iter().map(|adj| {
+      adj.mask.map(|x, y| {
+        self.rotatecrop.point_forward(x * iwidth as f32, y * iheight as f32, iwidth, iheight)
+      })
+    }).collect();
+    let curves: Vec<Option<SplineFunc>> = self.adjustments.iter().map(|adj| {
+      if adj.points.is_empty() {None} else {Some(SplineFunc::new(&adj.points))}
+    }).collect();
+    let multipliers: Vec<Option<[f32;3]>> = self.adjustments.iter().map(|adj| {
+      adj.multipliers()
+    }).collect();
+    let rgbmatrix = *XYZ_D65_33;
+    let labmatrix = *SRGB_D65_43;
+    let neutral = 127.0 / 255.0;
+
+    Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], row| {
+      for (col, pix) in line.chunks_exact_mut(3).enumerate() {
+        for (i, adj) in self.adjustments.iter().enumerate() {
+          let value = masks[i].value(col as f32, row as f32);
+          let weight = if adj.invert {1.0 - value} else {value};
+          if weight <= 0.0 {
+            continue
+          }
+
+          let (mut l, mut a, mut b) = (pix[0], pix[1], pix[2]);
+          if let Some(mul) = multipliers[i] {
+            let (r, g, bl) = lab_to_rgb(rgbmatrix, &[l, a, b]);
+            let rgb = [r * mul[0], g * mul[1], bl * mul[2], 0.0];
+            let lab = camera_to_lab([1.0, 1.0, 1.0, 0.0], labmatrix, &rgb);
+            l = lab.0; a = lab.1; b = lab.2;
+          }
+          a = neutral + (a - neutral) * adj.saturation;
+          b = neutral + (b - neutral) * adj.saturation;
+          if let Some(ref curve) = curves[i] {
+            l = curve.interpolate(l);
+          }
+
+          pix[0] += (l - pix[0]) * weight;
+          pix[1] += (a - pix[1]) * weight;
+          pix[2] += (b - pix[2]) * weight;
+        }
+      }
+    })))
+  }
+
+  fn reset(&mut self) {
+    self.rotatecrop = OpRotateCrop::empty();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn gray_buffer() -> Arc<OpBuffer> {
+    gray_buffer_sized(100, 100)
+  }
+
+  fn gray_buffer_sized(width: usize, height: usize) -> Arc<OpBuffer> {
+    let mut buf = OpBuffer::new(width, height, 3, false);
+    for pix in buf.data.chunks_exact_mut(3) {
+      pix[0] = 0.5;
+      pix[1] = 127.0 / 255.0;
+      pix[2] = 127.0 / 255.0;
+    }
+    Arc::new(buf)
+  }
+
+  fn at(buf: &OpBuffer, x: usize, y: usize) -> &[f32] {
+    &buf.data[(y*buf.width+x)*3..(y*buf.width+x)*3+3]
+  }
+
+  #[test]
+  fn graduated_exposure() {
+    let mut op = OpLocalAdjust::default();
+    let mut adj = LocalAdjustment::new(GeometricMask::Linear{start: (0.0, 0.2), end: (0.0, 0.6)});
+    adj.exposure = -1.0;
+    op.adjustments.push(adj);
+    let out = op.run(&PipelineGlobals::mock(100, 100), gray_buffer());
+    assert!(at(&out, 50, 0)[0] < 0.45);
+    assert!(at(&out, 50, 40)[0] < 0.5 && at(&out, 50, 40)[0] > at(&out, 50, 0)[0]);
+    assert_eq!(at(&out, 50, 90)[0], 0.5);
+  }
+
+  #[test]
+  fn radial_follows_crop() {
+    let mut op = OpLocalAdjust::default();
+    let mut adj = LocalAdjustment::new(GeometricMask::Radial{
+      center: (0.6, 0.6), axis: (0.65, 0.6), ratio: 1.0, feather: 0.0,
+    });
+    adj.points = vec![(0.0, 0.0), (0.5, 0.8), (1.0, 1.0)];
+    op.adjustments.push(adj);
+
+    // Cropping away the top left of the image moves the mask there
+    let mut rotatecrop = OpRotateCrop::empty();
+    rotatecrop.crop_left = 0.5;
+    rotatecrop.crop_top = 0.5;
+    op.set_geometry(&rotatecrop);
+    let out = op.run(&PipelineGlobals::mock(100, 100), gray_buffer_sized(50, 50));
+    assert!((at(&out, 10, 10)[0] - 0.8).abs() < 0.001);
+    assert_eq!(at(&out, 30, 30)[0], 0.5);
+  }
+
+  #[test]
+  fn saturation_and_invert() {
+    let mut op = OpLocalAdjust::default();
+    let mut adj = LocalAdjustment::new(GeometricMask::Linear{start: (0.0, 0.5), end: (0.0, 0.6)});
+    adj.saturation = 0.0;
+    adj.invert = true;
+    op.adjustments.push(adj);
+    let mut buf = gray_buffer().as_ref().clone();
+    for pix in buf.data.chunks_exact_mut(3) {
+      pix[1] = 0.7;
+    }
+    let out = op.run(&PipelineGlobals::mock(100, 100), Arc::new(buf));
+    assert_eq!(at(&out, 0, 0)[1], 0.7);
+    assert!((at(&out, 0, 90)[1] - 127.0/255.0).abs() < 0.0001);
+  }
+
+  #[test]
+  fn geometry_hashed_not_serialized() {
+    let mut op = OpLocalAdjust::default();
+    op.adjustments.push(LocalAdjustment::new(GeometricMask::Linear{start: (0.0, 0.2), end: (0.0, 0.6)}));
+    let settings = op.to_settings();
+    let mut before = BufHasher::new();
+    op.hash(&mut before);
+
+    let mut rotatecrop = OpRotateCrop::empty();
+    rotatecrop.crop_left = 0.5;
+    op.set_geometry(&rotatecrop);
+    assert_eq!(op.to_settings(), settings);
+    let mut after = BufHasher::new();
+    op.hash(&mut after);
+    assert_ne!(before.result(), after.result());
+  }
+
+  #[test]
+  fn no_adjustments_is_noop() {
+    let op = OpLocalAdjust::default();
+    let buf = gray_buffer();
+    assert!(Arc::ptr_eq(&buf, &op.run(&PipelineGlobals::mock(100, 100), buf.clone())));
+  }
+}
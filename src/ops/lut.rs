@@ -0,0 +1,546 @@
+use crate::opbasics::*;
+use crate::color_conversions::*;
+
+use image::{DynamicImage, ImageBuffer};
+use std::fs;
+use std::path::Path;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LutInterpolation {
+  Trilinear,
+  Tetrahedral,
+}
+
+/// What values the table expects as input and produces as output
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LutSpace {
+  /// Linear RGB as it comes out of the previous op
+  Linear,
+  /// sRGB gamma encoded RGB, the values are decoded again after the lookup
+  Encoded,
+}
+
+/// Where in the pipeline the table is applied
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum LutPosition {
+  /// Scene referred values right after conversion from the camera colors,
+  /// before the base curve or any other tone change. The op converts to linear
+  /// sRGB for the lookup and back to Lab.
+  AfterToLab,
+  /// Linear RGB after the Lab adjustments and before filmic tone mapping
+  AfterFromLab,
+  /// Linear RGB after filmic and before the output encoding
+  #[default]
+  AfterFilmic,
+  /// The values after the output encoding of the gamma op, as tables made for
+  /// sRGB, PQ or HLG output expect. The space is only used if the output is
+  /// linear.
+  Encoded,
+}
+
+/// A 1D or 3D color lookup table
+///
+/// The table is a Vec<f32> of RGB triplets. 1D tables have size entries with one
+/// curve per channel. 3D tables have size*size*size entries with red changing
+/// fastest and blue slowest, the same order as in .cube files.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Lut {
+  pub title: String,
+  pub size: usize,
+  pub is_3d: bool,
+  pub domain_min: [f32;3],
+  pub domain_max: [f32;3],
+  pub table: Vec<f32>,
+}
+
+fn parse_floats(vals: &[&str], linenum: usize) -> Result<Vec<f32>, String> {
+  vals.iter().map(|v| {
+    v.parse::<f32>().map_err(|_| format!("imagepipe: invalid number \"{}\" in line {}", v, linenum))
+  }).collect()
+}
+
+impl Lut {
+  /// Parse the contents of an Adobe/Resolve .cube file
+  pub fn from_cube_str(contents: &str) -> Result<Lut, String> {
+    let mut title = String::new();
+    let mut size = 0;
+    let mut is_3d = false;
+    let mut domain_min = [0.0; 3];
+    let mut domain_max = [1.0; 3];
+    let mut table = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+      let linenum = i+1;
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue
+      }
+      let parts: Vec<&str> = line.split_whitespace().collect();
+      match parts[0] {
+        "TITLE" => {
+          title = line["TITLE".len()..].trim().trim_matches('"').to_string();
+        },
+        "LUT_1D_SIZE" | "LUT_3D_SIZE" => {
+          if parts.len() != 2 {
+            return Err(format!("imagepipe: invalid size in line {}", linenum))
+          }
+          size = parts[1].parse::<usize>().map_err(|_| {
+            format!("imagepipe: invalid size in line {}", linenum)
+          })?;
+          is_3d = parts[0] == "LUT_3D_SIZE";
+        },
+        "DOMAIN_MIN" | "DOMAIN_MAX" => {
+          if parts.len() != 4 {
+            return Err(format!("imagepipe: invalid domain in line {}", linenum))
+          }
+          let vals = parse_floats(&parts[1..], linenum)?;
+          let domain = if parts[0] == "DOMAIN_MIN" {&mut domain_min} else {&mut domain_max};
+          domain.copy_from_slice(&vals);
+        },
+        "LUT_1D_INPUT_RANGE" | "LUT_3D_INPUT_RANGE" => {
+          if parts.len() != 3 {
+            return Err(format!("imagepipe: invalid input range in line {}", linenum))
+          }
+          let vals = parse_floats(&parts[1..], linenum)?;
+          domain_min = [vals[0]; 3];
+          domain_max = [vals[1]; 3];
+        },
+        keyword if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+          // Some tools add their own keywords, those are safe to skip
+          debug!("Skipping unknown .cube keyword {}", keyword);
+        },
+        _ => {
+          if parts.len() != 3 {
+            return Err(format!("imagepipe: expected 3 values in line {}", linenum))
+          }
+          table.extend(parse_floats(&parts, linenum)?);
+        },
+      }
+    }
+
+    if size < 2 {
+      return Err("imagepipe: LUT has no valid size".to_string())
+    }
+    let entries = if is_3d {size*size*size} else {size};
+    if table.len() != entries*3 {
+      return Err(format!("imagepipe: LUT should have {} entries but has {}", entries, table.len()/3))
+    }
+    for c in 0..3 {
+      if domain_max[c] <= domain_min[c] {
+        return Err("imagepipe: LUT has an empty domain".to_string())
+      }
+    }
+
+    Ok(Lut {
+      title,
+      size,
+      is_3d,
+      domain_min,
+      domain_max,
+      table,
+    })
+  }
+
+  pub fn from_cube_file<P: AsRef<Path>>(path: P) -> Result<Lut, String> {
+    let contents = fs::read_to_string(&path).map_err(|e| {
+      format!("imagepipe: couldn't read LUT file: {}", e)
+    })?;
+    Self::from_cube_str(&contents)
+  }
+
+  /// Read a Hald CLUT image
+  ///
+  /// A level L Hald image is L^3 pixels square and holds a 3D table of size L^2
+  /// with the pixels in the same order as a .cube file.
+  pub fn from_hald_image(img: &DynamicImage) -> Result<Lut, String> {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let level = (1..=16).find(|l| l*l*l == width);
+    let level = match level {
+      Some(level) if level >= 2 && width == height => level,
+      _ => return Err(format!("imagepipe: {}x{} is not a valid Hald CLUT size", width, height)),
+    };
+
+    let table = img.to_rgb16().into_raw().into_iter().map(input16bit).collect();
+    Ok(Lut {
+      title: String::new(),
+      size: level*level,
+      is_3d: true,
+      domain_min: [0.0; 3],
+      domain_max: [1.0; 3],
+      table,
+    })
+  }
+
+  pub fn from_hald_file<P: AsRef<Path>>(path: P) -> Result<Lut, String> {
+    let img = image::open(&path).map_err(|e| {
+      format!("imagepipe: couldn't read Hald CLUT file: {}", e)
+    })?;
+    Self::from_hald_image(&img)
+  }
+
+  /// Write the table as a 16 bit Hald CLUT image
+  ///
+  /// Only 3D tables with a 0.0-1.0 domain and a size that's a square work
+  pub fn to_hald_image(&self) -> Result<DynamicImage, String> {
+    let level = (2..=16).find(|l| l*l == self.size);
+    let level = match level {
+      Some(level) if self.is_3d => level,
+      _ => return Err(format!("imagepipe: a LUT of size {} can't be a Hald CLUT", self.size)),
+    };
+    if self.domain_min != [0.0; 3] || self.domain_max != [1.0; 3] {
+      return Err("imagepipe: Hald CLUTs need a 0.0-1.0 domain".to_string())
+    }
+
+    let side = (level*level*level) as u32;
+    let data = self.table.iter().map(|v| output16bit(*v)).collect();
+    let img = ImageBuffer::from_raw(side, side, data).unwrap();
+    Ok(DynamicImage::ImageRgb16(img))
+  }
+
+  /// An identity 3D table of the given size
+  pub fn identity(size: usize) -> Lut {
+    let mut table = Vec::with_capacity(size*size*size*3);
+    let max = (size-1) as f32;
+    for b in 0..size {
+      for g in 0..size {
+        for r in 0..size {
+          table.push(r as f32 / max);
+          table.push(g as f32 / max);
+          table.push(b as f32 / max);
+        }
+      }
+    }
+
+    Lut {
+      title: String::new(),
+      size,
+      is_3d: true,
+      domain_min: [0.0; 3],
+      domain_max: [1.0; 3],
+      table,
+    }
+  }
+
+  #[inline(always)]
+  fn entry(&self, r: usize, g: usize, b: usize) -> &[f32] {
+    let pos = ((b*self.size + g)*self.size + r)*3;
+    &self.table[pos..pos+3]
+  }
+
+  /// Look up an RGB value in the table
+  pub fn apply(&self, pix: &[f32], interpolation: LutInterpolation) -> [f32;3] {
+    let max = (self.size - 1) as f32;
+    let mut pos = [0.0; 3];
+    for c in 0..3 {
+      let v = (pix[c] - self.domain_min[c]) / (self.domain_max[c] - self.domain_min[c]);
+      pos[c] = if v.is_nan() {0.0} else {v.clamp(0.0, 1.0) * max};
+    }
+
+    if !self.is_3d {
+      let mut out = [0.0; 3];
+      for c in 0..3 {
+        let base = cmp::min(pos[c] as usize, self.size - 2);
+        let frac = pos[c] - base as f32;
+        let v1 = self.table[base*3+c];
+        let v2 = self.table[(base+1)*3+c];
+        out[c] = v1 + frac * (v2 - v1);
+      }
+      return out
+    }
+
+    let r = cmp::min(pos[0] as usize, self.size - 2);
+    let g = cmp::min(pos[1] as usize, self.size - 2);
+    let b = cmp::min(pos[2] as usize, self.size - 2);
+    let (fr, fg, fb) = (pos[0] - r as f32, pos[1] - g as f32, pos[2] - b as f32);
+
+    let c000 = self.entry(r, g, b);
+    let c111 = self.entry(r+1, g+1, b+1);
+    let mut out = [0.0; 3];
+    match interpolation {
+      LutInterpolation::Trilinear => {
+        let c100 = self.entry(r+1, g, b);
+        let c010 = self.entry(r, g+1, b);
+        let c001 = self.entry(r, g, b+1);
+        let c110 = self.entry(r+1, g+1, b);
+        let c101 = self.entry(r+1, g, b+1);
+        let c011 = self.entry(r, g+1, b+1);
+        for c in 0..3 {
+          let c00 = c000[c] + (c100[c] - c000[c]) * fr;
+          let c10 = c010[c] + (c110[c] - c010[c]) * fr;
+          let c01 = c001[c] + (c101[c] - c001[c]) * fr;
+          let c11 = c011[c] + (c111[c] - c011[c]) * fr;
+          let c0 = c00 + (c10 - c00) * fg;
+          let c1 = c01 + (c11 - c01) * fg;
+          out[c] = c0 + (c1 - c0) * fb;
+        }
+      },
+      LutInterpolation::Tetrahedral => {
+        // Split the cube into six tetrahedra along the diagonal from c000 to c111
+        // and interpolate between the four corners of the one we're in
+        let (w0, w1, w2, w3, ca, cb);
+        if fr > fg {
+          if fg > fb {
+            ca = self.entry(r+1, g, b); cb = self.entry(r+1, g+1, b);
+            w0 = 1.0 - fr; w1 = fr - fg; w2 = fg - fb; w3 = fb;
+          } else if fr > fb {
+            ca = self.entry(r+1, g, b); cb = self.entry(r+1, g, b+1);
+            w0 = 1.0 - fr; w1 = fr - fb; w2 = fb - fg; w3 = fg;
+          } else {
+            ca = self.entry(r, g, b+1); cb = self.entry(r+1, g, b+1);
+            w0 = 1.0 - fb; w1 = fb - fr; w2 = fr - fg; w3 = fg;
+          }
+        } else if fb > fg {
+          ca = self.entry(r, g, b+1); cb = self.entry(r, g+1, b+1);
+          w0 = 1.0 - fb; w1 = fb - fg; w2 = fg - fr; w3 = fr;
+        } else if fb > fr {
+          ca = self.entry(r, g+1, b); cb = self.entry(r, g+1, b+1);
+          w0 = 1.0 - fg; w1 = fg - fb; w2 = fb - fr; w3 = fr;
+        } else {
+          ca = self.entry(r, g+1, b); cb = self.entry(r+1, g+1, b);
+          w0 = 1.0 - fg; w1 = fg - fr; w2 = fr - fb; w3 = fb;
+        }
+        for c in 0..3 {
+          out[c] = w0*c000[c] + w1*ca[c] + w2*cb[c] + w3*c111[c];
+        }
+      },
+    }
+    out
+  }
+}
+
+/// Apply a color lookup table to the image at a chosen position
+///
+/// The op is in the pipeline after filmic. For the other positions the pipeline
+/// runs copies of it, made with `at()`, in those places and the op itself does
+/// nothing.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OpLut {
+  /// The whole table is kept in the settings so the op is self contained and
+  /// a different table changes the op hash
+  pub lut: Option<Lut>,
+  pub interpolation: LutInterpolation,
+  pub space: LutSpace,
+  #[serde(default)]
+  pub position: LutPosition,
+  // Where in the pipeline this instance runs
+  #[serde(skip)]
+  slot: LutPosition,
+}
+
+impl Default for OpLut {
+  fn default() -> Self {
+    Self {
+      lut: None,
+      interpolation: LutInterpolation::Tetrahedral,
+      space: LutSpace::Encoded,
+      position: LutPosition::AfterFilmic,
+      slot: LutPosition::AfterFilmic,
+    }
+  }
+}
+
+impl OpLut {
+  pub fn new(_img: &ImageSource) -> OpLut {
+    Self::default()
+  }
+
+  pub fn load_cube<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+    self.lut = Some(Lut::from_cube_file(path)?);
+    Ok(())
+  }
+
+  pub fn load_hald<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+    self.lut = Some(Lut::from_hald_file(path)?);
+    Ok(())
+  }
+
+  /// The op to run at a given position, without a table if it's set to run
+  /// somewhere else
+  pub(crate) fn at(&self, slot: LutPosition) -> OpLut {
+    if slot == self.position {
+      OpLut{slot, ..self.clone()}
+    } else {
+      OpLut{lut: None, slot, ..*self}
+    }
+  }
+
+  // Apply the table to linear or encoded RGB values
+  fn lookup(&self, lut: &Lut, pix: &mut [f32], encoded: bool) {
+    if encoded {
+      let inpix = [
+        apply_srgb_gamma(pix[0].clamp(0.0, 1.0)),
+        apply_srgb_gamma(pix[1].clamp(0.0, 1.0)),
+        apply_srgb_gamma(pix[2].clamp(0.0, 1.0)),
+      ];
+      let out = lut.apply(&inpix, self.interpolation);
+      pix[0] = expand_srgb_gamma(out[0]);
+      pix[1] = expand_srgb_gamma(out[1]);
+      pix[2] = expand_srgb_gamma(out[2]);
+    } else {
+      let out = lut.apply(pix, self.interpolation);
+      pix.copy_from_slice(&out);
+    }
+  }
+}
+
+impl<'a> ImageOp<'a> for OpLut {
+  fn name(&self) -> &str {"lut"}
+  fn run(&self, pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    let lut = match self.lut {
+      Some(ref lut) if self.slot == self.position => lut,
+      _ => return buf,
+    };
+
+    let encoded = self.space == LutSpace::Encoded;
+    match self.slot {
+      LutPosition::AfterToLab => {
+        let rgbmatrix = *XYZ_D65_33;
+        let labmatrix = *SRGB_D65_43;
+        Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], _| {
+          for pix in line.chunks_exact_mut(3) {
+            let (r, g, b) = lab_to_rgb(rgbmatrix, pix);
+            let mut rgb = [r, g, b, 0.0];
+            self.lookup(lut, &mut rgb[0..3], encoded);
+            let (l, a, b) = camera_to_lab_unclipped([1.0, 1.0, 1.0, 0.0], labmatrix, &rgb);
+            pix[0] = l;
+            pix[1] = a;
+            pix[2] = b;
+          }
+        })))
+      },
+      LutPosition::Encoded if !pipeline.settings.linear => {
+        Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], _| {
+          for pix in line.chunks_exact_mut(3) {
+            let out = lut.apply(pix, self.interpolation);
+            pix.copy_from_slice(&out);
+          }
+        })))
+      },
+      _ => {
+        Arc::new(buf.mutate_lines_copying(&(|line: &mut [f32], _| {
+          for pix in line.chunks_exact_mut(3) {
+            self.lookup(lut, pix, encoded);
+          }
+        })))
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  static IDENTITY_CUBE: &str = "
+# Created by hand
+TITLE \"Identity\"
+LUT_3D_SIZE 2
+DOMAIN_MIN 0.0 0.0 0.0
+DOMAIN_MAX 1.0 1.0 1.0
+0.0 0.0 0.0
+1.0 0.0 0.0
+0.0 1.0 0.0
+1.0 1.0 0.0
+0.0 0.0 1.0
+1.0 0.0 1.0
+0.0 1.0 1.0
+1.0 1.0 1.0
+";
+
+  static INVERT_1D_CUBE: &str = "
+LUT_1D_SIZE 3
+1.0 1.0 1.0
+0.5 0.5 0.5
+0.0 0.0 0.0
+";
+
+  #[test]
+  fn parse_cube() {
+    let lut = Lut::from_cube_str(IDENTITY_CUBE).unwrap();
+    assert_eq!(lut.title, "Identity");
+    assert_eq!(lut.size, 2);
+    assert!(lut.is_3d);
+    assert_eq!(lut.table, Lut::identity(2).table);
+  }
+
+  #[test]
+  fn parse_errors() {
+    assert!(Lut::from_cube_str("LUT_3D_SIZE 2\n0.0 0.0 0.0\n").is_err());
+    assert!(Lut::from_cube_str("LUT_1D_SIZE 2\n0.0 0.0\n1.0 1.0 1.0\n").is_err());
+    assert!(Lut::from_cube_str("LUT_1D_SIZE 2\n0.0 0.0 zero\n1.0 1.0 1.0\n").is_err());
+    assert!(Lut::from_cube_str("").is_err());
+  }
+
+  #[test]
+  fn identity_roundtrip() {
+    let lut = Lut::identity(17);
+    for interpolation in [LutInterpolation::Trilinear, LutInterpolation::Tetrahedral].iter() {
+      for r in 0..10 {
+        for g in 0..10 {
+          for b in 0..10 {
+            let pix = [r as f32 / 9.0, g as f32 / 9.0, b as f32 / 9.0];
+            let out = lut.apply(&pix, *interpolation);
+            for c in 0..3 {
+              assert!((out[c] - pix[c]).abs() < 0.0001, "{:?} became {:?}", pix, out);
+            }
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn apply_1d() {
+    let lut = Lut::from_cube_str(INVERT_1D_CUBE).unwrap();
+    let out = lut.apply(&[0.0, 0.25, 1.0], LutInterpolation::Trilinear);
+    assert_eq!(out, [1.0, 0.75, 0.0]);
+  }
+
+  #[test]
+  fn hald_roundtrip() {
+    let lut = Lut::identity(4);
+    let img = lut.to_hald_image().unwrap();
+    assert_eq!((img.width(), img.height()), (8, 8));
+    let back = Lut::from_hald_image(&img).unwrap();
+    assert_eq!(back.size, 4);
+    for (a, b) in back.table.iter().zip(lut.table.iter()) {
+      assert!((a - b).abs() < 0.0001);
+    }
+  }
+
+  #[test]
+  fn hald_errors() {
+    let img = DynamicImage::ImageRgb8(image::RgbImage::new(10, 10));
+    assert!(Lut::from_hald_image(&img).is_err());
+    let img = DynamicImage::ImageRgb8(image::RgbImage::new(27, 8));
+    assert!(Lut::from_hald_image(&img).is_err());
+    assert!(Lut::identity(5).to_hald_image().is_err());
+  }
+
+  #[test]
+  fn runs_only_at_its_position() {
+    let globals = PipelineGlobals::mock(10, 10);
+    let mut op = OpLut{lut: Some(Lut::from_cube_str(INVERT_1D_CUBE).unwrap()), ..OpLut::default()};
+    op.position = LutPosition::AfterToLab;
+    let buf = Arc::new(OpBuffer::new(2, 2, 3, false));
+    assert!(Arc::ptr_eq(&buf, &op.run(&globals, buf.clone())));
+    assert!(op.at(LutPosition::Encoded).lut.is_none());
+
+    // In the Lab stage black becomes white
+    let mut black = OpBuffer::new(2, 2, 3, false);
+    let (l, a, b) = camera_to_lab_unclipped([1.0, 1.0, 1.0, 0.0], *SRGB_D65_43, &[0.0; 4]);
+    for pix in black.data.chunks_exact_mut(3) {
+      pix.copy_from_slice(&[l, a, b]);
+    }
+    let out = op.at(LutPosition::AfterToLab).run(&globals, Arc::new(black));
+    assert!((out.data[0] - 1.0).abs() < 0.001, "got L of {}", out.data[0]);
+  }
+
+  #[test]
+  fn op_changes_hash() {
+    let mut op = OpLut::default();
+    let hash = op.shash();
+    op.lut = Some(Lut::identity(2));
+    assert_ne!(hash, op.shash());
+  }
+}
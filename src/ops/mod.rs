@@ -1,7 +1,14 @@
 pub mod gofloat;
+pub mod hotpixels;
 pub mod demosaic;
+pub mod retouch;
 pub mod colorspaces;
 pub mod curves;
+pub mod localadjust;
+pub mod blackwhite;
 pub mod gamma;
+pub mod filmic;
+pub mod lut;
 pub mod transform;
+pub mod fit;
 pub mod rotatecrop;
@@ -0,0 +1,316 @@
+use crate::opbasics::*;
+
+// Iterations at each resolution and over-relaxation factor used to solve the
+// healing membrane, and the largest side that's solved without a coarser start
+static HEAL_ITERATIONS: usize = 100;
+static HEAL_OMEGA: f32 = 1.9;
+static HEAL_COARSEST: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RetouchMode {
+  /// Copy the source over the destination
+  Clone,
+  /// Copy the texture of the source but match the destination's surroundings
+  Heal,
+}
+
+/// A circular area to be covered with the contents of another one
+///
+/// Positions are in normalized coordinates of the image before rotation and
+/// crop, like masks, and the radius is relative to the largest side of the
+/// image. Use `Pipeline::spot_from_output()` to get one from the output.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RetouchSpot {
+  pub mode: RetouchMode,
+  pub source: (f32, f32),
+  pub destination: (f32, f32),
+  pub radius: f32,
+  /// Fraction of the radius over which the edge blends into the image
+  pub feather: f32,
+}
+
+/// Spot removal by cloning or healing from other parts of the image
+///
+/// Runs on the camera colors right after demosaic so it works on the whole
+/// image whatever the crop, and later spots see the results of earlier ones.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OpRetouch {
+  pub spots: Vec<RetouchSpot>,
+}
+
+impl OpRetouch {
+  pub fn new(_img: &ImageSource) -> OpRetouch {
+    Self::default()
+  }
+}
+
+impl<'a> ImageOp<'a> for OpRetouch {
+  fn name(&self) -> &str {"retouch"}
+  fn run(&self, _pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    if self.spots.is_empty() {
+      return buf
+    }
+
+    let mut out = (*buf).clone();
+    for spot in self.spots.iter() {
+      apply_spot(&mut out, spot);
+    }
+    Arc::new(out)
+  }
+}
+
+// Spot in pixels of a given buffer
+struct PixelSpot {
+  sx: isize,
+  sy: isize,
+  dx: isize,
+  dy: isize,
+  radius: f32,
+  inner: f32,
+}
+
+impl PixelSpot {
+  fn new(spot: &RetouchSpot, width: usize, height: usize) -> PixelSpot {
+    let scale = cmp::max(width, height) as f32;
+    let radius = spot.radius * scale;
+    PixelSpot {
+      sx: (spot.source.0 * width as f32) as isize,
+      sy: (spot.source.1 * height as f32) as isize,
+      dx: (spot.destination.0 * width as f32) as isize,
+      dy: (spot.destination.1 * height as f32) as isize,
+      radius,
+      inner: radius * (1.0 - spot.feather.clamp(0.0, 1.0)),
+    }
+  }
+
+  fn weight(&self, x: isize, y: isize) -> f32 {
+    let dist = ((x*x + y*y) as f32).sqrt();
+    if dist <= self.inner {
+      1.0
+    } else if dist >= self.radius {
+      0.0
+    } else {
+      let v = (self.radius - dist) / (self.radius - self.inner);
+      v * v * (3.0 - 2.0 * v)
+    }
+  }
+}
+
+fn apply_spot(buf: &mut OpBuffer, spot: &RetouchSpot) {
+  let pspot = PixelSpot::new(spot, buf.width, buf.height);
+  if pspot.radius < 0.5 {
+    return
+  }
+  let colors = buf.colors;
+  let (width, height) = (buf.width as isize, buf.height as isize);
+
+  // Work on a box around the destination with a one pixel border outside
+  // the spot that sets the boundary conditions for healing
+  let r = pspot.radius.ceil() as isize + 1;
+  let side = (2*r + 1) as usize;
+  let clamp = |v: isize, max: isize| -> usize { v.clamp(0, max-1) as usize };
+  let get = |buf: &OpBuffer, x: isize, y: isize, c: usize| -> f32 {
+    buf.data[(clamp(y, height)*buf.width + clamp(x, width))*colors + c]
+  };
+
+  let mut result = vec![0.0; side*side*colors];
+  for by in 0..side {
+    for bx in 0..side {
+      let (ox, oy) = (bx as isize - r, by as isize - r);
+      for c in 0..colors {
+        result[(by*side+bx)*colors+c] = get(buf, pspot.sx + ox, pspot.sy + oy, c);
+      }
+    }
+  }
+
+  if spot.mode == RetouchMode::Heal {
+    // Solve Laplace's equation for the difference between destination and
+    // source with the border as the boundary, which adds a smooth correction
+    // to the source so it matches the destination's surroundings
+    let inside = |bx: usize, by: usize| -> bool {
+      let (ox, oy) = (bx as isize - r, by as isize - r);
+      ((ox*ox + oy*oy) as f32).sqrt() <= pspot.radius
+    };
+    let mut fixed = vec![false; side*side];
+    for by in 0..side {
+      for bx in 0..side {
+        fixed[by*side+bx] = !inside(bx, by);
+      }
+    }
+    let mut diff = vec![0.0; side*side];
+    for c in 0..colors {
+      for by in 0..side {
+        for bx in 0..side {
+          if fixed[by*side+bx] {
+            let (ox, oy) = (bx as isize - r, by as isize - r);
+            diff[by*side+bx] = get(buf, pspot.dx + ox, pspot.dy + oy, c) - result[(by*side+bx)*colors+c];
+          }
+        }
+      }
+      solve_membrane(&mut diff, &fixed, side);
+      for (pos, d) in diff.iter().enumerate() {
+        result[pos*colors+c] += d;
+      }
+    }
+  }
+
+  for by in 0..side {
+    for bx in 0..side {
+      let (ox, oy) = (bx as isize - r, by as isize - r);
+      let (x, y) = (pspot.dx + ox, pspot.dy + oy);
+      let weight = pspot.weight(ox, oy);
+      if weight <= 0.0 || x < 0 || y < 0 || x >= width || y >= height {
+        continue
+      }
+      let pos = (y as usize * buf.width + x as usize) * colors;
+      for c in 0..colors {
+        let orig = buf.data[pos+c];
+        buf.data[pos+c] = orig + (result[(by*side+bx)*colors+c] - orig) * weight;
+      }
+    }
+  }
+}
+
+// Solve Laplace's equation for the values that aren't fixed, with the fixed
+// ones as the boundary. Larger grids start from the solution at half the
+// resolution so they converge in as many iterations as small ones, which keeps
+// a spot looking the same in a scaled down preview and the full size image.
+fn solve_membrane(values: &mut [f32], fixed: &[bool], side: usize) {
+  if side > HEAL_COARSEST {
+    let cside = side.div_ceil(2);
+    let mut cvalues = vec![0.0; cside*cside];
+    let mut cfixed = vec![false; cside*cside];
+    let mut counts = vec![0; cside*cside];
+    for y in 0..side {
+      for x in 0..side {
+        if fixed[y*side+x] {
+          let cpos = (y/2)*cside + x/2;
+          cvalues[cpos] += values[y*side+x];
+          cfixed[cpos] = true;
+          counts[cpos] += 1;
+        }
+      }
+    }
+    for (v, count) in cvalues.iter_mut().zip(counts) {
+      if count > 0 {
+        *v /= count as f32;
+      }
+    }
+    solve_membrane(&mut cvalues, &cfixed, cside);
+    for y in 0..side {
+      for x in 0..side {
+        if !fixed[y*side+x] {
+          values[y*side+x] = cvalues[(y/2)*cside + x/2];
+        }
+      }
+    }
+  } else {
+    let (sum, count) = values.iter().zip(fixed).filter(|(_, f)| **f).fold((0.0, 0), |(sum, count), (v, _)| {
+      (sum + v, count + 1)
+    });
+    let mean = if count > 0 {sum / count as f32} else {0.0};
+    for (v, f) in values.iter_mut().zip(fixed) {
+      if !f {
+        *v = mean;
+      }
+    }
+  }
+
+  for _ in 0..HEAL_ITERATIONS {
+    for y in 1..side-1 {
+      for x in 1..side-1 {
+        let pos = y*side+x;
+        if !fixed[pos] {
+          let avg = (values[pos-1] + values[pos+1] + values[pos-side] + values[pos+side]) / 4.0;
+          values[pos] += HEAL_OMEGA * (avg - values[pos]);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Horizontal gradient with a dark spot at 70x50
+  fn dusty() -> Arc<OpBuffer> {
+    let mut buf = OpBuffer::new(100, 100, 4, false);
+    buf.mutate_lines(&(|line: &mut [f32], row| {
+      for (col, pix) in line.chunks_exact_mut(4).enumerate() {
+        let mut v = col as f32 / 100.0;
+        let (dx, dy) = (col as f32 - 70.0, row as f32 - 50.0);
+        if (dx*dx + dy*dy).sqrt() < 4.0 {
+          v *= 0.5;
+        }
+        for c in pix.iter_mut() {
+          *c = v;
+        }
+      }
+    }));
+    Arc::new(buf)
+  }
+
+  fn spot(mode: RetouchMode) -> OpRetouch {
+    OpRetouch {
+      spots: vec![RetouchSpot {
+        mode,
+        source: (0.3, 0.5),
+        destination: (0.7, 0.5),
+        radius: 0.08,
+        feather: 0.0,
+      }],
+    }
+  }
+
+  #[test]
+  fn clone_copies_source() {
+    let out = spot(RetouchMode::Clone).run(&PipelineGlobals::mock(100, 100), dusty());
+    let pos = (50*100 + 70)*4;
+    assert!((out.data[pos] - 0.30).abs() < 0.001);
+  }
+
+  #[test]
+  fn heal_matches_surroundings() {
+    let out = spot(RetouchMode::Heal).run(&PipelineGlobals::mock(100, 100), dusty());
+    for col in 62..79 {
+      let pos = (50*100 + col)*4;
+      let expected = col as f32 / 100.0;
+      assert!((out.data[pos] - expected).abs() < 0.01, "{} is {} instead of {}", col, out.data[pos], expected);
+    }
+  }
+
+  #[test]
+  fn heal_same_at_any_scale() {
+    // A curved gradient needs the solve to converge as the correction isn't flat
+    let heal = |size: usize| {
+      let mut buf = OpBuffer::new(size, size, 1, false);
+      buf.mutate_lines(&(|line: &mut [f32], row| {
+        for (col, pix) in line.iter_mut().enumerate() {
+          let (x, y) = (col as f32 / size as f32, row as f32 / size as f32);
+          let (dx, dy) = (x - 0.7, y - 0.5);
+          *pix = x * x * if (dx*dx + dy*dy).sqrt() < 0.04 {0.5} else {1.0};
+        }
+      }));
+      let mut op = spot(RetouchMode::Heal);
+      op.spots[0].radius = 0.15;
+      let out = op.run(&PipelineGlobals::mock(size as u32, size as u32), Arc::new(buf));
+      (0..=20).map(|i| {
+        let x = 0.6 + i as f32 * 0.01;
+        let col = (x * size as f32) as usize;
+        let expected = (col as f32 / size as f32).powi(2);
+        out.data[size/2*size + col] - expected
+      }).collect::<Vec<f32>>()
+    };
+    for (small, large) in heal(100).iter().zip(heal(800).iter()) {
+      assert!(small.abs() < 0.002 && large.abs() < 0.002, "off by {} at 100 and {} at 800", small, large);
+    }
+  }
+
+  #[test]
+  fn no_spots_is_noop() {
+    let op = OpRetouch::default();
+    let buf = dusty();
+    assert!(Arc::ptr_eq(&buf, &op.run(&PipelineGlobals::mock(100, 100), buf.clone())));
+  }
+}
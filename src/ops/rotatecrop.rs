@@ -12,7 +12,12 @@ pub struct OpRotateCrop {
   pub crop_right: f32,
   pub crop_bottom: f32,
   pub crop_left: f32,
+  /// Rotation from -1.0 to 1.0 for -90 to 90 degrees clockwise
   pub rotation: f32,
+  /// Keep the output at this width/height ratio by cropping further from the
+  /// sides or the top and bottom around the center of the crop
+  #[serde(default)]
+  pub aspect_ratio: Option<f32>,
   input_ratio: f32,
   output_size: Option<(usize, usize)>,
 }
@@ -28,6 +33,7 @@ impl OpRotateCrop {
       crop_bottom: 0.0,
       crop_left: 0.0,
       rotation: 0.0,
+      aspect_ratio: None,
       input_ratio: 1.0,
       output_size: None,
     }
@@ -36,33 +42,39 @@ impl OpRotateCrop {
 
 impl<'a> ImageOp<'a> for OpRotateCrop {
   fn name(&self) -> &str {"rotatecrop"}
-  fn run(&self, _pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
+  fn run(&self, pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
     if self.noop() { return buf; }
 
     // Calculate source and destination sizes
     let (swidth, sheight) = (buf.width as f32, buf.height as f32);
     let (nwidth, nheight) = self.calc_size(buf.width, buf.height, false);
     let (fnwidth, fnheight) = (nwidth as f32, nheight as f32);
+    let (bwidth, bheight) = self.rotated_size(swidth, sheight);
+    let (crop_top, _, _, crop_left) = self.crops(bwidth / bheight);
 
     // Figure out x and y
-    let x = (swidth * self.crop_left).floor();
-    if x < 0.0 || x > swidth {
+    let x = (bwidth * crop_left).floor();
+    if x < 0.0 || x > bwidth {
       log::error!("Trying to crop left outside image");
       return buf;
     }
-    let y = (sheight * self.crop_top).floor();
-    if y < 0.0 || y > sheight {
+    let y = (bheight * crop_top).floor();
+    if y < 0.0 || y > bheight {
       log::error!("Trying to crop top outside image");
       return buf;
     }
 
-    let topleft = self.rotate_point_reverse(x, y, fnwidth, fnheight, swidth, sheight);
-    let topright = self.rotate_point_reverse(x + fnwidth - 1.0, y, fnwidth, fnheight, swidth, sheight);
-    let bottomleft = self.rotate_point_reverse(x, y + fnheight - 1.0, fnwidth, fnheight, swidth, sheight);
-    let newbuffer = buf.transform(topleft, topright, bottomleft, nwidth, nheight);
+    let topleft = self.rotate_point_reverse(x, y, bwidth, bheight, swidth, sheight);
+    let topright = self.rotate_point_reverse(x + fnwidth - 1.0, y, bwidth, bheight, swidth, sheight);
+    let bottomleft = self.rotate_point_reverse(x, y + fnheight - 1.0, bwidth, bheight, swidth, sheight);
+    let newbuffer = buf.transform(topleft, topright, bottomleft, nwidth, nheight, pipeline.settings.resampling);
     Arc::new(newbuffer)
   }
 
+  fn run_alpha(&self, pipeline: &PipelineGlobals, alpha: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    self.run(pipeline, alpha)
+  }
+
   fn transform_forward(&mut self, width: usize, height: usize) -> (usize, usize) {
     if let Some(size) = self.output_size {
       // We're going forward after going reverse so we're commited to an output size
@@ -91,38 +103,190 @@ impl OpRotateCrop {
     self.crop_top.abs() < EPSILON &&
     self.crop_right.abs() < EPSILON &&
     self.crop_bottom.abs() < EPSILON &&
-    self.crop_left.abs() < EPSILON
+    self.crop_left.abs() < EPSILON &&
+    self.aspect_ratio.is_none()
   }
 
-  fn rotate_point_reverse(&self, x: f32, y: f32, width: f32, height: f32, swidth: f32, sheight: f32) -> (isize, isize) {
+  // Rotation in radians, clockwise for positive values
+  fn angle(&self) -> f32 {
+    FRAC_PI_2 * self.rotation.clamp(-1.0, 1.0)
+  }
+
+  // Size of the whole input after rotation, before any crops
+  fn rotated_size(&self, width: f32, height: f32) -> (f32, f32) {
+    if self.rotation.abs() < EPSILON {
+      (width, height)
+    } else {
+      let (sin, cos) = self.angle().sin_cos();
+      let sin = sin.abs();
+      (width*cos + height*sin, width*sin + height*cos)
+    }
+  }
+
+  // Crops as (top, right, bottom, left) after applying the aspect ratio, for a
+  // rotated image with a given width/height ratio
+  fn crops(&self, ratio: f32) -> (f32, f32, f32, f32) {
+    let (mut top, mut right, mut bottom, mut left) =
+      (self.crop_top, self.crop_right, self.crop_bottom, self.crop_left);
+    if let Some(aspect) = self.aspect_ratio {
+      let width = 1.0 - left - right;
+      let height = 1.0 - top - bottom;
+      if aspect > EPSILON && width > EPSILON && height > EPSILON {
+        let current = ratio * width / height;
+        if current > aspect {
+          let extra = (width - width * aspect / current) / 2.0;
+          left += extra;
+          right += extra;
+        } else {
+          let extra = (height - height * current / aspect) / 2.0;
+          top += extra;
+          bottom += extra;
+        }
+      }
+    }
+    (top, right, bottom, left)
+  }
+
+  /// Set the crops to the largest rectangle that fits inside the rotated image
+  ///
+  /// The rectangle has the aspect ratio if there's one and otherwise it's the
+  /// one with the largest area. The width and height are the size of the image
+  /// before rotation.
+  pub fn set_max_crop(&mut self, width: usize, height: usize) {
+    let (width, height) = (width as f32, height as f32);
+    let (sin, cos) = self.angle().abs().sin_cos();
+    let (mut cwidth, mut cheight) = match self.aspect_ratio {
+      Some(aspect) if aspect > EPSILON => {
+        // The corners of the crop need to be inside the rotated image
+        let cheight = (width / (aspect * cos + sin)).min(height / (aspect * sin + cos));
+        (cheight * aspect, cheight)
+      },
+      _ => {
+        let (long, short) = if width >= height {(width, height)} else {(height, width)};
+        if short <= 2.0 * sin * cos * long || (sin - cos).abs() < EPSILON {
+          // Half constrained, two corners touch the longer side
+          let x = 0.5 * short;
+          if width >= height {(x / sin, x / cos)} else {(x / cos, x / sin)}
+        } else {
+          // Fully constrained, the crop touches all four sides
+          let cos2 = cos*cos - sin*sin;
+          ((width*cos - height*sin) / cos2, (height*cos - width*sin) / cos2)
+        }
+      },
+    };
+    // Stay away from the edges so rounding doesn't let any border in
+    if self.rotation.abs() >= EPSILON {
+      cwidth -= 2.0;
+      cheight -= 2.0;
+    }
+    let (bwidth, bheight) = self.rotated_size(width, height);
+    let horizontal = ((bwidth - cwidth) / (2.0 * bwidth)).clamp(0.0, 0.5);
+    let vertical = ((bheight - cheight) / (2.0 * bheight)).clamp(0.0, 0.5);
+    self.crop_left = horizontal;
+    self.crop_right = horizontal;
+    self.crop_top = vertical;
+    self.crop_bottom = vertical;
+  }
+
+  /// Convert crops from settings before version 1 so they keep the same region
+  ///
+  /// The offsets used to be fractions of the image before rotation, which was
+  /// around the center of the crop instead of the image, and negative rotations
+  /// were ignored. The width and height are the size of the image before rotation.
+  pub(crate) fn convert_v0_crops(&mut self, width: usize, height: usize) {
     if self.rotation < EPSILON {
-      (x as isize, y as isize)
+      self.rotation = 0.0;
+      return
+    }
+    let (width, height) = (width as f32, height as f32);
+    let (bwidth, bheight) = self.rotated_size(width, height);
+    let horizontal = self.crop_left + self.crop_right;
+    let vertical = self.crop_top + self.crop_bottom;
+    // Keep the size of the crop and shift it so the same point of the image
+    // ends up at its center
+    self.crop_left = self.crop_left * width / bwidth + horizontal / 2.0;
+    self.crop_right = horizontal - self.crop_left;
+    self.crop_top = self.crop_top * height / bheight + vertical / 2.0;
+    self.crop_bottom = vertical - self.crop_top;
+  }
+
+  fn rotate_point_reverse(&self, x: f32, y: f32, width: f32, height: f32, swidth: f32, sheight: f32) -> (isize, isize) {
+    let (nx, ny) = self.rotate_reverse(x, y, width, height, swidth, sheight);
+    (nx as isize, ny as isize)
+  }
+
+  fn rotate_reverse(&self, x: f32, y: f32, width: f32, height: f32, swidth: f32, sheight: f32) -> (f32, f32) {
+    if self.rotation.abs() < EPSILON {
+      (x, y)
     } else {
-      let angle = FRAC_PI_2 * if self.rotation > 1.0 {1.0} else {self.rotation};
-      let (sin, cos) = angle.sin_cos();
+      let (sin, cos) = self.angle().sin_cos();
       // Translate the coordinates to the center
       let (tx, ty) = (x - (width / 2.0), y - (height / 2.0));
       let nx = tx*cos + ty*sin + (swidth / 2.0);
       let ny = - tx*sin + ty*cos + (sheight / 2.0);
-      (nx as isize, ny as isize)
+      (nx, ny)
+    }
+  }
+
+  fn rotate_forward(&self, x: f32, y: f32, width: f32, height: f32, swidth: f32, sheight: f32) -> (f32, f32) {
+    if self.rotation.abs() < EPSILON {
+      (x, y)
+    } else {
+      let (sin, cos) = self.angle().sin_cos();
+      let (tx, ty) = (x - (swidth / 2.0), y - (sheight / 2.0));
+      let nx = tx*cos - ty*sin + (width / 2.0);
+      let ny = tx*sin + ty*cos + (height / 2.0);
+      (nx, ny)
     }
   }
 
+  /// Map a point in pixels of the output of the op to the input of a given size
+  pub fn point_reverse(&self, x: f32, y: f32, swidth: usize, sheight: usize) -> (f32, f32) {
+    if self.noop() { return (x, y); }
+    let (swidth, sheight) = (swidth as f32, sheight as f32);
+    let (bwidth, bheight) = self.rotated_size(swidth, sheight);
+    let (crop_top, _, _, crop_left) = self.crops(bwidth / bheight);
+    let x = x + (bwidth * crop_left).floor();
+    let y = y + (bheight * crop_top).floor();
+    self.rotate_reverse(x, y, bwidth, bheight, swidth, sheight)
+  }
+
+  /// Map a point in pixels of an input of a given size to the output of the op
+  pub fn point_forward(&self, x: f32, y: f32, swidth: usize, sheight: usize) -> (f32, f32) {
+    if self.noop() { return (x, y); }
+    let (swidth, sheight) = (swidth as f32, sheight as f32);
+    let (bwidth, bheight) = self.rotated_size(swidth, sheight);
+    let (crop_top, _, _, crop_left) = self.crops(bwidth / bheight);
+    let (x, y) = self.rotate_forward(x, y, bwidth, bheight, swidth, sheight);
+    (x - (bwidth * crop_left).floor(), y - (bheight * crop_top).floor())
+  }
+
+  /// Size of the input needed to get an output of a given size
+  pub(crate) fn input_size(&self, width: usize, height: usize) -> (usize, usize) {
+    self.calc_size(width, height, true)
+  }
+
   fn calc_size(&self, owidth: usize, oheight: usize, reverse: bool) -> (usize, usize){
     if self.noop() { return (owidth, oheight); }
 
     let (width, height) = (owidth as f32, oheight as f32);
 
-    let (width, height) = if reverse || self.rotation < EPSILON {
+    let (width, height) = if reverse {
       (width, height)
     } else {
-      let angle = FRAC_PI_2 * if self.rotation > 1.0 {1.0} else {self.rotation};
-      let (sin, cos) = angle.sin_cos();
-      (width*cos + height*sin, width*sin + height*cos)
+      self.rotated_size(width, height)
     };
+    // The crops depend on the shape of the rotated image, which going in reverse
+    // we only know from the ratio of the last input
+    let (bwidth, bheight) = if reverse {
+      self.rotated_size(self.input_ratio, 1.0)
+    } else {
+      (width, height)
+    };
+    let (crop_top, crop_right, crop_bottom, crop_left) = self.crops(bwidth / bheight);
 
     let nwidth = {
-      let ratio = 1.0 - self.crop_left - self.crop_right;
+      let ratio = 1.0 - crop_left - crop_right;
       let nwidth = if reverse {
         (width / ratio).round()
       } else {
@@ -136,7 +300,7 @@ impl OpRotateCrop {
     };
 
     let nheight = {
-      let ratio = 1.0 - self.crop_top - self.crop_bottom;
+      let ratio = 1.0 - crop_top - crop_bottom;
       let nheight = if reverse {
         (height / ratio).round()
       } else {
@@ -149,11 +313,11 @@ impl OpRotateCrop {
       nheight
     };
 
-    let (nwidth, nheight) = if !reverse || self.rotation < EPSILON {
+    let (nwidth, nheight) = if !reverse || self.rotation.abs() < EPSILON {
       (nwidth, nheight)
     } else {
-      let angle = FRAC_PI_2 * if self.rotation > 1.0 {1.0} else {self.rotation};
-      let (sin, cos) = angle.sin_cos();
+      let (sin, cos) = self.angle().sin_cos();
+      let sin = sin.abs();
       let width = (nheight / (sin + (cos/self.input_ratio))).round();
       let height = (width / self.input_ratio).round();
       (width, height)
@@ -261,6 +425,21 @@ mod tests {
     assert_eq!(newbuf.width, 141);
   }
 
+  #[test]
+  fn rotate_negative() {
+    let (buffer, mut op, globals) = setup();
+    op.rotation = -0.5;
+    let newbuf = op.run(&globals, buffer.clone());
+    assert_eq!(newbuf.height, 141);
+    assert_eq!(newbuf.width, 141);
+    op.rotation = -0.3;
+    for (x, y) in [(0.0, 0.0), (50.0, 10.0), (77.0, 33.0)].iter() {
+      let (ix, iy) = op.point_reverse(*x, *y, 100, 100);
+      let (ox, oy) = op.point_forward(ix, iy, 100, 100);
+      assert!((ox - x).abs() < 0.001 && (oy - y).abs() < 0.001);
+    }
+  }
+
   #[test]
   fn rotate_90() {
     let (buffer, mut op, globals) = setup();
@@ -270,6 +449,115 @@ mod tests {
     assert_eq!(newbuf.width, 100);
   }
 
+  #[test]
+  fn map_points() {
+    let (_, mut op, _) = setup();
+    op.crop_left = 0.1;
+    op.crop_top = 0.2;
+    assert_eq!(op.point_reverse(0.0, 0.0, 100, 100), (10.0, 20.0));
+    assert_eq!(op.point_forward(10.0, 20.0, 100, 100), (0.0, 0.0));
+    op.rotation = 0.3;
+    for (x, y) in [(0.0, 0.0), (50.0, 10.0), (77.0, 33.0)].iter() {
+      let (ix, iy) = op.point_reverse(*x, *y, 100, 100);
+      let (ox, oy) = op.point_forward(ix, iy, 100, 100);
+      assert!((ox - x).abs() < 0.001 && (oy - y).abs() < 0.001);
+    }
+  }
+
+  #[test]
+  fn aspect_ratio() {
+    let (buffer, mut op, globals) = setup();
+    op.aspect_ratio = Some(2.0);
+    let newbuf = op.run(&globals, buffer.clone());
+    assert_eq!(newbuf.width, 100);
+    assert_eq!(newbuf.height, 50);
+    assert_eq!(&newbuf.data[0], &buffer.data[100*25*3]);
+
+    op.crop_left = 0.5;
+    let newbuf = op.run(&globals, buffer.clone());
+    assert_eq!(newbuf.width, 50);
+    assert_eq!(newbuf.height, 25);
+  }
+
+  #[test]
+  fn centered_crop_with_rotation() {
+    let (_, mut op, _) = setup();
+    op.rotation = 0.2;
+    op.crop_top = 0.1;
+    op.crop_right = 0.1;
+    op.crop_bottom = 0.1;
+    op.crop_left = 0.1;
+    let (width, height) = op.calc_size(100, 100, false);
+    let (x, y) = op.point_reverse(width as f32 / 2.0, height as f32 / 2.0, 100, 100);
+    assert!((x - 50.0).abs() < 1.0 && (y - 50.0).abs() < 1.0, "center went to {}x{}", x, y);
+  }
+
+  #[test]
+  fn crop_offsets_with_rotation() {
+    // Crops are fractions of the rotated image and it rotates around its center,
+    // so settings saved with rotation and crops depend on this staying the same
+    let (buffer, mut op, globals) = setup();
+    op.rotation = 0.2;
+    op.crop_top = 0.2;
+    op.crop_left = 0.1;
+    let newbuf = op.run(&globals, buffer.clone());
+    assert_eq!((newbuf.width, newbuf.height), (113, 101));
+    let (x, y) = op.point_reverse(0.0, 0.0, 100, 100);
+    assert!((x + 10.25).abs() < 0.01 && (y - 29.62).abs() < 0.01, "top left went to {}x{}", x, y);
+    let (x, y) = op.point_reverse(112.0, 100.0, 100, 100);
+    assert!((x - 127.17).abs() < 0.01 && (y - 90.11).abs() < 0.01, "bottom right went to {}x{}", x, y);
+  }
+
+  #[test]
+  fn convert_v0_crops() {
+    // The same crop as above used to start at 10x20 of the source, rotated
+    // around the center of the 113x101 output
+    let (buffer, mut op, globals) = setup();
+    op.rotation = 0.2;
+    op.crop_top = 0.2;
+    op.crop_left = 0.1;
+    op.convert_v0_crops(100, 100);
+    let newbuf = op.run(&globals, buffer.clone());
+    assert_eq!((newbuf.width, newbuf.height), (113, 101));
+    let (x, y) = op.point_reverse(0.0, 0.0, 100, 100);
+    assert!((x + 3.65).abs() < 1.0 && (y - 35.36).abs() < 1.0, "top left went to {}x{}", x, y);
+    let (x, y) = op.point_reverse(112.0, 100.0, 100, 100);
+    assert!((x - 133.77).abs() < 1.0 && (y - 95.86).abs() < 1.0, "bottom right went to {}x{}", x, y);
+
+    // Negative rotations didn't rotate at all
+    let mut op = OpRotateCrop::empty();
+    op.rotation = -0.2;
+    op.crop_left = 0.1;
+    op.convert_v0_crops(100, 100);
+    assert_eq!((op.rotation, op.crop_left, op.crop_right), (0.0, 0.1, 0.0));
+  }
+
+  #[test]
+  fn max_crop() {
+    let (_, mut op, _) = setup();
+    for aspect in [None, Some(1.5), Some(0.5)] {
+      for rotation in [0.0, 0.05, -0.05, 0.3, 0.5, -0.9] {
+        op.rotation = rotation;
+        op.aspect_ratio = aspect;
+        op.set_max_crop(300, 200);
+        let (width, height) = op.calc_size(300, 200, false);
+        if let Some(aspect) = aspect {
+          assert!((width as f32 / height as f32 - aspect).abs() < 0.02);
+        }
+        for (x, y) in [(0, 0), (width-1, 0), (0, height-1), (width-1, height-1)] {
+          let (ix, iy) = op.point_reverse(x as f32, y as f32, 300, 200);
+          assert!(ix > -0.5 && iy > -0.5 && ix < 299.5 && iy < 199.5,
+            "{:?} at {} has corner {}x{} outside", aspect, rotation, ix, iy);
+        }
+      }
+    }
+    // Without rotation the whole image fits
+    op.rotation = 0.0;
+    op.aspect_ratio = None;
+    op.set_max_crop(300, 200);
+    assert_eq!(op.calc_size(300, 200, false), (300, 200));
+  }
+
   #[test]
   fn roundtrip_transform() {
     let mut op = OpRotateCrop::empty();
@@ -19,41 +19,30 @@ pub struct OpTransform {
 }
 
 impl OpTransform {
-  pub fn new(img: &ImageSource) -> OpTransform {
-    match img {
-      ImageSource::Raw(img) => {
-        let (rotation, fliph, flipv) = match img.orientation {
-          Orientation::Normal
-          | Orientation::Unknown      => (Rotation::Normal, false, false),
-          Orientation::VerticalFlip   => (Rotation::Normal, false, true),
-          Orientation::HorizontalFlip => (Rotation::Normal, true, false),
-          Orientation::Rotate180      => (Rotation::Rotate180, false, false),
-          Orientation::Transpose      => (Rotation::Rotate90, false, true),
-          Orientation::Rotate90       => (Rotation::Rotate90, false, false),
-          Orientation::Rotate270      => (Rotation::Rotate270, false, false),
-          Orientation::Transverse     => (Rotation::Rotate270, true, false),
-        };
-
-        OpTransform{
-          rotation,
-          fliph,
-          flipv,
-        }
-      },
-      ImageSource::Other(_) => {
-        OpTransform{
-          rotation: Rotation::Normal,
-          fliph: false,
-          flipv: false,
-        }
-      }
+  /// Transform that displays an image stored with the given orientation
+  pub fn from_orientation(orientation: Orientation) -> OpTransform {
+    let (rotation, fliph, flipv) = match orientation {
+      Orientation::Normal
+      | Orientation::Unknown      => (Rotation::Normal, false, false),
+      Orientation::VerticalFlip   => (Rotation::Normal, false, true),
+      Orientation::HorizontalFlip => (Rotation::Normal, true, false),
+      Orientation::Rotate180      => (Rotation::Rotate180, false, false),
+      Orientation::Transpose      => (Rotation::Rotate90, false, true),
+      Orientation::Rotate90       => (Rotation::Rotate90, false, false),
+      Orientation::Rotate270      => (Rotation::Rotate270, false, false),
+      Orientation::Transverse     => (Rotation::Rotate270, false, true),
+    };
+
+    OpTransform{
+      rotation,
+      fliph,
+      flipv,
     }
   }
 }
 
-impl<'a> ImageOp<'a> for OpTransform {
-  fn name(&self) -> &str {"transform"}
-  fn run(&self, _pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
+impl OpTransform {
+  fn orientation(&self) -> Orientation {
     // Grab back a base orientation
     let (f1, f2, f3) = match self.rotation {
       Rotation::Normal    => Orientation::Normal,
@@ -63,7 +52,39 @@ impl<'a> ImageOp<'a> for OpTransform {
     }.to_flips();
 
     // Adjust it with the vertical and horizontal flips if that applies
-    let orientation = Orientation::from_flips((f1, f2 ^ self.fliph, f3 ^ self.flipv));
+    Orientation::from_flips((f1, f2 ^ self.fliph, f3 ^ self.flipv))
+  }
+
+  /// Apply the transform directly to a raster image, for when it doesn't go
+  /// through the pipeline. Returns None if there's nothing to do.
+  pub(crate) fn orient_image(&self, img: &OtherImage) -> Option<OtherImage> {
+    match self.orientation() {
+      Orientation::Normal
+      | Orientation::Unknown      => None,
+      Orientation::HorizontalFlip => Some(img.fliph()),
+      Orientation::VerticalFlip   => Some(img.flipv()),
+      Orientation::Rotate180      => Some(img.rotate180()),
+      Orientation::Rotate90       => Some(img.rotate90()),
+      Orientation::Rotate270      => Some(img.rotate270()),
+      Orientation::Transpose      => Some(img.rotate90().fliph()),
+      Orientation::Transverse     => Some(img.rotate270().fliph()),
+    }
+  }
+
+  /// Map a point in normalized coordinates of the output back to the input
+  pub fn map_reverse(&self, x: f32, y: f32) -> (f32, f32) {
+    let (transpose, flip_x, flip_y) = self.orientation().to_flips();
+    let (x, y) = if transpose {(y, x)} else {(x, y)};
+    let x = if flip_x {1.0 - x} else {x};
+    let y = if flip_y {1.0 - y} else {y};
+    (x, y)
+  }
+}
+
+impl<'a> ImageOp<'a> for OpTransform {
+  fn name(&self) -> &str {"transform"}
+  fn run(&self, _pipeline: &PipelineGlobals, buf: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    let orientation = self.orientation();
 
     if orientation == Orientation::Normal || orientation == Orientation::Unknown {
       buf
@@ -72,6 +93,10 @@ impl<'a> ImageOp<'a> for OpTransform {
     }
   }
 
+  fn run_alpha(&self, pipeline: &PipelineGlobals, alpha: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    self.run(pipeline, alpha)
+  }
+
   fn transform_forward(&mut self, width: usize, height: usize) -> (usize, usize) {
     match self.rotation {
       Rotation::Rotate90 | Rotation::Rotate270 => (height, width),
@@ -85,7 +110,8 @@ impl<'a> ImageOp<'a> for OpTransform {
 }
 
 fn rotate_buffer(buf: &OpBuffer, orientation: &Orientation) -> OpBuffer {
-  assert_eq!(buf.colors, 3); // When we're rotating we're always at 3 cpp
+  // Images are at 3 cpp when we're rotating but alpha planes are 1 cpp
+  let colors = buf.colors as isize;
 
   // Don't rotate things we don't know how to rotate or don't need to
   if *orientation == Orientation::Normal || *orientation == Orientation::Unknown {
@@ -106,25 +132,25 @@ fn rotate_buffer(buf: &OpBuffer, orientation: &Orientation) -> OpBuffer {
   let (transpose, flip_x, flip_y) = orientation.to_flips();
 
   let mut base_offset: isize = 0;
-  let mut x_step: isize = 3;
-  let mut y_step: isize = width * 3;
+  let mut x_step: isize = colors;
+  let mut y_step: isize = width * colors;
 
   if flip_x {
     x_step = -x_step;
-    base_offset += (width - 1) * 3;
+    base_offset += (width - 1) * colors;
   }
 
   if flip_y {
     y_step = -y_step;
-    base_offset += width * (height - 1) * 3;
+    base_offset += width * (height - 1) * colors;
   }
 
   let mut out = if transpose {
     mem::swap(&mut width, &mut height);
     mem::swap(&mut x_step, &mut y_step);
-    OpBuffer::new(buf.height, buf.width, 3 as usize, buf.monochrome)
+    OpBuffer::new(buf.height, buf.width, buf.colors, buf.monochrome)
   } else {
-    OpBuffer::new(buf.width, buf.height, 3 as usize, buf.monochrome)
+    OpBuffer::new(buf.width, buf.height, buf.colors, buf.monochrome)
   };
 
   out.mutate_lines(&(|line: &mut [f32], row| {
@@ -134,8 +160,8 @@ fn rotate_buffer(buf: &OpBuffer, orientation: &Orientation) -> OpBuffer {
     for col in 0..width {
       // The current pixel's offset in original buffer
       let offset = line_offset + x_step * col;
-      for c in 0..3 {
-        line[(col * 3 + c) as usize] = buf.data[(offset + c) as usize];
+      for c in 0..colors {
+        line[(col * colors + c) as usize] = buf.data[(offset + c) as usize];
       }
     }
   }));
@@ -164,6 +190,65 @@ mod tests {
       };
   }
 
+  #[test]
+  fn map_reverse_matches_rotation() {
+    use crate::ops::transform::*;
+    let globals = PipelineGlobals::mock(8, 7);
+    let buf = std::sync::Arc::new(F.clone());
+    for rotation in [Rotation::Normal, Rotation::Rotate90, Rotation::Rotate180, Rotation::Rotate270].iter() {
+      for (fliph, flipv) in [(false, false), (true, false), (false, true)].iter() {
+        let op = OpTransform { rotation: *rotation, fliph: *fliph, flipv: *flipv };
+        let out = op.run(&globals, buf.clone());
+        for row in 0..out.height {
+          for col in 0..out.width {
+            let x = (col as f32 + 0.5) / out.width as f32;
+            let y = (row as f32 + 0.5) / out.height as f32;
+            let (ix, iy) = op.map_reverse(x, y);
+            let (ix, iy) = ((ix * buf.width as f32) as usize, (iy * buf.height as f32) as usize);
+            let outpos = (row * out.width + col) * 3;
+            let inpos = (iy * buf.width + ix) * 3;
+            assert_eq!(out.data[outpos..outpos+3], buf.data[inpos..inpos+3]);
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn all_orientations() {
+    use crate::ops::transform::*;
+    let mut img = image::RgbImage::new(F.width as u32, F.height as u32);
+    for (o, i) in img.chunks_exact_mut(3).zip(F.data.chunks_exact(3)) {
+      for c in 0..3 {
+        o[c] = (i[c] * 255.0) as u8;
+      }
+    }
+    let img = OtherImage::ImageRgb8(img);
+    for value in 1..=8 {
+      let orientation = Orientation::from_u16(value);
+      let op = OpTransform::from_orientation(orientation);
+      assert_eq!(op.orientation(), orientation);
+      // The fast path on raster images gives the same result as the pipeline
+      let expected = rotate_buffer(&F.clone(), &orientation);
+      let oriented = op.orient_image(&img).unwrap_or_else(|| img.clone()).to_rgb8();
+      assert_eq!((oriented.width() as usize, oriented.height() as usize), (expected.width, expected.height));
+      for (o, e) in oriented.as_raw().iter().zip(expected.data.iter()) {
+        assert_eq!(*o, (e * 255.0) as u8, "wrong pixels for {:?}", orientation);
+      }
+    }
+  }
+
+  #[test]
+  fn raw_orientations() {
+    use crate::ops::transform::*;
+    // Raws are displayed by rotating the buffer with the orientation they're
+    // stored in, so the transform needs to give it back exactly
+    for value in 1..=8 {
+      let orientation = Orientation::from_u16(value);
+      assert_eq!(OpTransform::from_orientation(orientation).orientation(), orientation);
+    }
+  }
+
   #[test]
   fn rotate_unknown() {
     assert_eq!(rotate_buffer(&F.clone(), &Orientation::Unknown), F.clone());
@@ -1,5 +1,12 @@
 use crate::ops::*;
 use crate::opbasics::*;
+use crate::scopes::*;
+use crate::clipping::ClippingMask;
+use crate::masks::{GeometricMask, ParametricMask};
+use crate::calibration::Calibration;
+use crate::horizon;
+use crate::metadata::Metadata;
+use crate::scaling::{FitMode, Resampling, Upscaling};
 
 extern crate rawloader;
 extern crate multicache;
@@ -13,34 +20,79 @@ use image::{RgbImage, DynamicImage};
 
 use std::fmt::Debug;
 use std::sync::Arc;
-use std::io::Write;
+use std::io::{Cursor, Write};
 use std::path::Path;
 use std::hash::{Hash, Hasher};
 use std::time::Instant;
+use std::cmp;
+use std::collections::BTreeMap;
 
 /// A RawImage processed into a full 8bit sRGB image with levels and gamma
 ///
 /// The data is a Vec<u8> width width*height*3 elements, where each element is a value
-/// between 0 and 255 with the intensity of the color channel with gamma applied
+/// between 0 and 255 with the intensity of the color channel. If linear is false
+/// sRGB gamma has been applied to the values, which is the default for 8 bit.
 #[derive(Debug, Clone, PartialEq)]
 pub struct SRGBImage {
   pub width: usize,
   pub height: usize,
   pub data: Vec<u8>,
+  pub linear: bool,
 }
 
-/// A RawImage processed into a full 16bit sRGB image with levels and gamma
+/// A RawImage processed into a full 16bit sRGB image with levels
 ///
 /// The data is a Vec<u16> width width*height*3 elements, where each element is a value
-/// between 0 and 65535 with the intensity of the color channel with gamma applied
+/// between 0 and 65535 with the intensity of the color channel. If linear is false
+/// sRGB gamma has been applied to the values. By default 16 bit output is linear.
 #[derive(Debug, Clone, PartialEq)]
 pub struct SRGBImage16 {
   pub width: usize,
   pub height: usize,
   pub data: Vec<u16>,
+  pub linear: bool,
+}
+
+/// An 8 bit sRGB image with an alpha channel
+///
+/// Same as SRGBImage but with width*height*4 elements in RGBA order. Alpha is
+/// always linear and the colors aren't premultiplied by it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SRGBAImage {
+  pub width: usize,
+  pub height: usize,
+  pub data: Vec<u8>,
+  pub linear: bool,
+}
+
+/// A 16 bit sRGB image with an alpha channel
+///
+/// Same as SRGBImage16 but with width*height*4 elements in RGBA order. Alpha is
+/// always linear and the colors aren't premultiplied by it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SRGBAImage16 {
+  pub width: usize,
+  pub height: usize,
+  pub data: Vec<u16>,
+  pub linear: bool,
+}
+
+/// A RawImage processed into a full floating point sRGB image with levels
+///
+/// The data is a Vec<f32> width width*height*3 elements, where each element is
+/// the intensity of the color channel with 0.0 as black and 1.0 as white. If linear
+/// is false sRGB gamma has been applied to the values. By default float output is
+/// linear.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SRGBImageFloat {
+  pub width: usize,
+  pub height: usize,
+  pub data: Vec<f32>,
+  pub linear: bool,
 }
 
 pub type PipelineCache = MultiCache<BufHash, OpBuffer>;
+pub type ScopesCache = MultiCache<BufHash, Scopes>;
 pub type OtherImage = DynamicImage;
 
 #[derive(Debug, Clone)]
@@ -63,6 +115,23 @@ impl ImageSource {
       Self::Other(img) => img.height() as usize,
     }
   }
+
+  /// Whether the image has floating point values, which are linear and can go
+  /// above 1.0 like in HDR and EXR files
+  pub fn is_float(&self) -> bool {
+    match self {
+      Self::Raw(_) => false,
+      Self::Other(img) => matches!(img, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)),
+    }
+  }
+
+  /// Whether the image has an alpha channel, which raws never do
+  pub fn has_alpha(&self) -> bool {
+    match self {
+      Self::Raw(_) => false,
+      Self::Other(img) => img.color().has_alpha(),
+    }
+  }
 }
 
 macro_rules! do_timing {
@@ -105,6 +174,22 @@ pub trait ImageOp<'a>: Debug+Serialize+Deserialize<'a> {
   }
   // Reset any saved data so the pipeline runs again, for most ops this is noop
   fn reset(&mut self) {}
+  // Apply the op to the single channel alpha plane of the image after run() has
+  // set it up. Only ops that change the geometry of the image need to do this.
+  fn run_alpha(&self, _pipeline: &PipelineGlobals, alpha: Arc<OpBuffer>) -> Arc<OpBuffer> {
+    alpha
+  }
+}
+
+/// Encoding of the values returned by the output functions
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum OutputEncoding {
+  /// Gamma encoded for 8 bit outputs and linear for 16 bit and float outputs
+  Default,
+  /// Always linear
+  Linear,
+  /// Always sRGB gamma encoded
+  Gamma,
 }
 
 #[derive(Debug, Copy, Clone, Serialize)]
@@ -115,6 +200,15 @@ pub struct PipelineSettings {
   pub demosaic_height: usize,
   pub linear: bool,
   pub use_fastpath: bool,
+  /// Filter used when scaling and rotating
+  pub resampling: Resampling,
+  /// Allow the output to be larger than the image when maxwidth/maxheight are
+  pub upscaling: Upscaling,
+  /// How to fit the image when both maxwidth and maxheight are set
+  pub fit: FitMode,
+  // Only used by the output functions to set linear, which is what ops look at
+  #[serde(skip)]
+  pub output_encoding: OutputEncoding,
 }
 
 impl PipelineSettings {
@@ -126,6 +220,10 @@ impl PipelineSettings {
       demosaic_height: 0,
       linear: false,
       use_fastpath: true,
+      resampling: Resampling::default(),
+      upscaling: Upscaling::default(),
+      fit: FitMode::default(),
+      output_encoding: OutputEncoding::Default,
     }
   }
 }
@@ -140,6 +238,9 @@ impl PipelineSettings{
 pub struct PipelineGlobals {
   pub image: ImageSource,
   pub settings: PipelineSettings,
+  pub calibration: Calibration,
+  /// Metadata of the source, including the orientation the image is stored in
+  pub metadata: Metadata,
 }
 
 impl PipelineGlobals {
@@ -147,6 +248,8 @@ impl PipelineGlobals {
     Self {
       image: ImageSource::Other(DynamicImage::ImageRgb8(RgbImage::new(width, height))),
       settings: PipelineSettings::default(),
+      calibration: Calibration::default(),
+      metadata: Metadata::default(),
     }
   }
 }
@@ -154,27 +257,101 @@ impl PipelineGlobals {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PipelineOps {
   pub gofloat: gofloat::OpGoFloat,
+  #[serde(default)]
+  pub hotpixels: hotpixels::OpHotPixels,
   pub demosaic: demosaic::OpDemosaic,
+  #[serde(default)]
+  pub retouch: retouch::OpRetouch,
   pub rotatecrop: rotatecrop::OpRotateCrop,
   pub tolab: colorspaces::OpToLab,
+  // Copies of the lut op for the positions other than after filmic, set up from
+  // it on every run so they're not saved
+  #[serde(skip)]
+  lut_tolab: lut::OpLut,
   pub basecurve: curves::OpBaseCurve,
+  #[serde(default)]
+  pub localadjust: localadjust::OpLocalAdjust,
+  #[serde(default)]
+  pub blackwhite: blackwhite::OpBlackWhite,
   pub fromlab: colorspaces::OpFromLab,
+  #[serde(skip)]
+  lut_fromlab: lut::OpLut,
+  #[serde(default)]
+  pub filmic: filmic::OpFilmic,
+  #[serde(default)]
+  pub lut: lut::OpLut,
   pub gamma: gamma::OpGamma,
+  #[serde(skip)]
+  lut_encoded: lut::OpLut,
   pub transform: transform::OpTransform,
+  // Set from the pipeline settings on every run so it's not saved
+  #[serde(skip)]
+  pub fit: fit::OpFit,
+  // Parametric masks by the name of the op they restrict, only changed through
+  // set_mask() so they're always for one of MASKABLE_OPS
+  #[serde(default, deserialize_with = "deserialize_masks")]
+  masks: BTreeMap<String, ParametricMask>,
+}
+
+/// Ops that can be restricted by a parametric mask
+///
+/// These are the ops after tolab that keep the color space and the geometry of
+/// the buffer, so the output can be blended with the input. The ones before
+/// tolab run before there's a Lab buffer to build the mask from, fromlab and
+/// gamma change the encoding of the values, and transform and fit change the
+/// size of the image. Masks for any other op are rejected.
+pub static MASKABLE_OPS: [&str; 5] = ["basecurve", "localadjust", "blackwhite", "filmic", "lut"];
+
+fn deserialize_masks<'de, D>(deserializer: D) -> Result<BTreeMap<String, ParametricMask>, D::Error>
+  where D: serde::Deserializer<'de> {
+  let masks = BTreeMap::<String, ParametricMask>::deserialize(deserializer)?;
+  if let Some(opname) = masks.keys().find(|name| !MASKABLE_OPS.contains(&name.as_str())) {
+    return Err(serde::de::Error::custom(format!("imagepipe: op {} can't be masked", opname)))
+  }
+  Ok(masks)
 }
 
 impl PipelineOps {
-  fn new(img: &ImageSource) -> Self {
+  fn new(img: &ImageSource, orientation: Orientation) -> Self {
     Self {
       gofloat: gofloat::OpGoFloat::new(&img),
+      hotpixels: hotpixels::OpHotPixels::new(&img),
       demosaic: demosaic::OpDemosaic::new(&img),
+      retouch: retouch::OpRetouch::new(&img),
       rotatecrop: rotatecrop::OpRotateCrop::new(&img),
       tolab: colorspaces::OpToLab::new(&img),
+      lut_tolab: lut::OpLut::default(),
       basecurve: curves::OpBaseCurve::new(&img),
+      localadjust: localadjust::OpLocalAdjust::new(&img),
+      blackwhite: blackwhite::OpBlackWhite::new(&img),
       fromlab: colorspaces::OpFromLab::new(&img),
+      lut_fromlab: lut::OpLut::default(),
+      filmic: filmic::OpFilmic::new(&img),
+      lut: lut::OpLut::new(&img),
       gamma: gamma::OpGamma::new(&img),
-      transform: transform::OpTransform::new(&img),
+      lut_encoded: lut::OpLut::default(),
+      transform: transform::OpTransform::from_orientation(orientation),
+      fit: fit::OpFit::new(&img),
+      masks: BTreeMap::new(),
+    }
+  }
+
+  /// Restrict the op with the given name to a parametric mask, or remove the
+  /// mask with None
+  pub fn set_mask(&mut self, opname: &str, mask: Option<ParametricMask>) -> Result<(), String> {
+    if !MASKABLE_OPS.contains(&opname) {
+      return Err(format!("imagepipe: op {} can't be masked", opname))
     }
+    match mask {
+      Some(mask) => self.masks.insert(opname.to_string(), mask),
+      None => self.masks.remove(opname),
+    };
+    Ok(())
+  }
+
+  /// The parametric mask restricting the op with the given name, if any
+  pub fn mask(&self, opname: &str) -> Option<&ParametricMask> {
+    self.masks.get(opname)
   }
 }
 
@@ -212,13 +389,23 @@ macro_rules! all_ops {
   ($ops:expr, |$x:pat, $i:ident| $body:expr) => {
     for_vals!([
       $ops.gofloat,
+      $ops.hotpixels,
       $ops.demosaic,
+      $ops.retouch,
       $ops.rotatecrop,
       $ops.tolab,
+      $ops.lut_tolab,
       $ops.basecurve,
+      $ops.localadjust,
+      $ops.blackwhite,
       $ops.fromlab,
+      $ops.lut_fromlab,
+      $ops.filmic,
+      $ops.lut,
       $ops.gamma,
-      $ops.transform
+      $ops.lut_encoded,
+      $ops.transform,
+      $ops.fit
     ] |$x, $i| {
       $body
     });
@@ -228,13 +415,23 @@ macro_rules! all_ops {
 macro_rules! all_ops_reverse {
   ($ops:expr, |$x:pat, $i:ident| $body:expr) => {
     for_vals!([
+      $ops.fit,
       $ops.transform,
+      $ops.lut_encoded,
       $ops.gamma,
+      $ops.lut,
+      $ops.filmic,
+      $ops.lut_fromlab,
       $ops.fromlab,
+      $ops.blackwhite,
+      $ops.localadjust,
       $ops.basecurve,
+      $ops.lut_tolab,
       $ops.tolab,
       $ops.rotatecrop,
+      $ops.retouch,
       $ops.demosaic,
+      $ops.hotpixels,
       $ops.gofloat
     ] |$x, $i| {
       $body
@@ -242,12 +439,38 @@ macro_rules! all_ops_reverse {
   }
 }
 
+// Decode a non raw image, using the extension for the formats that can't be
+// told apart by their contents like image::open() does
+fn decode_other<P: AsRef<Path>>(path: P, data: &[u8]) -> image::ImageResult<DynamicImage> {
+  let mut reader = image::io::Reader::new(Cursor::new(data)).with_guessed_format()?;
+  if reader.format().is_none() {
+    if let Ok(format) = image::ImageFormat::from_path(path) {
+      reader.set_format(format);
+    }
+  }
+  reader.decode()
+}
+
+fn load_calibration<P: AsRef<Path>>(image: &ImageSource, path: P) -> Result<(&RawImage, RawImage), String> {
+  let img = match image {
+    ImageSource::Raw(img) => img,
+    ImageSource::Other(_) => return Err("imagepipe: calibration frames only apply to raw images".to_string()),
+  };
+  let frame = rawloader::decode_file(&path).map_err(|e| {
+    format!("imagepipe: couldn't decode calibration frame: {}", e)
+  })?;
+  Ok((img, frame))
+}
+
 #[derive(Debug)]
 pub struct Pipeline {
   pub globals: PipelineGlobals,
   pub ops: PipelineOps,
 }
 
+// Version 1 changed the rotatecrop offsets to fractions of the rotated image
+static SERIAL_VERSION: u32 = 1;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PipelineSerialization {
   pub version: u32,
@@ -259,37 +482,74 @@ impl Pipeline {
     MultiCache::new(size)
   }
 
+  pub fn new_scopes_cache(size: usize) -> ScopesCache {
+    MultiCache::new(size)
+  }
+
   pub fn new_from_file<P: AsRef<Path>>(path: P) -> Result<Pipeline, String> {
     do_timing!("total new_from_file()", {
-    if let Ok(img) = do_timing!("  rawloader", rawloader::decode_file(&path)) {
-      Self::new_from_source(ImageSource::Raw(img))
-    } else if let Ok(img) = do_timing!("  image::open", image::open(&path)) {
-      Self::new_from_source(ImageSource::Other(img))
+    // Read the file once for both the image and the metadata
+    let data = std::fs::read(&path).map_err(|e| format!("imagepipe: couldn't read file: {}", e))?;
+    let img = if let Ok(img) = do_timing!("  rawloader", rawloader::decode(&mut Cursor::new(&data))) {
+      ImageSource::Raw(img)
+    } else if let Ok(img) = do_timing!("  image::load", decode_other(&path, &data)) {
+      ImageSource::Other(img)
     } else {
-      Err("imagepipe: Don't know how to decode image".to_string())
-    }
+      return Err("imagepipe: Don't know how to decode image".to_string())
+    };
+    let metadata = do_timing!("  metadata", Metadata::from_data(&data, &img));
+    Self::new_from_source_with_metadata(img, metadata)
     })
   }
 
   pub fn new_from_source(img: ImageSource) -> Result<Pipeline, String> {
-    let ops = PipelineOps::new(&img);
+    let metadata = Metadata::from_source(&img);
+    Self::new_from_source_with_metadata(img, metadata)
+  }
+
+  /// Create a pipeline for an image with metadata from elsewhere, for sources
+  /// that don't carry it themselves like decoded JPEGs
+  ///
+  /// The orientation of the metadata sets up the transform op.
+  pub fn new_from_source_with_metadata(img: ImageSource, metadata: Metadata) -> Result<Pipeline, String> {
+    let ops = PipelineOps::new(&img, metadata.orientation);
 
     Ok(Pipeline {
       globals: PipelineGlobals {
         image: img,
         settings: PipelineSettings::default(),
+        calibration: Calibration::default(),
+        metadata,
       },
       ops,
     })
   }
 
+  /// Subtract a dark frame from the raw data before it's normalized
+  ///
+  /// The dark frame needs to be a raw file from the same camera with the same
+  /// dimensions and CFA, ideally taken with the same exposure and temperature.
+  pub fn set_dark_frame<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+    let (img, frame) = load_calibration(&self.globals.image, path)?;
+    self.globals.calibration.set_dark_frame(img, &frame)
+  }
+
+  /// Divide the raw data by a flat field to fix vignetting and sensor dust
+  ///
+  /// The flat field needs to be a raw file from the same camera with the same
+  /// dimensions and CFA, taken of an evenly lit surface.
+  pub fn set_flat_field<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+    let (img, frame) = load_calibration(&self.globals.image, path)?;
+    self.globals.calibration.set_flat_field(img, &frame)
+  }
+
   pub fn default_ops(&self) -> bool {
-    self.ops == PipelineOps::new(&self.globals.image)
+    self.ops == PipelineOps::new(&self.globals.image, self.globals.metadata.orientation)
   }
 
   pub fn to_serial(&self) -> String {
     let serial = (PipelineSerialization {
-      version: 0,
+      version: SERIAL_VERSION,
       filehash: "0".to_string(),
     }, &self.ops);
 
@@ -297,23 +557,236 @@ impl Pipeline {
   }
 
   pub fn new_from_serial(img: ImageSource, serial: String) -> Pipeline {
-    let serial: (PipelineSerialization, PipelineOps) = serde_yaml::from_str(&serial).unwrap();
+    let (serial, ops): (PipelineSerialization, PipelineOps) = serde_yaml::from_str(&serial).unwrap();
+    let metadata = Metadata::from_source(&img);
 
-    Pipeline {
+    let mut pipeline = Pipeline {
       globals: PipelineGlobals {
         image: img,
         settings: PipelineSettings::default(),
+        calibration: Calibration::default(),
+        metadata,
       },
-      ops: serial.1,
+      ops,
+    };
+    if serial.version < 1 {
+      let ((width, height), _) = pipeline.geometry_sizes();
+      pipeline.ops.rotatecrop.convert_v0_crops(width, height);
     }
+    pipeline
   }
 
   pub fn run(&mut self, cache: Option<&PipelineCache>) -> Arc<OpBuffer> {
+    self.run_ops(cache, None).0
+  }
+
+  /// Run the pipeline stopping after the op with the given name
+  ///
+  /// Useful to analyze the image at an intermediate stage. The buffers are cached
+  /// the same way as for a full run so a later run can pick up from here.
+  /// Fails if there's no op with that name.
+  pub fn run_until(&mut self, cache: Option<&PipelineCache>, opname: &str) -> Result<Arc<OpBuffer>, String> {
+    let mut found = false;
+    all_ops!(self.ops, |ref op, _i| {
+      found |= op.name() == opname;
+    });
+    if !found {
+      return Err(format!("imagepipe: no op named {}", opname))
+    }
+    Ok(self.run_ops(cache, Some(opname)).0)
+  }
+
+  /// Suggest exposure and black/white points from the luminance of the image
+  pub fn suggest_levels(&mut self, cache: Option<&PipelineCache>) -> curves::AutoLevels {
+    let buf = self.run_ops(cache, Some("to_lab")).0;
+    self.ops.basecurve.auto_levels(&buf)
+  }
+
+  /// Suggest a rotation that levels the horizon or makes verticals upright
+  ///
+  /// Looks for lines within `max_angle` degrees of horizontal or vertical in the
+  /// image without the current rotation. Returns the value to use as rotation in
+  /// the rotatecrop op, or None if there's no clear line in the image.
+  pub fn suggest_rotation(&mut self, cache: Option<&PipelineCache>, max_angle: f32) -> Option<f32> {
+    let rotation = self.ops.rotatecrop.rotation;
+    self.ops.rotatecrop.rotation = 0.0;
+    let buf = self.run_ops(cache, Some("to_lab")).0;
+    self.ops.rotatecrop.rotation = rotation;
+    horizon::straighten_angle(&buf, max_angle).map(|angle| angle / 90.0)
+  }
+
+  /// Apply the suggested rotation, returning false if none was found
+  ///
+  /// The rotation leaves empty corners, use `set_max_crop()` to crop them away.
+  pub fn straighten(&mut self, cache: Option<&PipelineCache>, max_angle: f32) -> bool {
+    match self.suggest_rotation(cache, max_angle) {
+      Some(rotation) => {
+        self.ops.rotatecrop.rotation = rotation;
+        true
+      },
+      None => false,
+    }
+  }
+
+  /// Calculate histograms, waveforms and the vectorscope for the image
+  ///
+  /// The scopes are cached by the hash of the buffer they were taken from, so
+  /// asking again after changing settings past the chosen stage is cheap.
+  pub fn scopes(&mut self, cache: Option<&PipelineCache>, scopes_cache: Option<&ScopesCache>,
+                settings: &ScopeSettings) -> Arc<Scopes> {
+    do_timing!("total scopes()", {
+    if settings.stage == ScopeStage::Output {
+      self.globals.settings.linear = false;
+    }
+    let (buffer, bufhash) = self.run_ops(cache, settings.stage.opname());
+
+    let mut hasher = BufHasher::new();
+    hasher.from_serialize(&bufhash);
+    hasher.from_serialize(settings);
+    let hash = hasher.result();
+    if let Some(scopes_cache) = scopes_cache {
+      if let Some(scopes) = scopes_cache.get(&hash) {
+        return scopes
+      }
+    }
+
+    let scopes = Arc::new(do_timing!("  scopes calculation", Scopes::calculate(&buffer, settings)));
+    if let Some(scopes_cache) = scopes_cache {
+      scopes_cache.put_arc(hash, scopes.clone(), scopes.size());
+    }
+    scopes
+    })
+  }
+
+  /// Render the color transform of the pipeline into a Hald CLUT image
+  ///
+  /// The input of the table is sRGB so the look can be applied to other images
+  /// in other tools. Only the color ops from tolab to gamma are included, with
+  /// auto levels fixed to the values for the current image.
+  pub fn hald_clut(&mut self, cache: Option<&PipelineCache>, level: usize) -> Result<DynamicImage, String> {
+    do_timing!("total hald_clut()", {
+    if !(2..=16).contains(&level) {
+      return Err(format!("imagepipe: Hald CLUT level {} is not between 2 and 16", level))
+    }
+    let identity = lut::Lut::identity(level*level);
+    let side = level*level*level;
+    let mut buf = OpBuffer::new(side, side, 4, false);
+    for (pixout, pixin) in buf.data.chunks_exact_mut(4).zip(identity.table.chunks_exact(3)) {
+      pixout[0] = expand_srgb_gamma(pixin[0]);
+      pixout[1] = expand_srgb_gamma(pixin[1]);
+      pixout[2] = expand_srgb_gamma(pixin[2]);
+    }
+
+    let basecurve = if self.ops.basecurve.auto {
+      let levels = self.suggest_levels(cache);
+      self.ops.basecurve.leveled(&levels)
+    } else {
+      self.ops.basecurve.clone()
+    };
+    let mut globals = PipelineGlobals::mock(1, 1);
+    globals.settings.linear = false;
+    let tolab = colorspaces::OpToLab::new(&globals.image);
+    let mut bufin = Arc::new(buf);
+    for_vals!([
+      &tolab,
+      &self.ops.lut.at(lut::LutPosition::AfterToLab),
+      &basecurve,
+      &self.ops.blackwhite,
+      &self.ops.fromlab,
+      &self.ops.lut.at(lut::LutPosition::AfterFromLab),
+      &self.ops.filmic,
+      &self.ops.lut,
+      &self.ops.gamma,
+      &self.ops.lut.at(lut::LutPosition::Encoded)
+    ] |op, _i| {
+      bufin = op.run(&globals, bufin.clone());
+    });
+
+    lut::Lut {
+      title: String::new(),
+      size: level*level,
+      is_3d: true,
+      domain_min: [0.0; 3],
+      domain_max: [1.0; 3],
+      table: bufin.data.clone(),
+    }.to_hald_image()
+    })
+  }
+
+  /// Map a point in normalized coordinates of the output to the image before
+  /// rotation and crop, where masks are defined
+  pub fn output_to_image(&mut self, x: f32, y: f32) -> (f32, f32) {
+    let ((width, height), (owidth, oheight)) = self.geometry_sizes();
+    let ((fwidth, fheight), _) = self.fit_sizes(owidth, oheight);
+    let (x, y) = self.ops.fit.map_reverse(x, y, fwidth, fheight);
+    let (x, y) = self.ops.transform.map_reverse(x, y);
+    let (x, y) = self.ops.rotatecrop.point_reverse(x * owidth as f32, y * oheight as f32, width, height);
+    (x / width as f32, y / height as f32)
+  }
+
+  // Full size of the image before and after rotation and crop
+  fn geometry_sizes(&mut self) -> ((usize, usize), (usize, usize)) {
+    let width = self.globals.image.width();
+    let height = self.globals.image.height();
+    let (width, height) = self.ops.gofloat.transform_forward(width, height);
+    let (width, height) = self.ops.demosaic.transform_forward(width, height);
+    self.ops.rotatecrop.reset();
+    let (owidth, oheight) = self.ops.rotatecrop.transform_forward(width, height);
+    self.ops.rotatecrop.reset();
+    ((width, height), (owidth, oheight))
+  }
+
+  // Full size of the image before and after the fit to the output size
+  fn fit_sizes(&mut self, owidth: usize, oheight: usize) -> ((usize, usize), (usize, usize)) {
+    self.ops.fit.set_target(&self.globals.settings);
+    let (width, height) = self.ops.transform.transform_forward(owidth, oheight);
+    self.ops.fit.reset();
+    let size = self.ops.fit.transform_forward(width, height);
+    self.ops.fit.reset();
+    ((width, height), size)
+  }
+
+  /// Metadata to write with the outputs, with the orientation already applied
+  pub fn output_metadata(&self) -> Metadata {
+    self.globals.metadata.with_normal_orientation()
+  }
+
+  /// Crop to the largest rectangle inside the image at the current rotation
+  ///
+  /// Keeps the aspect ratio of the rotatecrop op if it has one.
+  pub fn set_max_crop(&mut self) {
+    let ((width, height), _) = self.geometry_sizes();
+    self.ops.rotatecrop.set_max_crop(width, height);
+  }
+
+  /// Get a mask drawn on the output of the pipeline into image coordinates, so
+  /// it stays on the same part of the image when the rotation or crop change
+  pub fn mask_from_output(&mut self, mask: &GeometricMask) -> GeometricMask {
+    mask.map(|x, y| self.output_to_image(x, y))
+  }
+
+  /// Get a retouch spot placed on the output of the pipeline into image
+  /// coordinates, with the radius relative to the largest side of the output
+  pub fn spot_from_output(&mut self, spot: &retouch::RetouchSpot) -> retouch::RetouchSpot {
+    let ((width, height), (owidth, oheight)) = self.geometry_sizes();
+    let ((fwidth, fheight), (fowidth, foheight)) = self.fit_sizes(owidth, oheight);
+    let pixel = self.ops.fit.pixel_reverse(fwidth, fheight);
+    let radius = spot.radius * cmp::max(fowidth, foheight) as f32 * pixel / cmp::max(width, height) as f32;
+    retouch::RetouchSpot {
+      source: self.output_to_image(spot.source.0, spot.source.1),
+      destination: self.output_to_image(spot.destination.0, spot.destination.1),
+      radius,
+      ..*spot
+    }
+  }
+
+  fn run_ops(&mut self, cache: Option<&PipelineCache>, until: Option<&str>) -> (Arc<OpBuffer>, BufHash) {
     do_timing!("  total pipeline", {
     // Reset all ops to make sure we're starting clean
     all_ops!(self.ops, |ref mut op, _i| {
       op.reset();
     });
+    self.ops.fit.set_target(&self.globals.settings);
     // Calculate what size of image we should scale down to at the demosaic stage
     let mut width = self.globals.image.width();
     let mut height = self.globals.image.height();
@@ -326,7 +799,7 @@ impl Pipeline {
     let maxwidth = self.globals.settings.maxwidth;
     let maxheight = self.globals.settings.maxheight;
     let (mut width, mut height) =
-      crate::scaling::scaling_size(width, height, maxwidth, maxheight);
+      crate::scaling::scaling_size(width, height, maxwidth, maxheight, self.globals.settings.upscaling.enabled());
     log::debug!("Final image size is {}x{}", width, height);
     all_ops_reverse!(self.ops, |ref mut op, _i| {
       let (w, h) = op.transform_reverse(width, height);
@@ -336,73 +809,185 @@ impl Pipeline {
     log::debug!("Needed image size at demosaic {}x{}", width, height);
     self.globals.settings.demosaic_width = width;
     self.globals.settings.demosaic_height = height;
+    // Masks are defined on the image before rotation and crop so the ops that
+    // use them need to know what those are
+    self.ops.localadjust.set_geometry(&self.ops.rotatecrop);
+    self.ops.lut_tolab = self.ops.lut.at(lut::LutPosition::AfterToLab);
+    self.ops.lut_fromlab = self.ops.lut.at(lut::LutPosition::AfterFromLab);
+    self.ops.lut_encoded = self.ops.lut.at(lut::LutPosition::Encoded);
+
+    // Find the last op we need to run
+    let mut lastpos = usize::MAX;
+    if let Some(until) = until {
+      all_ops!(self.ops, |ref op, i| {
+        if op.name() == until {
+          lastpos = i;
+        }
+      });
+    }
 
     // Generate all the hashes for the operations
     let mut hasher = BufHasher::new();
     let mut ophashes = Vec::new();
     let mut startpos = 0;
+    // Masked ops need the Lab buffer so we can't skip past it without having it
+    let masks = &self.ops.masks;
+    let mut labbuf: Option<Arc<OpBuffer>> = None;
+    let mut labpos = 0;
+    all_ops!(self.ops, |ref op, i| {
+      if op.name() == "to_lab" {
+        labpos = i;
+      }
+    });
     // Hash the base settings that are potentially used by all operations
     self.globals.settings.hash(&mut hasher);
+    self.globals.calibration.hash(&mut hasher);
     // Start with a dummy buffer as gofloat doesn't use it
     let mut bufin = Arc::new(OpBuffer::default());
     // Find the hashes of all ops
     all_ops!(self.ops, |ref op, i| {
       op.hash(&mut hasher);
+      if let Some(mask) = masks.get(op.name()) {
+        hasher.from_serialize(mask);
+      }
       let result = hasher.result();
       ophashes.push(result);
 
       // Set the latest op for which we already have the calculated buffer
       if let Some(cache) = cache {
-        if let Some(buffer) = cache.get(&result) {
-          bufin = buffer;
-          startpos = i+1;
+        if i <= lastpos && (masks.is_empty() || labbuf.is_some() || i <= labpos) {
+          if let Some(buffer) = cache.get(&result) {
+            if op.name() == "to_lab" {
+              labbuf = Some(buffer.clone());
+            }
+            bufin = buffer;
+            startpos = i+1;
+          }
         }
       }
     });
 
     // Do the operations, starting for the last we have a cached buffer for
     all_ops!(self.ops, |ref op, i| {
-      if i >= startpos {
+      if i >= startpos && i <= lastpos {
         let opstr = "    ".to_string() + op.name();
-        bufin = do_timing!(&opstr, op.run(&self.globals, bufin.clone()));
+        let input = bufin.clone();
+        bufin = do_timing!(&opstr, op.run(&self.globals, input.clone()));
+        if op.name() == "to_lab" {
+          labbuf = Some(bufin.clone());
+        }
+        if let (Some(mask), Some(lab)) = (masks.get(op.name()), &labbuf) {
+          // Ops that did nothing, like the lut copies for other positions, have
+          // nothing to blend
+          if !Arc::ptr_eq(&input, &bufin) {
+            bufin = Arc::new(do_timing!("    parametric mask", mask.blend(lab, &input, &bufin)));
+          }
+        }
         if let Some(cache) = cache {
           cache.put_arc(ophashes[i], bufin.clone(), bufin.width*bufin.height*bufin.colors*4);
         }
       }
     });
-    bufin
+    let lasthash = ophashes[cmp::min(lastpos, ophashes.len()-1)];
+    (bufin, lasthash)
+    })
+  }
+
+  // Run the alpha plane through the ops, which needs a run() with the same
+  // settings first so the ops know the sizes to output
+  fn run_alpha(&self) -> Arc<OpBuffer> {
+    let mut alpha = Arc::new(OpBuffer::default());
+    all_ops!(self.ops, |ref op, _i| {
+      alpha = op.run_alpha(&self.globals, alpha);
+    });
+    alpha
+  }
+
+  /// Calculate which pixels of the output clip in shadows, highlights or gamut
+  ///
+  /// The mask has the same size as the images from the output functions with the
+  /// same settings. It's calculated from the linear output before any clamping so
+  /// it needs a pipeline run separate from the gamma encoded outputs.
+  pub fn output_clipping_mask(&mut self, cache: Option<&PipelineCache>) -> Result<ClippingMask, String> {
+    do_timing!("total output_clipping_mask()", {
+    let linear = self.globals.settings.linear;
+    self.globals.settings.linear = true;
+    let buffer = self.run(cache);
+    self.globals.settings.linear = linear;
+    // HDR encodings only clip above 1.0
+    let white = self.ops.gamma.white();
+    Ok(do_timing!("  clipping mask", ClippingMask::from_linear_white(&buffer, white)))
     })
   }
 
+  /// Set the linear setting from the requested output encoding
+  fn set_output_linear(&mut self, default: bool) {
+    self.globals.settings.linear = match self.globals.settings.output_encoding {
+      OutputEncoding::Default => default,
+      OutputEncoding::Linear => true,
+      OutputEncoding::Gamma => false,
+    };
+  }
+
+  // The fast path just converts the values of the source image so it's only
+  // used if the ops haven't been changed and no specific encoding was requested.
+  // The values are taken as they are so the source needs to already have the
+  // output encoding, 8 bit sources being gamma encoded and 16 bit ones linear.
+  // Float images are linear and unclamped so the conversion would be wrong.
+  fn fastpath_image(&self) -> Option<&OtherImage> {
+    if let ImageSource::Other(ref image) = self.globals.image {
+      let bits_per_channel = image.color().bits_per_pixel() / image.color().channel_count() as u16;
+      let source_linear = bits_per_channel != 8;
+      if self.globals.settings.use_fastpath &&
+         !self.globals.image.is_float() &&
+         source_linear == self.globals.settings.linear &&
+         self.globals.settings.output_encoding == OutputEncoding::Default &&
+         self.globals.settings.fit == FitMode::Contain &&
+         self.default_ops() {
+        return Some(image)
+      }
+    }
+    None
+  }
+
+  /// Run the pipeline and output an 8 bit image
+  ///
+  /// Unless the output encoding says otherwise the result has sRGB gamma applied
   pub fn output_8bit(&mut self, cache: Option<&PipelineCache>) -> Result<SRGBImage, String> {
+    self.set_output_linear(false);
+    let linear = self.globals.settings.linear;
+
     // If the image is raster and we haven't changed it yet there's no need to go
     // through the whole pipeline. Just go straight to 8bit using the image
     // crate and resize if needed
-    if let ImageSource::Other(ref image) = self.globals.image {
-      if self.globals.settings.use_fastpath && self.default_ops() {
-        return Ok(do_timing!("total output_8bit_fastpath()", {
-        let rgb = image.to_rgb8();
-        let (width, height) = (rgb.width() as usize, rgb.height() as usize);
-        let out = SRGBImage{
-          width,
-          height,
-          data: rgb.into_raw(),
-        };
-        let (nwidth, nheight) = crate::scaling::scaling_size(
-          out.width, out.height,
-          self.globals.settings.maxwidth, self.globals.settings.maxheight
-        );
-        if nwidth != out.width || nheight != out.height {
-          crate::scaling::scale_down_srgb(&out, nwidth, nheight)
-        } else {
-          out
-        }
-        }))
+    if let Some(image) = self.fastpath_image() {
+      return Ok(do_timing!("total output_8bit_fastpath()", {
+      let oriented = self.ops.transform.orient_image(image);
+      let rgb = oriented.as_ref().unwrap_or(image).to_rgb8();
+      let (width, height) = (rgb.width() as usize, rgb.height() as usize);
+      let out = SRGBImage{
+        width,
+        height,
+        data: rgb.into_raw(),
+        linear,
+      };
+      let upscaling = self.globals.settings.upscaling;
+      let (nwidth, nheight) = crate::scaling::scaling_size(
+        out.width, out.height,
+        self.globals.settings.maxwidth, self.globals.settings.maxheight,
+        upscaling.enabled(),
+      );
+      if nwidth > out.width || nheight > out.height {
+        crate::scaling::scale_up_srgb(&out, nwidth, nheight, upscaling)
+      } else if nwidth != out.width || nheight != out.height {
+        crate::scaling::scale_down_srgb(&out, nwidth, nheight, self.globals.settings.resampling)
+      } else {
+        out
       }
+      }))
     }
 
     do_timing!("total output_8bit()", {
-    self.globals.settings.linear = false;
     let buffer = self.run(cache);
 
     let image = do_timing!("  8 bit conversion", {
@@ -417,42 +1002,52 @@ impl Pipeline {
       width: buffer.width,
       height: buffer.height,
       data: image,
+      linear,
     })
     })
   }
 
+  /// Run the pipeline and output a 16 bit image
+  ///
+  /// Unless the output encoding says otherwise the result is linear
   pub fn output_16bit(&mut self, cache: Option<&PipelineCache>) -> Result<SRGBImage16, String> {
+    self.set_output_linear(true);
+    let linear = self.globals.settings.linear;
+
     // If the image is raster and we haven't changed it yet there's no need to go
     // through the whole pipeline. Just go straight to 16bit using the image
     // crate and resize if needed
-    if let ImageSource::Other(ref image) = self.globals.image {
-      if self.globals.settings.use_fastpath && self.default_ops() {
-        return Ok(do_timing!("total output_16bit_fastpath()", {
-        let rgb = image.to_rgb16();
-        let (width, height) = (rgb.width() as usize, rgb.height() as usize);
-        let out = SRGBImage16{
-          width,
-          height,
-          data: rgb.into_raw(),
-        };
-        let (nwidth, nheight) = crate::scaling::scaling_size(
-          out.width, out.height,
-          self.globals.settings.maxwidth, self.globals.settings.maxheight
-        );
-        if nwidth != out.width || nheight != out.height {
-          crate::scaling::scale_down_srgb16(&out, nwidth, nheight)
-        } else {
-          out
-        }
-        }))
+    if let Some(image) = self.fastpath_image() {
+      return Ok(do_timing!("total output_16bit_fastpath()", {
+      let oriented = self.ops.transform.orient_image(image);
+      let rgb = oriented.as_ref().unwrap_or(image).to_rgb16();
+      let (width, height) = (rgb.width() as usize, rgb.height() as usize);
+      let out = SRGBImage16{
+        width,
+        height,
+        data: rgb.into_raw(),
+        linear,
+      };
+      let upscaling = self.globals.settings.upscaling;
+      let (nwidth, nheight) = crate::scaling::scaling_size(
+        out.width, out.height,
+        self.globals.settings.maxwidth, self.globals.settings.maxheight,
+        upscaling.enabled(),
+      );
+      if nwidth > out.width || nheight > out.height {
+        crate::scaling::scale_up_srgb16(&out, nwidth, nheight, upscaling)
+      } else if nwidth != out.width || nheight != out.height {
+        crate::scaling::scale_down_srgb16(&out, nwidth, nheight, self.globals.settings.resampling)
+      } else {
+        out
       }
+      }))
     }
 
     do_timing!("total output_16bit()", {
-    self.globals.settings.linear = true;
     let buffer = self.run(cache);
 
-    let image = do_timing!("  8 bit conversion", {
+    let image = do_timing!("  16 bit conversion", {
       let mut image = vec![0 as u16; buffer.width*buffer.height*3];
       for (o, i) in image.chunks_exact_mut(1).zip(buffer.data.iter()) {
         o[0] = output16bit(*i);
@@ -464,6 +1059,121 @@ impl Pipeline {
       width: buffer.width,
       height: buffer.height,
       data: image,
+      linear,
+    })
+    })
+  }
+
+  /// Run the pipeline and output an 8 bit image with an alpha channel
+  ///
+  /// The alpha of the source follows the rotation, crop and scaling of the image.
+  /// Images without alpha come out fully opaque.
+  pub fn output_8bit_rgba(&mut self, cache: Option<&PipelineCache>) -> Result<SRGBAImage, String> {
+    if !self.globals.image.has_alpha() {
+      let image = self.output_8bit(cache)?;
+      let mut data = vec![255u8; image.width*image.height*4];
+      for (o, i) in data.chunks_exact_mut(4).zip(image.data.chunks_exact(3)) {
+        o[0..3].copy_from_slice(i);
+      }
+      return Ok(SRGBAImage{
+        width: image.width,
+        height: image.height,
+        data,
+        linear: image.linear,
+      })
+    }
+
+    // The fast path only knows about RGB so always go through the pipeline
+    self.set_output_linear(false);
+    let linear = self.globals.settings.linear;
+
+    do_timing!("total output_8bit_rgba()", {
+    let buffer = self.run(cache);
+    let alpha = do_timing!("  alpha", self.run_alpha());
+
+    let image = do_timing!("  8 bit conversion", {
+      let mut image = vec![0u8; buffer.width*buffer.height*4];
+      for ((o, i), a) in image.chunks_exact_mut(4).zip(buffer.data.chunks_exact(3)).zip(alpha.data.iter()) {
+        o[0] = output8bit(i[0]);
+        o[1] = output8bit(i[1]);
+        o[2] = output8bit(i[2]);
+        o[3] = output8bit(*a);
+      }
+      image
+    });
+
+    Ok(SRGBAImage{
+      width: buffer.width,
+      height: buffer.height,
+      data: image,
+      linear,
+    })
+    })
+  }
+
+  /// Run the pipeline and output a 16 bit image with an alpha channel
+  ///
+  /// The alpha of the source follows the rotation, crop and scaling of the image.
+  /// Images without alpha come out fully opaque.
+  pub fn output_16bit_rgba(&mut self, cache: Option<&PipelineCache>) -> Result<SRGBAImage16, String> {
+    if !self.globals.image.has_alpha() {
+      let image = self.output_16bit(cache)?;
+      let mut data = vec![65535u16; image.width*image.height*4];
+      for (o, i) in data.chunks_exact_mut(4).zip(image.data.chunks_exact(3)) {
+        o[0..3].copy_from_slice(i);
+      }
+      return Ok(SRGBAImage16{
+        width: image.width,
+        height: image.height,
+        data,
+        linear: image.linear,
+      })
+    }
+
+    // The fast path only knows about RGB so always go through the pipeline
+    self.set_output_linear(true);
+    let linear = self.globals.settings.linear;
+
+    do_timing!("total output_16bit_rgba()", {
+    let buffer = self.run(cache);
+    let alpha = do_timing!("  alpha", self.run_alpha());
+
+    let image = do_timing!("  16 bit conversion", {
+      let mut image = vec![0u16; buffer.width*buffer.height*4];
+      for ((o, i), a) in image.chunks_exact_mut(4).zip(buffer.data.chunks_exact(3)).zip(alpha.data.iter()) {
+        o[0] = output16bit(i[0]);
+        o[1] = output16bit(i[1]);
+        o[2] = output16bit(i[2]);
+        o[3] = output16bit(*a);
+      }
+      image
+    });
+
+    Ok(SRGBAImage16{
+      width: buffer.width,
+      height: buffer.height,
+      data: image,
+      linear,
+    })
+    })
+  }
+
+  /// Run the pipeline and output a floating point image
+  ///
+  /// Unless the output encoding says otherwise the result is linear. Values are
+  /// not clamped so they may go beyond the 0.0-1.0 range if nothing clipped them
+  /// in the pipeline.
+  pub fn output_float(&mut self, cache: Option<&PipelineCache>) -> Result<SRGBImageFloat, String> {
+    self.set_output_linear(true);
+
+    do_timing!("total output_float()", {
+    let buffer = self.run(cache);
+
+    Ok(SRGBImageFloat{
+      width: buffer.width,
+      height: buffer.height,
+      data: buffer.data.clone(),
+      linear: self.globals.settings.linear,
     })
     })
   }
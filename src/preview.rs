@@ -0,0 +1,388 @@
+use crate::ops::transform::OpTransform;
+use crate::pipeline::{Pipeline, SRGBImage};
+use crate::scaling::{self, Resampling};
+use rawloader::Orientation;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// TIFF tags that point to the previews
+static TAG_COMPRESSION: u16 = 259;
+static TAG_STRIP_OFFSETS: u16 = 273;
+static TAG_ORIENTATION: u16 = 274;
+static TAG_STRIP_BYTE_COUNTS: u16 = 279;
+static TAG_SUB_IFDS: u16 = 330;
+static TAG_JPEG_OFFSET: u16 = 513;
+static TAG_JPEG_LENGTH: u16 = 514;
+// Most IFDs we look at, so broken files with loops in them still end
+static MAX_IFDS: usize = 32;
+
+// Tags of an IFD with their values
+type IfdEntries = Vec<(u16, Vec<u32>)>;
+
+/// Where thumbnails come from
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum PreviewMode {
+  /// Use an embedded preview if there's one large enough and the pipeline if not
+  #[default]
+  Auto,
+  /// Only use embedded previews, failing if none is large enough
+  PreviewOnly,
+  /// Always run the pipeline
+  PipelineOnly,
+}
+
+// A JPEG preview stored in a file, with the orientation from its own EXIF
+#[derive(Debug, Clone, PartialEq)]
+struct EmbeddedJpeg {
+  offset: u64,
+  length: u64,
+  width: usize,
+  height: usize,
+  orientation: Option<Orientation>,
+}
+
+// Reads the parts of a file we need without loading the whole of it
+struct Reader<'a, R> {
+  reader: &'a mut R,
+  size: u64,
+  little_endian: bool,
+}
+
+impl<'a, R: Read + Seek> Reader<'a, R> {
+  fn new(reader: &'a mut R) -> Option<Self> {
+    let size = reader.seek(SeekFrom::End(0)).ok()?;
+    Some(Self {
+      reader,
+      size,
+      little_endian: false,
+    })
+  }
+
+  fn bytes(&mut self, offset: u64, len: u64) -> Option<Vec<u8>> {
+    if offset.checked_add(len)? > self.size {
+      return None
+    }
+    let mut buf = vec![0; len as usize];
+    self.reader.seek(SeekFrom::Start(offset)).ok()?;
+    self.reader.read_exact(&mut buf).ok()?;
+    Some(buf)
+  }
+
+  fn u16(&self, data: &[u8]) -> u16 {
+    let bytes = [data[0], data[1]];
+    if self.little_endian {u16::from_le_bytes(bytes)} else {u16::from_be_bytes(bytes)}
+  }
+
+  fn u32(&self, data: &[u8]) -> u32 {
+    let bytes = [data[0], data[1], data[2], data[3]];
+    if self.little_endian {u32::from_le_bytes(bytes)} else {u32::from_be_bytes(bytes)}
+  }
+
+  // The offset of the first IFD if this is a TIFF file. The magic number isn't
+  // checked as some raw formats change it.
+  fn tiff_header(&mut self) -> Option<u64> {
+    let header = self.bytes(0, 8)?;
+    self.little_endian = match &header[0..2] {
+      b"II" => true,
+      b"MM" => false,
+      _ => return None,
+    };
+    Some(self.u32(&header[4..8]) as u64)
+  }
+
+  // The SHORT and LONG entries of the IFD at offset, and the offset of the next one
+  fn ifd(&mut self, offset: u64) -> Option<(IfdEntries, u64)> {
+    let count = self.bytes(offset, 2)?;
+    let count = self.u16(&count) as u64;
+    let data = self.bytes(offset + 2, count * 12 + 4)?;
+    let mut entries = Vec::new();
+    for entry in data.chunks_exact(12) {
+      let tag = self.u16(&entry[0..2]);
+      let count = self.u32(&entry[4..8]) as u64;
+      let size = match self.u16(&entry[2..4]) {
+        3 => 2,
+        4 | 13 => 4,
+        _ => continue,
+      };
+      // Only the first few values are ever needed
+      let count = count.min(16);
+      let values = if count * size <= 4 {
+        entry[8..12].to_vec()
+      } else {
+        let offset = self.u32(&entry[8..12]) as u64;
+        self.bytes(offset, count * size)?
+      };
+      let values = values.chunks_exact(size as usize).take(count as usize).map(|v| {
+        if size == 2 {self.u16(v) as u32} else {self.u32(v)}
+      }).collect();
+      entries.push((tag, values));
+    }
+    let next = self.u32(&data[data.len()-4..]) as u64;
+    Some((entries, next))
+  }
+
+  // Size and orientation of the JPEG at offset from its header. Only baseline
+  // and progressive JPEGs are taken as previews, as lossless JPEGs are how many
+  // raws store the sensor data itself.
+  fn jpeg(&mut self, offset: u64, length: u64) -> Option<EmbeddedJpeg> {
+    if self.bytes(offset, 2)? != [0xff, 0xd8] {
+      return None
+    }
+    let mut orientation = None;
+    let mut pos = offset + 2;
+    while pos < offset + length {
+      let header = self.bytes(pos, 4)?;
+      if header[0] != 0xff {
+        return None
+      }
+      let marker = header[1];
+      if marker == 0xff {
+        pos += 1;
+        continue
+      }
+      let len = ((header[2] as u64) << 8) | header[3] as u64;
+      match marker {
+        0xc0..=0xc2 => {
+          let sof = self.bytes(pos + 4, 5)?;
+          let height = ((sof[1] as usize) << 8) | sof[2] as usize;
+          let width = ((sof[3] as usize) << 8) | sof[4] as usize;
+          if width == 0 || height == 0 {
+            return None
+          }
+          return Some(EmbeddedJpeg {
+            offset,
+            length,
+            width,
+            height,
+            orientation,
+          })
+        },
+        0xe1 if len > 8 => {
+          let segment = self.bytes(pos + 4, len - 2)?;
+          if segment.starts_with(b"Exif\0\0") {
+            orientation = tiff_orientation(&segment[6..]);
+          }
+        },
+        0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf | 0xd9 | 0xda => return None,
+        _ => {},
+      }
+      pos += 2 + len;
+    }
+    None
+  }
+}
+
+fn orientation_value(entries: &[(u16, Vec<u32>)]) -> Option<Orientation> {
+  match value(entries, TAG_ORIENTATION) {
+    Some(value @ 1..=8) => Some(Orientation::from_u16(value as u16)),
+    _ => None,
+  }
+}
+
+fn value(entries: &[(u16, Vec<u32>)], tag: u16) -> Option<u32> {
+  entries.iter().find(|(t, _)| *t == tag).and_then(|(_, v)| v.first().copied())
+}
+
+// Orientation in IFD0 of a TIFF structure held in memory
+fn tiff_orientation(data: &[u8]) -> Option<Orientation> {
+  let mut cursor = Cursor::new(data);
+  let mut reader = Reader::new(&mut cursor)?;
+  let offset = reader.tiff_header()?;
+  orientation_value(&reader.ifd(offset)?.0)
+}
+
+// The JPEG previews of a TIFF based raw and the orientation in its IFD0
+//
+// Previews are either pointed to by JPEGInterchangeFormat or stored as a single
+// JPEG compressed strip, in IFD0, the IFDs chained after it or their SubIFDs.
+fn find_jpegs<R: Read + Seek>(file: &mut R) -> Option<(Vec<EmbeddedJpeg>, Option<Orientation>)> {
+  let mut reader = Reader::new(file)?;
+  let first = reader.tiff_header()?;
+  let mut orientation = None;
+  let mut jpegs = Vec::new();
+  let mut pending = vec![first];
+  let mut visited = Vec::new();
+  while let Some(offset) = pending.pop() {
+    if offset == 0 || visited.contains(&offset) || visited.len() >= MAX_IFDS {
+      continue
+    }
+    visited.push(offset);
+    let (entries, next) = match reader.ifd(offset) {
+      Some(ifd) => ifd,
+      None => continue,
+    };
+    if offset == first {
+      orientation = orientation_value(&entries);
+    }
+
+    let mut previews = Vec::new();
+    if let (Some(start), Some(length)) = (value(&entries, TAG_JPEG_OFFSET), value(&entries, TAG_JPEG_LENGTH)) {
+      previews.push((start, length));
+    }
+    let values = |tag| entries.iter().find(|(t, _)| *t == tag).map(|(_, v)| v.as_slice());
+    if let (Some(6 | 7), Some(&[start]), Some(&[length])) =
+      (value(&entries, TAG_COMPRESSION), values(TAG_STRIP_OFFSETS), values(TAG_STRIP_BYTE_COUNTS)) {
+      previews.push((start, length));
+    }
+    for (start, length) in previews {
+      if let Some(jpeg) = reader.jpeg(start as u64, length as u64) {
+        jpegs.push(jpeg);
+      }
+    }
+
+    if let Some(subifds) = values(TAG_SUB_IFDS) {
+      pending.extend(subifds.iter().map(|v| *v as u64));
+    }
+    pending.push(next);
+  }
+  Some((jpegs, orientation))
+}
+
+// Whether an image of this size can be scaled down to the requested size
+fn large_enough(width: usize, height: usize, maxwidth: usize, maxheight: usize) -> bool {
+  if maxwidth == 0 && maxheight == 0 {
+    return false
+  }
+  let (nwidth, nheight) = scaling::scaling_size(width, height, maxwidth, maxheight, false);
+  (maxwidth > 0 && nwidth >= maxwidth) || (maxheight > 0 && nheight >= maxheight)
+}
+
+// The smallest embedded preview that's large enough once oriented, oriented
+// and scaled
+fn from_preview<R: Read + Seek>(file: &mut R, maxwidth: usize, maxheight: usize) -> Option<SRGBImage> {
+  let (jpegs, file_orientation) = find_jpegs(file)?;
+  // Previews are mostly stored like the raw and only sometimes have their own EXIF
+  let (jpeg, orientation) = jpegs.into_iter().filter_map(|jpeg| {
+    let orientation = jpeg.orientation.or(file_orientation).unwrap_or(Orientation::Normal);
+    let (width, height) = if orientation.to_flips().0 {
+      (jpeg.height, jpeg.width)
+    } else {
+      (jpeg.width, jpeg.height)
+    };
+    if large_enough(width, height, maxwidth, maxheight) {Some((jpeg, orientation))} else {None}
+  }).min_by_key(|(jpeg, _)| jpeg.width * jpeg.height)?;
+
+  let bytes = Reader::new(file)?.bytes(jpeg.offset, jpeg.length)?;
+  let img = image::load_from_memory_with_format(&bytes, image::ImageFormat::Jpeg).ok()?;
+  let transform = OpTransform::from_orientation(orientation);
+  let rgb = transform.orient_image(&img).unwrap_or(img).to_rgb8();
+  let out = SRGBImage {
+    width: rgb.width() as usize,
+    height: rgb.height() as usize,
+    data: rgb.into_raw(),
+    linear: false,
+  };
+  let (nwidth, nheight) = scaling::scaling_size(out.width, out.height, maxwidth, maxheight, false);
+  if nwidth != out.width || nheight != out.height {
+    Some(scaling::scale_down_srgb(&out, nwidth, nheight, Resampling::default()))
+  } else {
+    Some(out)
+  }
+}
+
+/// Decode a thumbnail of at most maxwidth x maxheight, using the preview
+/// embedded in the file when there's one large enough
+///
+/// Previews are found in TIFF based raws and are usually the camera's JPEG
+/// rendering, so they can look different from what the pipeline would output,
+/// but are much faster to get.
+pub fn thumbnail_8bit<P: AsRef<Path>>(path: P, maxwidth: usize, maxheight: usize, mode: PreviewMode) -> Result<SRGBImage, String> {
+  if mode != PreviewMode::PipelineOnly {
+    let mut file = File::open(&path).map_err(|e| format!("imagepipe: couldn't open file: {}", e))?;
+    if let Some(image) = from_preview(&mut file, maxwidth, maxheight) {
+      return Ok(image)
+    }
+    if mode == PreviewMode::PreviewOnly {
+      return Err("imagepipe: no embedded preview large enough".to_string())
+    }
+  }
+  let mut pipeline = Pipeline::new_from_file(&path)?;
+  pipeline.globals.settings.maxwidth = maxwidth;
+  pipeline.globals.settings.maxheight = maxheight;
+  pipeline.output_8bit(None)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn jpeg(width: u32, height: u32) -> Vec<u8> {
+    let img = image::RgbImage::from_pixel(width, height, image::Rgb([100, 150, 200]));
+    let mut out = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new(&mut out).encode_image(&img).unwrap();
+    out
+  }
+
+  fn entry(out: &mut Vec<u8>, tag: u16, value: u32) {
+    out.extend_from_slice(&tag.to_le_bytes());
+    out.extend_from_slice(&4u16.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&value.to_le_bytes());
+  }
+
+  // A little endian TIFF with the orientation and the first JPEG in IFD0 and the
+  // second one as a strip of a SubIFD
+  fn tiff(orientation: u32, first: &[u8], second: &[u8]) -> Vec<u8> {
+    let (ifd0, subifd, data) = (8, 62, 104);
+    let mut out = b"II\x2a\0".to_vec();
+    out.extend_from_slice(&(ifd0 as u32).to_le_bytes());
+    out.extend_from_slice(&4u16.to_le_bytes());
+    entry(&mut out, TAG_ORIENTATION, orientation);
+    entry(&mut out, TAG_SUB_IFDS, subifd);
+    entry(&mut out, TAG_JPEG_OFFSET, data);
+    entry(&mut out, TAG_JPEG_LENGTH, first.len() as u32);
+    out.extend_from_slice(&0u32.to_le_bytes());
+    assert_eq!(out.len(), subifd as usize);
+    out.extend_from_slice(&3u16.to_le_bytes());
+    entry(&mut out, TAG_COMPRESSION, 7);
+    entry(&mut out, TAG_STRIP_OFFSETS, data + first.len() as u32);
+    entry(&mut out, TAG_STRIP_BYTE_COUNTS, second.len() as u32);
+    out.extend_from_slice(&0u32.to_le_bytes());
+    assert_eq!(out.len(), data as usize);
+    out.extend_from_slice(first);
+    out.extend_from_slice(second);
+    out
+  }
+
+  #[test]
+  fn find_embedded() {
+    let (small, large) = (jpeg(16, 8), jpeg(64, 48));
+    let data = tiff(3, &small, &large);
+    let (jpegs, orientation) = find_jpegs(&mut Cursor::new(&data)).unwrap();
+    assert_eq!(orientation, Some(Orientation::Rotate180));
+    assert_eq!(jpegs, vec![
+      EmbeddedJpeg { offset: 104, length: small.len() as u64, width: 16, height: 8, orientation: None },
+      EmbeddedJpeg { offset: 104 + small.len() as u64, length: large.len() as u64, width: 64, height: 48, orientation: None },
+    ]);
+
+    // Lossless JPEG like the ones used for raw data
+    let lossless = vec![0xff, 0xd8, 0xff, 0xc3, 0x00, 0x0b, 0x0c, 0x00, 0x10, 0x00, 0x10, 0x01, 0x01, 0x11, 0x00,
+                        0xff, 0xd9];
+    let data = tiff(1, &small, &lossless);
+    assert_eq!(find_jpegs(&mut Cursor::new(&data)).unwrap().0.len(), 1);
+    assert_eq!(find_jpegs(&mut Cursor::new(&small)), None);
+  }
+
+  #[test]
+  fn preview_sizes() {
+    assert!(large_enough(640, 480, 256, 256));
+    assert!(large_enough(640, 480, 640, 0));
+    assert!(!large_enough(640, 480, 1024, 1024));
+    assert!(!large_enough(640, 480, 0, 0));
+
+    let data = tiff(1, &jpeg(16, 8), &jpeg(64, 48));
+    let thumb = from_preview(&mut Cursor::new(&data), 32, 32).unwrap();
+    assert_eq!((thumb.width, thumb.height), (32, 24));
+    assert!((thumb.data[0] as i32 - 100).abs() < 5);
+    assert_eq!(from_preview(&mut Cursor::new(&data), 100, 100), None);
+  }
+
+  #[test]
+  fn rotated_preview_sizes() {
+    // The first preview is wide enough as stored but not once rotated
+    let data = tiff(6, &jpeg(64, 48), &jpeg(160, 120));
+    let thumb = from_preview(&mut Cursor::new(&data), 60, 0).unwrap();
+    assert_eq!((thumb.width, thumb.height), (60, 80));
+  }
+}
@@ -4,8 +4,122 @@ use rawloader::CFA;
 use num_traits::cast::AsPrimitive;
 use rayon::prelude::*;
 use std::cmp;
+use std::f32::consts::PI;
 
-fn calculate_scaling_total(width: usize, height: usize, maxwidth: usize, maxheight: usize) -> (f32, usize, usize) {
+/// Filter used to resample images when scaling and rotating
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum Resampling {
+  /// Weighted average of the covered area, fast but lets some aliasing through
+  #[default]
+  Area,
+  /// Plain average of the covered area, also known as nearest neighbour when
+  /// not scaling down
+  Box,
+  /// Linear interpolation, bilinear when not scaling down
+  Triangle,
+  /// Smooth result with no ringing but somewhat soft
+  Gaussian,
+  /// Windowed sinc that keeps the most detail but can ring around edges
+  Lanczos3,
+  /// Mitchell-Netravali cubic, a compromise between blur and ringing
+  Mitchell,
+  /// Catmull-Rom cubic, sharper than Mitchell and the usual bicubic
+  CatmullRom,
+}
+
+/// How to make images larger when the maximum size is above the image size
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum Upscaling {
+  /// Never go above the size of the image
+  #[default]
+  Disabled,
+  /// Catmull-Rom bicubic interpolation
+  Bicubic,
+  /// Lanczos interpolation, sharper but can ring around edges
+  Lanczos3,
+  /// Interpolate along edges instead of across them so they stay smooth and
+  /// sharp, at the cost of some painterly look in fine textures
+  EdgeDirected,
+}
+
+/// How to fit the image into maxwidth x maxheight when both are set
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum FitMode {
+  /// Fit inside keeping the aspect ratio so one of the sides may be smaller
+  #[default]
+  Contain,
+  /// Fill the whole size keeping the aspect ratio and crop what doesn't fit.
+  /// Gravity is where the crop is taken from, (0.5, 0.5) for the center and
+  /// (0.0, 0.0) for the top left.
+  Cover {
+    gravity: (f32, f32),
+  },
+  /// Fill the whole size without keeping the aspect ratio
+  Stretch,
+  /// Fit inside keeping the aspect ratio and fill the rest with an sRGB color
+  Pad {
+    color: [f32;3],
+  },
+}
+
+impl Upscaling {
+  pub fn enabled(&self) -> bool {
+    *self != Upscaling::Disabled
+  }
+}
+
+impl Resampling {
+  // Distance from the center in destination pixels after which the kernel is 0
+  fn support(&self) -> f32 {
+    match self {
+      Resampling::Area => 1.0,
+      Resampling::Box => 0.5,
+      Resampling::Triangle => 1.0,
+      Resampling::Gaussian => 1.5,
+      Resampling::Lanczos3 => 3.0,
+      Resampling::Mitchell => 2.0,
+      Resampling::CatmullRom => 2.0,
+    }
+  }
+
+  #[inline(always)]
+  fn weight(&self, t: f32) -> f32 {
+    let t = t.abs();
+    if t > self.support() {
+      return 0.0
+    }
+    match self {
+      Resampling::Area => 1.0 - t*t,
+      Resampling::Box => 1.0,
+      Resampling::Triangle => 1.0 - t,
+      Resampling::Gaussian => (-2.0 * t * t).exp(),
+      Resampling::Lanczos3 => sinc(t) * sinc(t / 3.0),
+      Resampling::Mitchell => cubic(t, 1.0/3.0, 1.0/3.0),
+      Resampling::CatmullRom => cubic(t, 0.0, 0.5),
+    }
+  }
+}
+
+// Mitchell-Netravali family of cubics for 0 <= t <= 2
+#[inline(always)]
+fn cubic(t: f32, b: f32, c: f32) -> f32 {
+  if t < 1.0 {
+    ((12.0 - 9.0*b - 6.0*c)*t*t*t + (-18.0 + 12.0*b + 6.0*c)*t*t + (6.0 - 2.0*b)) / 6.0
+  } else {
+    ((-b - 6.0*c)*t*t*t + (6.0*b + 30.0*c)*t*t + (-12.0*b - 48.0*c)*t + (8.0*b + 24.0*c)) / 6.0
+  }
+}
+
+#[inline(always)]
+fn sinc(t: f32) -> f32 {
+  if t == 0.0 {
+    1.0
+  } else {
+    (PI * t).sin() / (PI * t)
+  }
+}
+
+fn calculate_scaling_total(width: usize, height: usize, maxwidth: usize, maxheight: usize, upscale: bool) -> (f32, usize, usize) {
   if maxwidth == 0 && maxheight == 0 {
     (1.0, width, height)
   } else {
@@ -13,7 +127,18 @@ fn calculate_scaling_total(width: usize, height: usize, maxwidth: usize, maxheig
     let xscale = if maxwidth == 0 {1.0} else {width as f32 / maxwidth as f32};
     let yscale = if maxheight == 0 {1.0} else {height as f32 / maxheight as f32};
     if yscale <= 1.0 && xscale <= 1.0 {
-      (1.0, width, height)
+      if upscale {
+        // Scale up until we hit one of the sides that is set
+        let xscale = if maxwidth == 0 {0.0} else {xscale};
+        let yscale = if maxheight == 0 {0.0} else {yscale};
+        if yscale > xscale {
+          (yscale, ((width as f32)/yscale).round() as usize, maxheight)
+        } else {
+          (xscale, maxwidth, ((height as f32)/xscale).round() as usize)
+        }
+      } else {
+        (1.0, width, height)
+      }
     } else if yscale > xscale {
       (yscale, ((width as f32)/yscale) as usize, maxheight)
     } else {
@@ -22,45 +147,59 @@ fn calculate_scaling_total(width: usize, height: usize, maxwidth: usize, maxheig
   }
 }
 
-pub fn scaling_size(width: usize, height: usize, maxwidth: usize, maxheight: usize) -> (usize, usize) {
-  let (_, width, height) = calculate_scaling_total(width, height, maxwidth, maxheight);
+pub fn scaling_size(width: usize, height: usize, maxwidth: usize, maxheight: usize, upscale: bool) -> (usize, usize) {
+  let (_, width, height) = calculate_scaling_total(width, height, maxwidth, maxheight, upscale);
   (width, height)
 }
 
-pub fn calculate_scale(width: usize, height: usize, maxwidth: usize, maxheight: usize) -> f32 {
-  calculate_scaling_total(width, height, maxwidth, maxheight).0
+pub fn calculate_scale(width: usize, height: usize, maxwidth: usize, maxheight: usize, upscale: bool) -> f32 {
+  calculate_scaling_total(width, height, maxwidth, maxheight, upscale).0
 }
 
-#[inline(always)]
-fn scale_down_buffer<T>(
-  src: &[T],
-  width: usize,
-  height: usize,
-  nwidth: usize,
-  nheight: usize,
-  components: usize,
-  cfa: Option<&CFA>,
-  ) -> Vec<T>
-  where f32: AsPrimitive<T>, T: AsPrimitive<f32>, T: Sync+Send {
+/// Source geometry and destination size of a resampling
+///
+/// The corners are the positions in the width x height source that end up at the
+/// top left, top right and bottom left pixels of the nwidth x nheight destination.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TransformGeometry {
+  pub width: usize,
+  pub height: usize,
+  pub topleft: (isize, isize),
+  pub topright: (isize, isize),
+  pub bottomleft: (isize, isize),
+  pub nwidth: usize,
+  pub nheight: usize,
+}
 
-  transform_buffer(src, width, height, (0, 0), (width as isize - 1, 0), (0, height as isize - 1),
-    nwidth, nheight, components, cfa)
+impl TransformGeometry {
+  /// Scale the whole source to the destination size
+  pub fn scale(width: usize, height: usize, nwidth: usize, nheight: usize) -> Self {
+    Self {
+      width,
+      height,
+      topleft: (0, 0),
+      topright: (width as isize - 1, 0),
+      bottomleft: (0, height as isize - 1),
+      nwidth,
+      nheight,
+    }
+  }
 }
 
 #[inline(always)]
 pub fn transform_buffer<T>(
   src: &[T],
-  width: usize,
-  height: usize,
-  topleft: (isize, isize),
-  topright: (isize, isize),
-  bottomleft: (isize, isize),
-  nwidth: usize,
-  nheight: usize,
+  geometry: TransformGeometry,
   components: usize,
   cfa: Option<&CFA>,
+  resampling: Resampling,
   ) -> Vec<T>
   where f32: AsPrimitive<T>, T: AsPrimitive<f32>, T: Sync+Send {
+  if resampling != Resampling::Area {
+    return transform_kernel(src, geometry, components, cfa, resampling)
+  }
+  let TransformGeometry{width, height, topleft, topright, bottomleft, nwidth, nheight} = geometry;
+
   let mut out = vec![(0 as f32).as_(); nwidth*nheight*components];
 
   // This scales by using a rectangular window of the source image for each
@@ -92,15 +231,8 @@ pub fn transform_buffer<T>(
       let mut counts = [0.0 as f32; 4];
       for y in from_y..=to_y {
         for x in from_x..=to_x {
-          // FIXME: Hopefully this is a reasonable low-pass filter that works for
-          //        most cases but something more sophisticated may be useful.
-          //        More specifically probably one of two things:
-          //        - A gaussian filter with parameters calculated based on how
-          //          much scale down we are doing so as to exactly remove the
-          //          high frequencies we can no longer represent
-          //        - A good windowed sinc function like Lanczos that should
-          //          preserve more detail but will always have some artifacts
-          //          in some cases
+          // A cheap low-pass filter, the other kernels in transform_kernel()
+          // remove aliasing better at a higher cost
           let delta_x = (x as f32 - center_x) / skip_x_x;
           let delta_y = (y as f32 - center_y) / skip_y_y;
           let factor = 1.0 - (delta_x*delta_x) - (delta_y*delta_y);
@@ -129,11 +261,92 @@ pub fn transform_buffer<T>(
   out
 }
 
-pub fn scaled_demosaic(cfa: CFA, buf: &OpBuffer, nwidth: usize, nheight: usize) -> OpBuffer {
+// Resample with one of the filter kernels. The kernel is placed on the center of
+// each destination pixel in the source and aligned with the destination axes so
+// it works the same for rotations. When scaling down it's stretched by the
+// scale factor so it removes the frequencies the destination can't represent.
+fn transform_kernel<T>(
+  src: &[T],
+  geometry: TransformGeometry,
+  components: usize,
+  cfa: Option<&CFA>,
+  resampling: Resampling,
+  ) -> Vec<T>
+  where f32: AsPrimitive<T>, T: AsPrimitive<f32>, T: Sync+Send {
+  let TransformGeometry{width, height, topleft, topright, bottomleft, nwidth, nheight} = geometry;
+  let mut out = vec![(0 as f32).as_(); nwidth*nheight*components];
+
+  let skip_x_x = (topright.0 - topleft.0) as f32 / cmp::max(nwidth-1, 1) as f32;
+  let skip_x_y = (topright.1 - topleft.1) as f32 / cmp::max(nwidth-1, 1) as f32;
+  let skip_y_x = (bottomleft.0 - topleft.0) as f32 / cmp::max(nheight-1, 1) as f32;
+  let skip_y_y = (bottomleft.1 - topleft.1) as f32 / cmp::max(nheight-1, 1) as f32;
+  let len_x = skip_x_x.hypot(skip_x_y).max(f32::EPSILON);
+  let len_y = skip_y_x.hypot(skip_y_y).max(f32::EPSILON);
+  // Unit vectors of the destination axes in the source
+  let (ux_x, ux_y) = (skip_x_x / len_x, skip_x_y / len_x);
+  let (uy_x, uy_y) = (skip_y_x / len_y, skip_y_y / len_y);
+  // Size of the kernel in source pixels, never smaller than a pixel so that
+  // scaling up interpolates between source pixels
+  let (fx, fy) = (len_x.max(1.0), len_y.max(1.0));
+  let support = resampling.support();
+  let reach_x = support * (fx * ux_x.abs() + fy * uy_x.abs());
+  let reach_y = support * (fx * ux_y.abs() + fy * uy_y.abs());
+
+  out.par_chunks_exact_mut(nwidth*components).enumerate().for_each(|(row, line)| {
+    for col in 0..nwidth {
+      // The destination pixel covers skip source pixels starting half a pixel
+      // before the topleft one in the direction of each axis
+      let (fcol, frow) = (col as f32 + 0.5, row as f32 + 0.5);
+      let center_x = topleft.0 as f32 + skip_x_x * fcol + skip_y_x * frow - 0.5 * (ux_x + uy_x);
+      let center_y = topleft.1 as f32 + skip_x_y * fcol + skip_y_y * frow - 0.5 * (ux_y + uy_y);
+      let from_x = (center_x - reach_x).ceil().max(0.0) as usize;
+      let to_x = ((center_x + reach_x).floor() as isize).min(width as isize - 1);
+      let from_y = (center_y - reach_y).ceil().max(0.0) as usize;
+      let to_y = ((center_y + reach_y).floor() as isize).min(height as isize - 1);
+      if to_x < 0 || to_y < 0 {
+        continue
+      }
+
+      let mut sums = [0.0f32; 4];
+      let mut counts = [0.0f32; 4];
+      for y in from_y..=(to_y as usize) {
+        for x in from_x..=(to_x as usize) {
+          let (dx, dy) = (x as f32 - center_x, y as f32 - center_y);
+          let factor = resampling.weight((dx * ux_x + dy * ux_y) / fx) *
+                       resampling.weight((dx * uy_x + dy * uy_y) / fy);
+          if factor == 0.0 {
+            continue
+          }
+
+          if let Some(cfa) = cfa {
+            let c = cfa.color_at(y, x);
+            sums[c] += src[y*width+x].as_() * factor;
+            counts[c] += factor;
+          } else {
+            for c in 0..components {
+              sums[c] += src[(y*width+x)*components+c].as_() * factor;
+              counts[c] += factor;
+            }
+          }
+        }
+      }
+
+      for c in 0..components {
+        if counts[c] > 0.0 {
+          line[col*components+c] = (sums[c] / counts[c]).as_();
+        }
+      }
+    }
+  });
+  out
+}
+
+pub fn scaled_demosaic(cfa: CFA, buf: &OpBuffer, nwidth: usize, nheight: usize, resampling: Resampling) -> OpBuffer {
   assert_eq!(buf.colors, 1); // When we're in demosaic we start with a 1 color buffer
 
   log::debug!("Doing a scaled demosaic from {}x{} to {}x{}", buf.width, buf.height, nwidth, nheight);
-  let data = scale_down_buffer(&buf.data, buf.width, buf.height, nwidth, nheight, 4, Some(&cfa));
+  let geometry = TransformGeometry::scale(buf.width, buf.height, nwidth, nheight);
+  let data = transform_buffer(&buf.data, geometry, 4, Some(&cfa), resampling);
 
   OpBuffer {
     width: nwidth,
@@ -144,40 +357,178 @@ pub fn scaled_demosaic(cfa: CFA, buf: &OpBuffer, nwidth: usize, nheight: usize)
   }
 }
 
-pub fn scale_down_opbuf(buf: &OpBuffer, nwidth: usize, nheight: usize) -> OpBuffer {
-  assert_eq!(buf.colors, 4); // When we're scaling down we're always at 4 cpp
-
+pub fn scale_down_opbuf(buf: &OpBuffer, nwidth: usize, nheight: usize, resampling: Resampling) -> OpBuffer {
+  // Images are always at 4 cpp here but alpha planes are scaled the same way
   log::debug!("Scaling OpBuffer from {}x{} to {}x{}", buf.width, buf.height, nwidth, nheight);
-  let data = scale_down_buffer(&buf.data, buf.width, buf.height, nwidth, nheight, 4, None);
+  let geometry = TransformGeometry::scale(buf.width, buf.height, nwidth, nheight);
+  let data = transform_buffer(&buf.data, geometry, buf.colors, None, resampling);
 
   OpBuffer {
     width: nwidth,
     height: nheight,
     data,
     monochrome: buf.monochrome,
-    colors: 4,
+    colors: buf.colors,
   }
 }
 
-pub fn scale_down_srgb(buf: &SRGBImage, nwidth: usize, nheight: usize) -> SRGBImage {
+pub fn scale_down_srgb(buf: &SRGBImage, nwidth: usize, nheight: usize, resampling: Resampling) -> SRGBImage {
   log::debug!("Scaling SRGBImage from {}x{} to {}x{}", buf.width, buf.height, nwidth, nheight);
-  let data = scale_down_buffer(&buf.data, buf.width, buf.height, nwidth, nheight, 3, None);
+  let geometry = TransformGeometry::scale(buf.width, buf.height, nwidth, nheight);
+  let data = transform_buffer(&buf.data, geometry, 3, None, resampling);
 
   SRGBImage {
     width: nwidth,
     height: nheight,
     data,
+    linear: buf.linear,
   }
 }
 
-pub fn scale_down_srgb16(buf: &SRGBImage16, nwidth: usize, nheight: usize) -> SRGBImage16 {
+pub fn scale_down_srgb16(buf: &SRGBImage16, nwidth: usize, nheight: usize, resampling: Resampling) -> SRGBImage16 {
   log::debug!("Scaling SRGBImage from {}x{} to {}x{}", buf.width, buf.height, nwidth, nheight);
-  let data = scale_down_buffer(&buf.data, buf.width, buf.height, nwidth, nheight, 3, None);
+  let geometry = TransformGeometry::scale(buf.width, buf.height, nwidth, nheight);
+  let data = transform_buffer(&buf.data, geometry, 3, None, resampling);
+
+  SRGBImage16 {
+    width: nwidth,
+    height: nheight,
+    data,
+    linear: buf.linear,
+  }
+}
+
+#[inline(always)]
+fn scale_up_buffer<T>(
+  src: &[T],
+  width: usize,
+  height: usize,
+  nwidth: usize,
+  nheight: usize,
+  components: usize,
+  upscaling: Upscaling,
+  ) -> Vec<T>
+  where f32: AsPrimitive<T>, T: AsPrimitive<f32>, T: Sync+Send {
+  let geometry = TransformGeometry::scale(width, height, nwidth, nheight);
+  match upscaling {
+    Upscaling::EdgeDirected => edge_directed(src, width, height, nwidth, nheight, components),
+    Upscaling::Lanczos3 => transform_buffer(src, geometry, components, None, Resampling::Lanczos3),
+    Upscaling::Bicubic | Upscaling::Disabled => {
+      transform_buffer(src, geometry, components, None, Resampling::CatmullRom)
+    },
+  }
+}
+
+// Interpolate with a gaussian that is stretched along the local edge direction
+// and squeezed across it. The direction comes from the structure tensor of the
+// luminance around each destination pixel, and how much the kernel is stretched
+// from how clearly there's a single direction there.
+fn edge_directed<T>(
+  src: &[T],
+  width: usize,
+  height: usize,
+  nwidth: usize,
+  nheight: usize,
+  components: usize,
+  ) -> Vec<T>
+  where f32: AsPrimitive<T>, T: AsPrimitive<f32>, T: Sync+Send {
+  let mut out = vec![(0 as f32).as_(); nwidth*nheight*components];
+
+  let lumcomponents = cmp::min(components, 3);
+  let luma: Vec<f32> = src.chunks_exact(components).map(|pix| {
+    pix[0..lumcomponents].iter().map(|v| v.as_()).sum::<f32>() / lumcomponents as f32
+  }).collect();
+  let get = |x: isize, y: isize| -> usize {
+    let x = x.clamp(0, width as isize - 1) as usize;
+    let y = y.clamp(0, height as isize - 1) as usize;
+    y*width+x
+  };
+
+  let (xratio, yratio) = (width as f32 / nwidth as f32, height as f32 / nheight as f32);
+  out.par_chunks_exact_mut(nwidth*components).enumerate().for_each(|(row, line)| {
+    let sy = (row as f32 + 0.5) * yratio - 0.5;
+    let iy = sy.floor() as isize;
+    for col in 0..nwidth {
+      let sx = (col as f32 + 0.5) * xratio - 0.5;
+      let ix = sx.floor() as isize;
+
+      let (mut jxx, mut jxy, mut jyy) = (0.0, 0.0, 0.0);
+      for y in iy..=iy+1 {
+        for x in ix..=ix+1 {
+          let gx = (luma[get(x+1, y)] - luma[get(x-1, y)]) / 2.0;
+          let gy = (luma[get(x, y+1)] - luma[get(x, y-1)]) / 2.0;
+          jxx += gx*gx;
+          jxy += gx*gy;
+          jyy += gy*gy;
+        }
+      }
+      // The gradient is across the edge so the edge goes perpendicular to it
+      let angle = 0.5 * (2.0 * jxy).atan2(jxx - jyy);
+      let (nx, ny) = (angle.cos(), angle.sin());
+      let root = ((jxx - jyy) * (jxx - jyy) + 4.0 * jxy * jxy).sqrt();
+      let coherence = if jxx + jyy > 1e-8 {(root / (jxx + jyy)).powi(2)} else {0.0};
+      let sigma_along = 0.7 + 0.8 * coherence;
+      let sigma_across = 0.7 - 0.35 * coherence;
+
+      let mut sums = [0.0f32; 4];
+      let mut total = 0.0;
+      for y in iy-1..=iy+2 {
+        for x in ix-1..=ix+2 {
+          let (dx, dy) = (x as f32 - sx, y as f32 - sy);
+          let across = dx * nx + dy * ny;
+          let along = dy * nx - dx * ny;
+          let factor = (-0.5 * (along*along / (sigma_along*sigma_along) +
+                                across*across / (sigma_across*sigma_across))).exp();
+          let pos = get(x, y);
+          for (c, sum) in sums.iter_mut().enumerate().take(components) {
+            *sum += src[pos*components+c].as_() * factor;
+          }
+          total += factor;
+        }
+      }
+      for c in 0..components {
+        line[col*components+c] = (sums[c] / total).as_();
+      }
+    }
+  });
+  out
+}
+
+pub fn scale_up_opbuf(buf: &OpBuffer, nwidth: usize, nheight: usize, upscaling: Upscaling) -> OpBuffer {
+  // Images are always at 4 cpp here but alpha planes are scaled the same way
+  log::debug!("Scaling up OpBuffer from {}x{} to {}x{}", buf.width, buf.height, nwidth, nheight);
+  let data = scale_up_buffer(&buf.data, buf.width, buf.height, nwidth, nheight, buf.colors, upscaling);
+
+  OpBuffer {
+    width: nwidth,
+    height: nheight,
+    data,
+    monochrome: buf.monochrome,
+    colors: buf.colors,
+  }
+}
+
+pub fn scale_up_srgb(buf: &SRGBImage, nwidth: usize, nheight: usize, upscaling: Upscaling) -> SRGBImage {
+  log::debug!("Scaling up SRGBImage from {}x{} to {}x{}", buf.width, buf.height, nwidth, nheight);
+  let data = scale_up_buffer(&buf.data, buf.width, buf.height, nwidth, nheight, 3, upscaling);
+
+  SRGBImage {
+    width: nwidth,
+    height: nheight,
+    data,
+    linear: buf.linear,
+  }
+}
+
+pub fn scale_up_srgb16(buf: &SRGBImage16, nwidth: usize, nheight: usize, upscaling: Upscaling) -> SRGBImage16 {
+  log::debug!("Scaling up SRGBImage from {}x{} to {}x{}", buf.width, buf.height, nwidth, nheight);
+  let data = scale_up_buffer(&buf.data, buf.width, buf.height, nwidth, nheight, 3, upscaling);
 
   SRGBImage16 {
     width: nwidth,
     height: nheight,
     data,
+    linear: buf.linear,
   }
 }
 
@@ -197,8 +548,123 @@ mod tests {
       width,
       height,
       data,
+      linear: true,
     };
-    let new = scale_down_srgb16(&orig, width, height);
+    let new = scale_down_srgb16(&orig, width, height, Resampling::Area);
     assert_eq!(orig, new);
   }
+
+  // Circular zone plate going from 0 at the center to the Nyquist frequency
+  // at the middle of the edges
+  fn zone_plate(size: usize) -> Vec<f32> {
+    let mut data = vec![0.0; size*size];
+    let center = size as f32 / 2.0;
+    for row in 0..size {
+      for col in 0..size {
+        let (dx, dy) = (col as f32 - center, row as f32 - center);
+        data[row*size+col] = 0.5 + 0.5 * (PI * (dx*dx + dy*dy) / size as f32).cos();
+      }
+    }
+    data
+  }
+
+  // Largest deviation from flat gray in the part of the output where the
+  // source frequencies are well above what the output can represent
+  fn aliasing(resampling: Resampling) -> f32 {
+    let (size, scale) = (256, 4);
+    let nsize = size / scale;
+    let out = transform_buffer(&zone_plate(size), TransformGeometry::scale(size, size, nsize, nsize), 1, None, resampling);
+    let center = nsize as f32 / 2.0;
+    let mut max: f32 = 0.0;
+    for row in 0..nsize {
+      for col in 0..nsize {
+        let (dx, dy) = (col as f32 - center, row as f32 - center);
+        let radius = (dx*dx + dy*dy).sqrt() * scale as f32;
+        if radius > 64.0 && radius < 120.0 {
+          max = max.max((out[row*nsize+col] - 0.5).abs());
+        }
+      }
+    }
+    max
+  }
+
+  #[test]
+  fn zone_plate_aliasing() {
+    let area = aliasing(Resampling::Area);
+    for kernel in [Resampling::Triangle, Resampling::Gaussian, Resampling::Lanczos3, Resampling::Mitchell] {
+      let value = aliasing(kernel);
+      assert!(value < 0.05 && value < area, "{:?} aliasing is {}", kernel, value);
+    }
+  }
+
+  #[test]
+  fn lanczos_keeps_detail() {
+    // Frequencies at half of what the output can represent should mostly survive
+    let out = transform_buffer(&zone_plate(256), TransformGeometry::scale(256, 256, 64, 64), 1, None, Resampling::Lanczos3);
+    let (mut min, mut max) = (1.0f32, 0.0f32);
+    for row in 24..40 {
+      for col in 24..40 {
+        min = min.min(out[row*64+col]);
+        max = max.max(out[row*64+col]);
+      }
+    }
+    assert!(max - min > 0.8, "contrast is only {}", max - min);
+  }
+
+  #[test]
+  fn kernels_noop_and_rotate() {
+    let data: Vec<f32> = (0..20*10).map(|v| v as f32).collect();
+    for kernel in [Resampling::Box, Resampling::Triangle, Resampling::Lanczos3] {
+      let out = transform_buffer(&data, TransformGeometry::scale(20, 10, 20, 10), 1, None, kernel);
+      for (a, b) in out.iter().zip(data.iter()) {
+        assert!((a - b).abs() < 0.001, "{:?} gives {} instead of {}", kernel, a, b);
+      }
+      // Rotate 90 degrees clockwise
+      let out = transform_buffer(&data, TransformGeometry {
+        width: 20, height: 10, topleft: (0, 9), topright: (0, 0), bottomleft: (19, 9), nwidth: 10, nheight: 20,
+      }, 1, None, kernel);
+      for row in 0..20 {
+        for col in 0..10 {
+          let expected = data[(9-col)*20+row];
+          assert!((out[row*10+col] - expected).abs() < 0.001, "{:?} rotated gives {} instead of {}",
+            kernel, out[row*10+col], expected);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn upscale_sizes() {
+    assert_eq!(scaling_size(100, 50, 300, 0, false), (100, 50));
+    assert_eq!(scaling_size(100, 50, 300, 0, true), (300, 150));
+    assert_eq!(scaling_size(100, 50, 0, 200, true), (400, 200));
+    assert_eq!(scaling_size(100, 50, 300, 100, true), (200, 100));
+    assert_eq!(scaling_size(100, 50, 50, 0, true), (50, 25));
+  }
+
+  #[test]
+  fn upscale_edges() {
+    // Diagonal step from 0 to 1
+    let size = 16;
+    let mut data = vec![0.0f32; size*size*3];
+    for row in 0..size {
+      for col in 0..size {
+        if col > row {
+          data[(row*size+col)*3..(row*size+col)*3+3].copy_from_slice(&[1.0, 1.0, 1.0]);
+        }
+      }
+    }
+    let nsize = size*4;
+    for upscaling in [Upscaling::Bicubic, Upscaling::Lanczos3, Upscaling::EdgeDirected] {
+      let out = scale_up_buffer(&data, size, size, nsize, nsize, 3, upscaling);
+      assert_eq!(out.len(), nsize*nsize*3);
+      // Far from the edge nothing changes
+      let pix = |row: usize, col: usize| out[(row*nsize+col)*3];
+      assert!((pix(nsize-2, 1) - 0.0).abs() < 0.001, "{:?}", upscaling);
+      assert!((pix(1, nsize-2) - 1.0).abs() < 0.001, "{:?}", upscaling);
+    }
+    // Edge directed interpolation doesn't ring
+    let out = scale_up_buffer(&data, size, size, nsize, nsize, 3, Upscaling::EdgeDirected);
+    assert!(out.iter().all(|&v| (-0.001..=1.001).contains(&v)));
+  }
 }
@@ -0,0 +1,313 @@
+use crate::buffer::*;
+use crate::color_conversions::*;
+use crate::histogram::*;
+use rayon::prelude::*;
+use std::cmp;
+
+/// Where in the pipeline to sample the image for scopes
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ScopeStage {
+  /// Right after conversion to Lab, before any tone changes
+  Lab,
+  /// After the base curve has been applied
+  BaseCurve,
+  /// The final display referred output
+  Output,
+}
+
+impl ScopeStage {
+  // Name of the op after which we sample, None for the end of the pipeline
+  pub(crate) fn opname(&self) -> Option<&'static str> {
+    match self {
+      ScopeStage::Lab => Some("to_lab"),
+      ScopeStage::BaseCurve => Some("basecurve"),
+      ScopeStage::Output => None,
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct ScopeSettings {
+  pub stage: ScopeStage,
+  /// Number of bins in the histograms
+  pub bins: usize,
+  /// Number of columns in the waveform and parade, 0 to use the image width
+  pub waveform_width: usize,
+  /// Number of intensity levels in the waveform and parade
+  pub waveform_height: usize,
+  /// Width and height of the vectorscope
+  pub vectorscope_size: usize,
+}
+
+impl Default for ScopeSettings {
+  fn default() -> Self {
+    Self {
+      stage: ScopeStage::Output,
+      bins: 256,
+      waveform_width: 0,
+      waveform_height: 256,
+      vectorscope_size: 256,
+    }
+  }
+}
+
+/// A 2D grid of counts, such as a waveform or a vectorscope
+///
+/// The data is a Vec<u32> with width*height elements, where each element is the
+/// number of image pixels that fall in that position
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScopeImage {
+  pub width: usize,
+  pub height: usize,
+  pub data: Vec<u32>,
+}
+
+impl ScopeImage {
+  fn new(width: usize, height: usize) -> ScopeImage {
+    ScopeImage {
+      width,
+      height,
+      data: vec![0; width*height],
+    }
+  }
+
+  #[inline(always)]
+  fn add(&mut self, x: usize, y: usize) {
+    self.data[y*self.width+x] += 1;
+  }
+
+  fn merge(mut self, other: &ScopeImage) -> ScopeImage {
+    for (o, i) in self.data.iter_mut().zip(other.data.iter()) {
+      *o += *i;
+    }
+    self
+  }
+}
+
+/// Histograms and scopes for an image at a given pipeline stage
+///
+/// Channel values are display referred (sRGB gamma applied) and go from 0.0 to
+/// 1.0 along the histogram bins. Waveforms have the left of the image on the
+/// left and the brightest values at the top. The vectorscope has a (green to
+/// red) going left to right and b (blue to yellow) going bottom to top.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scopes {
+  pub red: Histogram,
+  pub green: Histogram,
+  pub blue: Histogram,
+  pub luminance: Histogram,
+  pub waveform: ScopeImage,
+  pub parade: [ScopeImage;3],
+  pub vectorscope: ScopeImage,
+}
+
+impl Scopes {
+  fn new(settings: &ScopeSettings, waveform_width: usize) -> Scopes {
+    let wwidth = waveform_width;
+    let wheight = settings.waveform_height;
+    let vsize = settings.vectorscope_size;
+    Scopes {
+      red: Histogram::new(settings.bins),
+      green: Histogram::new(settings.bins),
+      blue: Histogram::new(settings.bins),
+      luminance: Histogram::new(settings.bins),
+      waveform: ScopeImage::new(wwidth, wheight),
+      parade: [
+        ScopeImage::new(wwidth, wheight),
+        ScopeImage::new(wwidth, wheight),
+        ScopeImage::new(wwidth, wheight),
+      ],
+      vectorscope: ScopeImage::new(vsize, vsize),
+    }
+  }
+
+  fn merge(self, other: Scopes) -> Scopes {
+    let [pr, pg, pb] = self.parade;
+    Scopes {
+      red: merge_histogram(self.red, &other.red),
+      green: merge_histogram(self.green, &other.green),
+      blue: merge_histogram(self.blue, &other.blue),
+      luminance: merge_histogram(self.luminance, &other.luminance),
+      waveform: self.waveform.merge(&other.waveform),
+      parade: [
+        pr.merge(&other.parade[0]),
+        pg.merge(&other.parade[1]),
+        pb.merge(&other.parade[2]),
+      ],
+      vectorscope: self.vectorscope.merge(&other.vectorscope),
+    }
+  }
+
+  /// Size in bytes used by the scopes, for cache accounting
+  pub fn size(&self) -> usize {
+    let histograms = [&self.red, &self.green, &self.blue, &self.luminance];
+    let bins: usize = histograms.iter().map(|h| h.bins.len()).sum();
+    let images = [&self.waveform, &self.parade[0], &self.parade[1], &self.parade[2], &self.vectorscope];
+    let pixels: usize = images.iter().map(|i| i.data.len()).sum();
+    (bins + pixels) * std::mem::size_of::<u32>()
+  }
+
+  /// Calculate the scopes for a buffer taken at the given stage
+  ///
+  /// Sizes of zero in the settings are taken as one, and an empty buffer gives
+  /// empty scopes.
+  pub fn calculate(buf: &OpBuffer, settings: &ScopeSettings) -> Scopes {
+    let settings = &ScopeSettings {
+      bins: cmp::max(settings.bins, 1),
+      waveform_height: cmp::max(settings.waveform_height, 1),
+      vectorscope_size: cmp::max(settings.vectorscope_size, 1),
+      ..*settings
+    };
+    let is_lab = settings.stage != ScopeStage::Output;
+    let wwidth = if settings.waveform_width == 0 {
+      buf.width
+    } else {
+      settings.waveform_width
+    };
+    if buf.width == 0 || buf.height == 0 {
+      return Scopes::new(settings, wwidth)
+    }
+    let bins = settings.bins;
+    let wheight = settings.waveform_height;
+    let vsize = settings.vectorscope_size;
+    let rgbmatrix = *XYZ_D65_33;
+    let labmatrix = *SRGB_D65_43;
+
+    let mut scopes = buf.data.par_chunks(buf.width*buf.colors).fold(
+      || Scopes::new(settings, wwidth),
+      |mut scopes, line| {
+        for (col, pix) in line.chunks_exact(buf.colors).enumerate() {
+          let (lab, rgb) = if is_lab {
+            let (r, g, b) = lab_to_rgb(rgbmatrix, pix);
+            let rgb = [
+              apply_srgb_gamma(r.clamp(0.0, 1.0)),
+              apply_srgb_gamma(g.clamp(0.0, 1.0)),
+              apply_srgb_gamma(b.clamp(0.0, 1.0)),
+            ];
+            ([pix[0], pix[1], pix[2]], rgb)
+          } else {
+            let linear = [
+              expand_srgb_gamma(pix[0]),
+              expand_srgb_gamma(pix[1]),
+              expand_srgb_gamma(pix[2]),
+              0.0,
+            ];
+            let (l, a, b) = camera_to_lab([1.0, 1.0, 1.0, 0.0], labmatrix, &linear);
+            ([l, a, b], [pix[0], pix[1], pix[2]])
+          };
+
+          scopes.red.bins[bin_for(rgb[0], bins)] += 1;
+          scopes.green.bins[bin_for(rgb[1], bins)] += 1;
+          scopes.blue.bins[bin_for(rgb[2], bins)] += 1;
+          scopes.luminance.bins[bin_for(lab[0], bins)] += 1;
+
+          let x = cmp::min(col * wwidth / buf.width, wwidth-1);
+          scopes.waveform.add(x, wheight - 1 - bin_for(lab[0], wheight));
+          for (c, parade) in scopes.parade.iter_mut().enumerate() {
+            parade.add(x, wheight - 1 - bin_for(rgb[c], wheight));
+          }
+
+          scopes.vectorscope.add(bin_for(lab[1], vsize), vsize - 1 - bin_for(lab[2], vsize));
+        }
+        scopes
+      }).reduce(|| Scopes::new(settings, wwidth), |a, b| a.merge(b));
+
+    let count = (buf.width*buf.height) as u64;
+    scopes.red.count = count;
+    scopes.green.count = count;
+    scopes.blue.count = count;
+    scopes.luminance.count = count;
+    scopes
+  }
+}
+
+fn merge_histogram(hist: Histogram, other: &Histogram) -> Histogram {
+  Histogram {
+    bins: add_bins(hist.bins, other.bins.clone()),
+    count: hist.count + other.count,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn settings(stage: ScopeStage) -> ScopeSettings {
+    ScopeSettings {
+      stage,
+      bins: 16,
+      waveform_height: 16,
+      vectorscope_size: 16,
+      ..ScopeSettings::default()
+    }
+  }
+
+  #[test]
+  fn size_counts_everything() {
+    let buf = OpBuffer::new(8, 4, 3, false);
+    let scopes = Scopes::calculate(&buf, &settings(ScopeStage::Output));
+    // 4 histograms, waveform, 3 parade channels and the vectorscope
+    assert_eq!(scopes.size(), (4*16 + 4*8*16 + 16*16) * 4);
+  }
+
+  #[test]
+  fn zero_sizes_and_empty_buffers() {
+    let zero = ScopeSettings {bins: 0, waveform_height: 0, vectorscope_size: 0, ..settings(ScopeStage::Output)};
+    let scopes = Scopes::calculate(&OpBuffer::new(8, 4, 3, false), &zero);
+    assert_eq!(scopes.red.bins, vec![32]);
+    assert_eq!((scopes.waveform.width, scopes.waveform.height), (8, 1));
+    assert_eq!(scopes.vectorscope.data, vec![32]);
+
+    let scopes = Scopes::calculate(&OpBuffer::new(0, 0, 3, false), &settings(ScopeStage::Output));
+    assert_eq!(scopes.luminance.count, 0);
+    assert!(scopes.red.bins.iter().all(|v| *v == 0));
+    assert!(scopes.waveform.data.is_empty());
+  }
+
+  #[test]
+  fn output_gray_ramp() {
+    let mut buf = OpBuffer::new(16, 4, 3, false);
+    buf.mutate_lines(&(|line: &mut [f32], _| {
+      for (col, pix) in line.chunks_exact_mut(3).enumerate() {
+        let v = (col as f32 + 0.5) / 16.0;
+        pix[0] = v;
+        pix[1] = v;
+        pix[2] = v;
+      }
+    }));
+    let scopes = Scopes::calculate(&buf, &settings(ScopeStage::Output));
+    assert!(scopes.red.bins.iter().all(|v| *v == 4));
+    assert_eq!(scopes.red, scopes.green);
+    assert_eq!(scopes.red, scopes.blue);
+    assert_eq!(scopes.luminance.count, 64);
+    // A gray ramp has a rising diagonal waveform
+    for col in 0..16 {
+      assert_eq!(scopes.parade[0].data[(15-col)*16+col], 4);
+    }
+    // and is all in the center of the vectorscope
+    let center: u32 = scopes.vectorscope.data.iter().enumerate().filter(|(i, _)| {
+      let (x, y) = (i % 16, i / 16);
+      (7..=8).contains(&x) && (7..=8).contains(&y)
+    }).map(|(_, v)| *v).sum();
+    assert_eq!(center, 64);
+  }
+
+  #[test]
+  fn lab_saturated_red() {
+    let mut buf = OpBuffer::new(8, 8, 3, false);
+    let (l, a, b) = camera_to_lab([1.0, 1.0, 1.0, 0.0], *SRGB_D65_43, &[1.0, 0.0, 0.0, 0.0]);
+    for pix in buf.data.chunks_exact_mut(3) {
+      pix[0] = l;
+      pix[1] = a;
+      pix[2] = b;
+    }
+    let scopes = Scopes::calculate(&buf, &settings(ScopeStage::Lab));
+    assert_eq!(scopes.red.bins[15], 64);
+    assert_eq!(scopes.green.bins[0], 64);
+    assert_eq!(scopes.blue.bins[0], 64);
+    // Red is towards the right and top of the vectorscope
+    let pos = scopes.vectorscope.data.iter().position(|v| *v == 64).unwrap();
+    assert!(pos % 16 > 8);
+    assert!(pos / 16 < 8);
+  }
+}
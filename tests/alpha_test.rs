@@ -0,0 +1,88 @@
+use imagepipe::{Pipeline, ImageSource, Rotation, FitMode};
+use image::{ImageBuffer, RgbImage, RgbaImage, Rgba, DynamicImage};
+
+// Opaque red on the left half and transparent blue on the right half
+fn create_pipeline() -> Pipeline {
+  let img = RgbaImage::from_fn(64, 32, |x, _| {
+    if x < 32 {Rgba([250, 20, 20, 255])} else {Rgba([20, 20, 250, 0])}
+  });
+  Pipeline::new_from_source(ImageSource::Other(DynamicImage::ImageRgba8(img))).unwrap()
+}
+
+fn pixel(data: &[u8], width: usize, x: usize, y: usize) -> &[u8] {
+  &data[(y*width+x)*4..(y*width+x)*4+4]
+}
+
+#[test]
+fn keeps_alpha() {
+  let mut pipeline = create_pipeline();
+  let out = pipeline.output_8bit_rgba(None).unwrap();
+  assert_eq!((out.width, out.height), (64, 32));
+  assert_eq!(pixel(&out.data, 64, 16, 16)[3], 255);
+  assert_eq!(pixel(&out.data, 64, 48, 16)[3], 0);
+  // Colors of transparent pixels are kept as they are
+  assert!(pixel(&out.data, 64, 16, 16)[0] > 200);
+  assert!(pixel(&out.data, 64, 48, 16)[2] > 200);
+  // The RGB output is the same image without alpha
+  let rgb = pipeline.output_8bit(None).unwrap();
+  assert_eq!((rgb.width, rgb.height), (64, 32));
+  assert_eq!(&rgb.data[(16*64+48)*3..(16*64+48)*3+3], &pixel(&out.data, 64, 48, 16)[0..3]);
+}
+
+#[test]
+fn alpha_follows_geometry() {
+  let mut pipeline = create_pipeline();
+  pipeline.ops.transform.rotation = Rotation::Rotate90;
+  let out = pipeline.output_8bit_rgba(None).unwrap();
+  assert_eq!((out.width, out.height), (32, 64));
+  // Rotated clockwise so the left half is now at the top
+  assert_eq!(pixel(&out.data, 32, 16, 16)[3], 255);
+  assert_eq!(pixel(&out.data, 32, 16, 48)[3], 0);
+
+  let mut pipeline = create_pipeline();
+  pipeline.ops.rotatecrop.crop_left = 0.25;
+  pipeline.globals.settings.maxwidth = 24;
+  let out = pipeline.output_8bit_rgba(None).unwrap();
+  assert_eq!((out.width, out.height), (24, 16));
+  // The opaque part is now the first third of the image
+  assert_eq!(pixel(&out.data, 24, 3, 8)[3], 255);
+  assert_eq!(pixel(&out.data, 24, 20, 8)[3], 0);
+  for x in 0..24 {
+    let p = pixel(&out.data, 24, x, 8);
+    assert_eq!(p[0] > 128, p[3] > 128, "color and alpha don't match at {}: {:?}", x, p);
+  }
+}
+
+#[test]
+fn padding_is_opaque() {
+  let mut pipeline = create_pipeline();
+  pipeline.globals.settings.maxwidth = 64;
+  pipeline.globals.settings.maxheight = 64;
+  pipeline.globals.settings.fit = FitMode::Pad{color: [0.0, 0.0, 0.0]};
+  let out = pipeline.output_8bit_rgba(None).unwrap();
+  assert_eq!((out.width, out.height), (64, 64));
+  assert_eq!(pixel(&out.data, 64, 48, 4), &[0, 0, 0, 255]);
+  assert_eq!(pixel(&out.data, 64, 16, 32)[3], 255);
+  assert_eq!(pixel(&out.data, 64, 48, 32)[3], 0);
+}
+
+#[test]
+fn alpha_16bit() {
+  let img = ImageBuffer::from_pixel(16, 16, Rgba([65535u16, 32768, 0, 12345]));
+  let mut pipeline = Pipeline::new_from_source(ImageSource::Other(DynamicImage::ImageRgba16(img))).unwrap();
+  let out = pipeline.output_16bit_rgba(None).unwrap();
+  assert_eq!((out.width, out.height), (16, 16));
+  assert_eq!(out.data.len(), 16*16*4);
+  assert!(out.data.chunks_exact(4).all(|p| p[3] == 12345));
+}
+
+#[test]
+fn opaque_without_alpha() {
+  let img = RgbImage::from_pixel(32, 16, image::Rgb([10, 20, 30]));
+  let mut pipeline = Pipeline::new_from_source(ImageSource::Other(DynamicImage::ImageRgb8(img))).unwrap();
+  let out = pipeline.output_8bit_rgba(None).unwrap();
+  assert_eq!((out.width, out.height), (32, 16));
+  assert!(out.data.chunks_exact(4).all(|p| p == [10, 20, 30, 255]));
+  let out = pipeline.output_16bit_rgba(None).unwrap();
+  assert!(out.data.chunks_exact(4).all(|p| p[3] == 65535));
+}
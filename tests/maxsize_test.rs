@@ -1,4 +1,5 @@
-use imagepipe::{Pipeline, ImageSource, Rotation};
+use imagepipe::{Pipeline, ImageSource, Rotation, Upscaling, FitMode};
+use imagepipe::retouch::{RetouchSpot, RetouchMode};
 use image::{RgbImage, DynamicImage};
 
 fn create_pipeline() -> Pipeline {
@@ -42,6 +43,70 @@ fn no_upscaling() {
   assert_width(&mut pipeline, 128, 64);
 }
 
+#[test]
+fn upscaling() {
+  for upscaling in [Upscaling::Bicubic, Upscaling::Lanczos3, Upscaling::EdgeDirected] {
+    let mut pipeline = create_pipeline();
+    pipeline.globals.settings.maxwidth = 256;
+    pipeline.globals.settings.upscaling = upscaling;
+    assert_width(&mut pipeline, 256, 128);
+  }
+}
+
+#[test]
+fn fit_modes() {
+  for fit in [FitMode::Cover{gravity: (0.5, 0.5)}, FitMode::Stretch, FitMode::Pad{color: [0.0; 3]}] {
+    let mut pipeline = create_pipeline();
+    pipeline.globals.settings.maxwidth = 48;
+    pipeline.globals.settings.maxheight = 48;
+    pipeline.globals.settings.fit = fit;
+    assert_width(&mut pipeline, 48, 48);
+  }
+
+  // Without upscaling only padding goes above the image size
+  let mut pipeline = create_pipeline();
+  pipeline.globals.settings.maxwidth = 256;
+  pipeline.globals.settings.maxheight = 256;
+  pipeline.globals.settings.fit = FitMode::Cover{gravity: (0.5, 0.5)};
+  assert_width(&mut pipeline, 64, 64);
+  pipeline.globals.settings.fit = FitMode::Pad{color: [0.0; 3]};
+  assert_width(&mut pipeline, 256, 256);
+  pipeline.globals.settings.upscaling = Upscaling::Bicubic;
+  pipeline.globals.settings.fit = FitMode::Cover{gravity: (0.5, 0.5)};
+  assert_width(&mut pipeline, 256, 256);
+}
+
+#[test]
+fn max_crop() {
+  let mut pipeline = create_pipeline();
+  pipeline.ops.rotatecrop.rotation = 0.1;
+  pipeline.ops.rotatecrop.aspect_ratio = Some(2.0);
+  pipeline.set_max_crop();
+  let decoded = pipeline.output_8bit(None).unwrap();
+  assert!((decoded.width as f32 / decoded.height as f32 - 2.0).abs() < 0.1);
+  assert!(decoded.width < 128 && decoded.height < 64);
+}
+
+#[test]
+fn straighten() {
+  // A horizon sloping up to the right by 4 degrees
+  let slope = 4.0f32.to_radians().tan();
+  let img = RgbImage::from_fn(300, 200, |x, y| {
+    let horizon = 100.0 - (x as f32 - 150.0) * slope;
+    if (y as f32) < horizon { image::Rgb([200, 220, 250]) } else { image::Rgb([40, 60, 30]) }
+  });
+  let source = ImageSource::Other(DynamicImage::ImageRgb8(img));
+  let mut pipeline = Pipeline::new_from_source(source).unwrap();
+  // The current rotation doesn't change the suggestion
+  pipeline.ops.rotatecrop.rotation = 0.5;
+  assert!(pipeline.straighten(None, 10.0));
+  let degrees = pipeline.ops.rotatecrop.rotation * 90.0;
+  assert!((degrees - 4.0).abs() < 0.3, "rotated by {} degrees", degrees);
+
+  let mut pipeline = create_pipeline();
+  assert_eq!(pipeline.suggest_rotation(None, 10.0), None);
+}
+
 #[test]
 fn downscale_keeps_ratio() {
   let mut pipeline = create_pipeline();
@@ -88,3 +153,113 @@ fn rotatecrop() {
   pipeline.ops.rotatecrop.crop_right = 0.1;
   assert_width(&mut pipeline, 64, 32);
 }
+
+#[test]
+fn run_until_unknown_op() {
+  let mut pipeline = create_pipeline();
+  let buf = pipeline.run_until(None, "rotatecrop").unwrap();
+  assert_eq!((buf.width, buf.height), (128, 64));
+  assert!(pipeline.run_until(None, "no_such_op").is_err());
+}
+
+#[test]
+fn clipping_mask_size() {
+  let mut pipeline = create_pipeline();
+  pipeline.globals.settings.maxwidth = 64;
+  pipeline.ops.transform.rotation = Rotation::Rotate90;
+  let mask = pipeline.output_clipping_mask(None).unwrap();
+  assert!(!pipeline.globals.settings.linear);
+  let decoded = pipeline.output_8bit(None).unwrap();
+  assert_eq!((mask.width, mask.height), (decoded.width, decoded.height));
+  assert_eq!(mask.data.len(), mask.width*mask.height);
+}
+
+#[test]
+fn output_to_image_coordinates() {
+  let mut pipeline = create_pipeline();
+  assert_eq!(pipeline.output_to_image(0.25, 0.5), (0.25, 0.5));
+
+  pipeline.ops.rotatecrop.crop_left = 0.5;
+  let (x, y) = pipeline.output_to_image(0.0, 0.0);
+  assert!((x - 0.5).abs() < 0.01 && y.abs() < 0.01, "got {}x{}", x, y);
+
+  pipeline.ops.rotatecrop.crop_left = 0.0;
+  pipeline.ops.transform.rotation = Rotation::Rotate90;
+  let (x, y) = pipeline.output_to_image(0.0, 0.0);
+  assert!(x.abs() < 0.01 && (y - 1.0).abs() < 0.01, "got {}x{}", x, y);
+}
+
+#[test]
+fn output_to_image_with_fit() {
+  let close = |(x, y): (f32, f32), (ex, ey): (f32, f32)| (x - ex).abs() < 0.01 && (y - ey).abs() < 0.01;
+  let mut pipeline = create_pipeline();
+  pipeline.globals.settings.maxwidth = 64;
+  pipeline.globals.settings.maxheight = 64;
+
+  // Cover takes the middle 64x64 of the 128x64 image
+  pipeline.globals.settings.fit = FitMode::Cover{gravity: (0.5, 0.5)};
+  let point = pipeline.output_to_image(0.0, 0.0);
+  assert!(close(point, (0.25, 0.0)), "got {:?}", point);
+  let point = pipeline.output_to_image(1.0, 1.0);
+  assert!(close(point, (0.75, 1.0)), "got {:?}", point);
+  let spot = RetouchSpot {
+    mode: RetouchMode::Heal,
+    source: (0.0, 0.5),
+    destination: (1.0, 0.5),
+    radius: 0.1,
+    feather: 0.2,
+  };
+  let covered = pipeline.spot_from_output(&spot);
+  assert!(close(covered.source, (0.25, 0.5)) && close(covered.destination, (0.75, 0.5)));
+  assert!((covered.radius - 0.05).abs() < 0.001);
+
+  // Pad scales the image to 64x32 with 16 pixels above and below
+  pipeline.globals.settings.fit = FitMode::Pad{color: [0.0; 3]};
+  let point = pipeline.output_to_image(0.0, 0.25);
+  assert!(close(point, (0.0, 0.0)), "got {:?}", point);
+  let point = pipeline.output_to_image(0.5, 0.75);
+  assert!(close(point, (0.5, 1.0)), "got {:?}", point);
+  let padded = pipeline.spot_from_output(&spot);
+  assert!((padded.radius - 0.1).abs() < 0.001);
+}
+
+#[test]
+fn spot_from_output() {
+  let mut pipeline = create_pipeline();
+  pipeline.ops.rotatecrop.crop_right = 0.5;
+  let spot = RetouchSpot {
+    mode: RetouchMode::Heal,
+    source: (0.5, 0.5),
+    destination: (1.0, 0.0),
+    radius: 0.1,
+    feather: 0.2,
+  };
+  // The output is 64x64 of a 128x64 image
+  let spot = pipeline.spot_from_output(&spot);
+  assert!((spot.source.0 - 0.25).abs() < 0.01 && (spot.source.1 - 0.5).abs() < 0.01);
+  assert!((spot.destination.0 - 0.5).abs() < 0.01 && spot.destination.1.abs() < 0.01);
+  assert!((spot.radius - 0.05).abs() < 0.001);
+  assert!(pipeline.to_serial().contains("spots"));
+}
+
+#[test]
+fn rotatecrop_v0_settings() {
+  let mut pipeline = create_pipeline();
+  pipeline.ops.rotatecrop.rotation = 0.2;
+  pipeline.ops.rotatecrop.crop_left = 0.1;
+  let serial = pipeline.to_serial();
+  let source = pipeline.globals.image.clone();
+
+  // Current settings load as they were saved
+  let loaded = Pipeline::new_from_serial(source.clone(), serial.clone());
+  assert_eq!(loaded.ops.rotatecrop.crop_left, 0.1);
+
+  // Settings from before the offsets were taken from the rotated image still
+  // crop the same region, with a crop of the same size
+  let serial = serial.replacen("version: 1", "version: 0", 1);
+  let loaded = Pipeline::new_from_serial(source, serial);
+  let crops = &loaded.ops.rotatecrop;
+  assert!(crops.crop_left > 0.1, "crop left is {}", crops.crop_left);
+  assert!((crops.crop_left + crops.crop_right - 0.1).abs() < 0.0001);
+  assert_eq!((crops.crop_top, crops.crop_bottom), (0.0, 0.0));
+}
@@ -0,0 +1,123 @@
+use imagepipe::Pipeline;
+use image::{RgbImage, Rgb};
+use image::codecs::jpeg::JpegEncoder;
+use exif::{Field, In, Tag, Value};
+use rawloader::Orientation;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+static XMP: &str = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF/></x:xmpmeta>";
+
+fn temp_path(name: &str) -> PathBuf {
+  std::env::temp_dir().join(format!("imagepipe-metadata-{}-{}", std::process::id(), name))
+}
+
+// A JPEG as a camera would write it, rotated and with EXIF, GPS and XMP
+fn camera_jpeg() -> PathBuf {
+  let field = |tag, value| Field {tag, ifd_num: In::PRIMARY, value};
+  let fields = vec![
+    field(Tag::Make, Value::Ascii(vec![b"Imagepipe".to_vec()])),
+    field(Tag::Model, Value::Ascii(vec![b"Test Camera".to_vec()])),
+    field(Tag::Orientation, Value::Short(vec![6])),
+    field(Tag::ImageWidth, Value::Long(vec![64])),
+    field(Tag::ExposureTime, Value::Rational(vec![(1, 125).into()])),
+    field(Tag::FNumber, Value::Rational(vec![(56, 10).into()])),
+    field(Tag::PhotographicSensitivity, Value::Short(vec![200])),
+    field(Tag::DateTimeOriginal, Value::Ascii(vec![b"2021:06:01 12:00:00".to_vec()])),
+    field(Tag::GPSLatitude, Value::Rational(vec![(40, 1).into(), (30, 1).into(), (0, 1).into()])),
+    field(Tag::GPSLatitudeRef, Value::Ascii(vec![b"S".to_vec()])),
+    field(Tag::GPSLongitude, Value::Rational(vec![(8, 1).into(), (0, 1).into(), (0, 1).into()])),
+    field(Tag::GPSLongitudeRef, Value::Ascii(vec![b"E".to_vec()])),
+  ];
+  let mut writer = exif::experimental::Writer::new();
+  for f in fields.iter() {
+    writer.push_field(f);
+  }
+  let mut exif = Cursor::new(Vec::new());
+  writer.write(&mut exif, true).unwrap();
+  let exif = exif.into_inner();
+
+  let img = RgbImage::from_fn(64, 32, |x, _| if x < 32 {Rgb([200, 50, 50])} else {Rgb([50, 50, 200])});
+  let mut jpeg = Vec::new();
+  JpegEncoder::new_with_quality(&mut jpeg, 95).encode_image(&img).unwrap();
+
+  let mut file = jpeg[..2].to_vec();
+  for (header, data) in [(&b"Exif\0\0"[..], &exif[..]), (&b"http://ns.adobe.com/xap/1.0/\0"[..], XMP.as_bytes())] {
+    let len = 2 + header.len() + data.len();
+    file.extend_from_slice(&[0xff, 0xe1, (len >> 8) as u8, len as u8]);
+    file.extend_from_slice(header);
+    file.extend_from_slice(data);
+  }
+  file.extend_from_slice(&jpeg[2..]);
+  let path = temp_path("camera.jpg");
+  std::fs::write(&path, file).unwrap();
+  path
+}
+
+#[test]
+fn read_metadata() {
+  let path = camera_jpeg();
+  let pipeline = Pipeline::new_from_file(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+  let metadata = &pipeline.globals.metadata;
+  assert_eq!(metadata.make.as_deref(), Some("Imagepipe"));
+  assert_eq!(metadata.model.as_deref(), Some("Test Camera"));
+  assert_eq!(metadata.exposure_time, Some(0.008));
+  assert_eq!(metadata.aperture, Some(5.6));
+  assert_eq!(metadata.iso, Some(200));
+  assert_eq!(metadata.datetime.as_deref(), Some("2021:06:01 12:00:00"));
+  assert_eq!(metadata.gps, Some((-40.5, 8.0)));
+  assert_eq!(metadata.orientation, Orientation::Rotate90);
+  assert_eq!(metadata.xmp.as_deref(), Some(XMP.as_bytes()));
+  assert_eq!(pipeline.output_metadata().orientation, Orientation::Normal);
+}
+
+#[test]
+fn write_metadata() {
+  let path = camera_jpeg();
+  let mut pipeline = Pipeline::new_from_file(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+  let metadata = pipeline.output_metadata();
+  let image8 = pipeline.output_8bit(None).unwrap();
+  let image16 = pipeline.output_16bit(None).unwrap();
+  assert_eq!((image8.width, image8.height), (32, 64));
+
+  let mut outputs = Vec::new();
+  let mut jpeg = Vec::new();
+  image8.write_jpeg(&metadata, 90, &mut jpeg).unwrap();
+  outputs.push(("out.jpg", jpeg));
+  let mut png = Vec::new();
+  image8.write_png(&metadata, &mut png).unwrap();
+  outputs.push(("out.png", png));
+  let mut png16 = Vec::new();
+  image16.write_png(&metadata, &mut png16).unwrap();
+  outputs.push(("out16.png", png16));
+  let mut tiff = Cursor::new(Vec::new());
+  image8.write_tiff(&metadata, &mut tiff).unwrap();
+  outputs.push(("out.tiff", tiff.into_inner()));
+  let mut tiff16 = Cursor::new(Vec::new());
+  image16.write_tiff(&metadata, &mut tiff16).unwrap();
+  outputs.push(("out16.tiff", tiff16.into_inner()));
+
+  for (name, data) in outputs {
+    let path = temp_path(name);
+    std::fs::write(&path, &data).unwrap();
+    let mut pipeline = Pipeline::new_from_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let read = &pipeline.globals.metadata;
+    assert_eq!(read.make, metadata.make, "{}", name);
+    assert_eq!(read.model, metadata.model, "{}", name);
+    assert_eq!(read.exposure_time, metadata.exposure_time, "{}", name);
+    assert_eq!(read.gps, metadata.gps, "{}", name);
+    assert_eq!(read.xmp, metadata.xmp, "{}", name);
+    // The image is already rotated so it's not rotated again
+    assert_eq!(read.orientation, Orientation::Normal, "{}", name);
+    let decoded = pipeline.output_8bit(None).unwrap();
+    assert_eq!((decoded.width, decoded.height), (32, 64), "{}", name);
+    // Red at the top and blue at the bottom after the rotation
+    let top = &decoded.data[(8*32+16)*3..(8*32+16)*3+3];
+    let bottom = &decoded.data[(56*32+16)*3..(56*32+16)*3+3];
+    assert!(top[0] > 150 && top[2] < 100, "{} has top {:?}", name, top);
+    assert!(bottom[2] > 150 && bottom[0] < 100, "{} has bottom {:?}", name, bottom);
+  }
+}
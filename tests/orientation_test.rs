@@ -0,0 +1,90 @@
+use imagepipe::Pipeline;
+use image::{RgbImage, Rgb};
+use image::codecs::jpeg::JpegEncoder;
+use std::path::PathBuf;
+
+// Four flat quadrants aligned with the JPEG blocks so they survive compression
+fn quadrants() -> RgbImage {
+  RgbImage::from_fn(64, 32, |x, y| {
+    match (x < 32, y < 16) {
+      (true, true)   => Rgb([250, 20, 20]),
+      (false, true)  => Rgb([20, 250, 20]),
+      (true, false)  => Rgb([20, 20, 250]),
+      (false, false) => Rgb([250, 250, 250]),
+    }
+  })
+}
+
+// What a viewer displays for each EXIF orientation value
+fn displayed(img: &RgbImage, orientation: u16) -> RgbImage {
+  let (w, h) = (img.width(), img.height());
+  let (ow, oh) = if orientation >= 5 {(h, w)} else {(w, h)};
+  RgbImage::from_fn(ow, oh, |x, y| {
+    let (ix, iy) = match orientation {
+      1 => (x, y),
+      2 => (w-1-x, y),
+      3 => (w-1-x, h-1-y),
+      4 => (x, h-1-y),
+      5 => (y, x),
+      6 => (y, h-1-x),
+      7 => (w-1-y, h-1-x),
+      8 => (w-1-y, x),
+      _ => unreachable!(),
+    };
+    *img.get_pixel(ix, iy)
+  })
+}
+
+// Write a JPEG with an EXIF block that only has the orientation tag
+fn write_jpeg(img: &RgbImage, orientation: u16) -> PathBuf {
+  let mut jpeg = Vec::new();
+  JpegEncoder::new_with_quality(&mut jpeg, 100).encode_image(img).unwrap();
+
+  let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08".to_vec();
+  tiff.extend_from_slice(&[0x00, 0x01]); // One entry
+  tiff.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01]); // Orientation, 1 SHORT
+  tiff.extend_from_slice(&orientation.to_be_bytes());
+  tiff.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00]); // Padding and no next IFD
+  let mut app1 = vec![0xff, 0xe1];
+  app1.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+  app1.extend_from_slice(b"Exif\x00\x00");
+  app1.extend_from_slice(&tiff);
+
+  let mut file = jpeg[..2].to_vec();
+  file.extend_from_slice(&app1);
+  file.extend_from_slice(&jpeg[2..]);
+  let path = std::env::temp_dir().join(format!("imagepipe-orientation-{}-{}.jpg", std::process::id(), orientation));
+  std::fs::write(&path, file).unwrap();
+  path
+}
+
+fn assert_quadrants(data: &[u8], width: usize, height: usize, expected: &RgbImage, what: &str) {
+  assert_eq!((width, height), (expected.width() as usize, expected.height() as usize), "{}", what);
+  for (x, y) in [(0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)] {
+    let (x, y) = ((x * width as f32) as usize, (y * height as f32) as usize);
+    let pixel = &data[(y*width+x)*3..(y*width+x)*3+3];
+    let wanted = expected.get_pixel(x as u32, y as u32);
+    for c in 0..3 {
+      assert!((pixel[c] as i32 - wanted[c] as i32).abs() < 12,
+        "{} has {:?} at {}x{} instead of {:?}", what, pixel, x, y, wanted);
+    }
+  }
+}
+
+#[test]
+fn exif_orientations() {
+  let img = quadrants();
+  for orientation in 1..=8 {
+    let path = write_jpeg(&img, orientation);
+    let expected = displayed(&img, orientation);
+    for fastpath in [true, false] {
+      let mut pipeline = Pipeline::new_from_file(&path).unwrap();
+      pipeline.globals.settings.use_fastpath = fastpath;
+      assert!(pipeline.default_ops());
+      let out = pipeline.output_8bit(None).unwrap();
+      let what = format!("orientation {} with fastpath {}", orientation, fastpath);
+      assert_quadrants(&out.data, out.width, out.height, &expected, &what);
+    }
+    std::fs::remove_file(&path).unwrap();
+  }
+}
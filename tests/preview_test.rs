@@ -0,0 +1,84 @@
+use imagepipe::{thumbnail_8bit, PreviewMode};
+use image::{RgbImage, Rgb};
+use image::codecs::jpeg::JpegEncoder;
+use std::path::PathBuf;
+
+fn jpeg(width: u32, height: u32) -> Vec<u8> {
+  let img = RgbImage::from_pixel(width, height, Rgb([200, 100, 50]));
+  let mut out = Vec::new();
+  JpegEncoder::new(&mut out).encode_image(&img).unwrap();
+  out
+}
+
+fn write_file(name: &str, data: &[u8]) -> PathBuf {
+  let path = std::env::temp_dir().join(format!("imagepipe-preview-{}-{}", std::process::id(), name));
+  std::fs::write(&path, data).unwrap();
+  path
+}
+
+fn entry(out: &mut Vec<u8>, tag: u16, value: u32) {
+  out.extend_from_slice(&tag.to_be_bytes());
+  out.extend_from_slice(&4u16.to_be_bytes());
+  out.extend_from_slice(&1u32.to_be_bytes());
+  out.extend_from_slice(&value.to_be_bytes());
+}
+
+// A big endian TIFF structure like raws have, with the orientation and a
+// thumbnail in IFD0 and a larger preview in the IFD chained after it
+fn fake_raw(orientation: u32, thumbnail: &[u8], preview: &[u8]) -> Vec<u8> {
+  let (ifd1, data) = (50u32, 80u32);
+  let mut out = b"MM\0\x2a\0\0\0\x08".to_vec();
+  out.extend_from_slice(&3u16.to_be_bytes());
+  entry(&mut out, 274, orientation);
+  entry(&mut out, 513, data);
+  entry(&mut out, 514, thumbnail.len() as u32);
+  out.extend_from_slice(&ifd1.to_be_bytes());
+  assert_eq!(out.len(), ifd1 as usize);
+  out.extend_from_slice(&2u16.to_be_bytes());
+  entry(&mut out, 513, data + thumbnail.len() as u32);
+  entry(&mut out, 514, preview.len() as u32);
+  out.extend_from_slice(&0u32.to_be_bytes());
+  assert_eq!(out.len(), data as usize);
+  out.extend_from_slice(thumbnail);
+  out.extend_from_slice(preview);
+  // Stand-in for the raw data
+  out.extend_from_slice(&[0u8; 100]);
+  out
+}
+
+#[test]
+fn embedded_preview() {
+  // Not something either decoder can read, so only the preview can be used
+  let path = write_file("fake.raw", &fake_raw(1, &jpeg(160, 120), &jpeg(1024, 768)));
+
+  let thumb = thumbnail_8bit(&path, 256, 256, PreviewMode::Auto).unwrap();
+  assert_eq!((thumb.width, thumb.height), (256, 192));
+  assert!(thumb.data[0] > 150 && thumb.data[2] < 100);
+  assert!(thumbnail_8bit(&path, 256, 256, PreviewMode::PreviewOnly).is_ok());
+  assert!(thumbnail_8bit(&path, 256, 256, PreviewMode::PipelineOnly).is_err());
+  // Too large for any of the previews and the file can't be decoded
+  assert!(thumbnail_8bit(&path, 2048, 2048, PreviewMode::Auto).is_err());
+  std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn rotated_preview() {
+  let path = write_file("rotated.raw", &fake_raw(8, &jpeg(160, 120), &jpeg(1024, 768)));
+  // The thumbnail is only 120 wide once rotated
+  let thumb = thumbnail_8bit(&path, 150, 0, PreviewMode::PreviewOnly).unwrap();
+  assert_eq!((thumb.width, thumb.height), (150, 200));
+  let thumb = thumbnail_8bit(&path, 100, 100, PreviewMode::PreviewOnly).unwrap();
+  assert_eq!((thumb.width, thumb.height), (75, 100));
+  std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn pipeline_fallback() {
+  let path = write_file("small.jpg", &jpeg(100, 50));
+  let thumb = thumbnail_8bit(&path, 256, 256, PreviewMode::Auto).unwrap();
+  assert_eq!((thumb.width, thumb.height), (100, 50));
+  assert!(thumbnail_8bit(&path, 256, 256, PreviewMode::PreviewOnly).is_err());
+  let thumb = thumbnail_8bit(&path, 50, 50, PreviewMode::PipelineOnly).unwrap();
+  assert_eq!((thumb.width, thumb.height), (50, 25));
+  std::fs::remove_file(&path).unwrap();
+}
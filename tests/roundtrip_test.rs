@@ -1,4 +1,7 @@
-use imagepipe::{Pipeline, ImageSource};
+use imagepipe::{Pipeline, ImageSource, OutputEncoding, ParametricMask, MaskRange};
+use imagepipe::lut::{Lut, LutSpace, LutPosition};
+use imagepipe::gamma::TransferFunction;
+use imagepipe::color_conversions::{output8bit, apply_srgb_gamma};
 use image::{ImageBuffer, DynamicImage};
 
 fn roundtrip_8bit(fast: bool) {
@@ -82,3 +85,238 @@ fn roundtrip_16bit_fastpath() {
 fn roundtrip_16bit_slowpath() {
   roundtrip_16bit(false);
 }
+
+fn fast_and_slow(source: DynamicImage) {
+  let mut pipeline = Pipeline::new_from_source(ImageSource::Other(source)).unwrap();
+  pipeline.globals.settings.use_fastpath = true;
+  let fast8 = pipeline.output_8bit(None).unwrap();
+  let fast16 = pipeline.output_16bit(None).unwrap();
+  pipeline.globals.settings.use_fastpath = false;
+  let slow8 = pipeline.output_8bit(None).unwrap();
+  let slow16 = pipeline.output_16bit(None).unwrap();
+  assert_eq!(fast8, slow8);
+  assert_eq!(fast16, slow16);
+}
+
+#[test]
+fn fastpath_matches_slowpath() {
+  let data8: Vec<u8> = (0..16*16*3).map(|v| (v % 256) as u8).collect();
+  let image = ImageBuffer::from_raw(16, 16, data8).unwrap();
+  fast_and_slow(DynamicImage::ImageRgb8(image));
+
+  let data16: Vec<u16> = (0..16*16*3).map(|v| (v * 85) as u16).collect();
+  let image = ImageBuffer::from_raw(16, 16, data16).unwrap();
+  fast_and_slow(DynamicImage::ImageRgb16(image));
+}
+
+#[test]
+fn output_encodings() {
+  let image_data: Vec<u8> = (0..16*16*3).map(|v| (v % 256) as u8).collect();
+  let image = ImageBuffer::from_raw(16, 16, image_data).unwrap();
+  let source = ImageSource::Other(DynamicImage::ImageRgb8(image));
+  let mut pipeline = Pipeline::new_from_source(source).unwrap();
+
+  let gamma = pipeline.output_8bit(None).unwrap();
+  assert!(!gamma.linear);
+  let float = pipeline.output_float(None).unwrap();
+  assert!(float.linear);
+
+  pipeline.globals.settings.output_encoding = OutputEncoding::Gamma;
+  let float_gamma = pipeline.output_float(None).unwrap();
+  assert!(!float_gamma.linear);
+  for (vf, v8) in float_gamma.data.iter().zip(gamma.data.iter()) {
+    assert_eq!(output8bit(*vf), *v8);
+  }
+
+  pipeline.globals.settings.output_encoding = OutputEncoding::Linear;
+  let linear = pipeline.output_8bit(None).unwrap();
+  assert!(linear.linear);
+  for (vl, vf) in linear.data.iter().zip(float.data.iter()) {
+    assert_eq!(*vl, output8bit(*vf));
+  }
+  let linear16 = pipeline.output_16bit(None).unwrap();
+  assert!(linear16.linear);
+}
+
+#[test]
+fn float_source() {
+  // Linear values beyond what 16 bits can represent and above 1.0
+  let values = [0.0, 0.5, 0.1234567, 1.0, 3.5, 100.0];
+  let image_data: Vec<f32> = (0..16*16*3).map(|v| values[v % values.len()]).collect();
+  let image = ImageBuffer::from_raw(16, 16, image_data.clone()).unwrap();
+  let source = ImageSource::Other(DynamicImage::ImageRgb32F(image));
+  let mut pipeline = Pipeline::new_from_source(source).unwrap();
+
+  let float = pipeline.output_float(None).unwrap();
+  for (vout, vin) in float.data.iter().zip(image_data.iter()) {
+    assert!((vout - vin).abs() <= vin * 1e-5 + 1e-6, "{} instead of {}", vout, vin);
+  }
+
+  // The fast path would take the linear values as gamma encoded
+  for fast in [true, false] {
+    pipeline.globals.settings.use_fastpath = fast;
+    let out = pipeline.output_8bit(None).unwrap();
+    for (vout, vin) in out.data.iter().zip(image_data.iter()) {
+      assert_eq!(*vout, output8bit(apply_srgb_gamma(*vin)));
+    }
+  }
+}
+
+#[test]
+fn hald_clut_identity() {
+  let image = ImageBuffer::from_raw(16, 16, vec![128u8; 16*16*3]).unwrap();
+  let source = ImageSource::Other(DynamicImage::ImageRgb8(image));
+  let mut pipeline = Pipeline::new_from_source(source).unwrap();
+
+  // With the default ops the pipeline doesn't change colors
+  let hald = pipeline.hald_clut(None, 4).unwrap();
+  assert_eq!((hald.width(), hald.height()), (64, 64));
+  let lut = imagepipe::lut::Lut::from_hald_image(&hald).unwrap();
+  let identity = imagepipe::lut::Lut::identity(16);
+  for (a, b) in lut.table.iter().zip(identity.table.iter()) {
+    assert!((a - b).abs() < 0.01, "{} became {}", b, a);
+  }
+
+  assert!(pipeline.hald_clut(None, 1).is_err());
+}
+
+#[test]
+fn encoded_lut_after_gamma() {
+  let image_data: Vec<u8> = (0..16*16*3).map(|v| (v % 256) as u8).collect();
+  let image = ImageBuffer::from_raw(16, 16, image_data).unwrap();
+  let source = ImageSource::Other(DynamicImage::ImageRgb8(image));
+  let mut pipeline = Pipeline::new_from_source(source).unwrap();
+  pipeline.globals.settings.output_encoding = OutputEncoding::Gamma;
+  let before = pipeline.output_float(None).unwrap();
+
+  // Running on encoded values is the same as applying the table to the output
+  let lut = Lut::from_cube_str("LUT_1D_SIZE 2\n1.0 0.8 0.6\n0.0 0.2 0.4\n").unwrap();
+  pipeline.ops.lut.lut = Some(lut.clone());
+  pipeline.ops.lut.space = LutSpace::Encoded;
+  let after = pipeline.output_float(None).unwrap();
+  for (pin, pout) in before.data.chunks_exact(3).zip(after.data.chunks_exact(3)) {
+    let expected = lut.apply(pin, pipeline.ops.lut.interpolation);
+    for c in 0..3 {
+      assert!((pout[c] - expected[c]).abs() < 0.001, "{:?} instead of {:?}", pout, expected);
+    }
+  }
+}
+
+fn lut_output(pipeline: &mut Pipeline, lut: Option<&Lut>, position: LutPosition) -> Vec<f32> {
+  pipeline.ops.lut.lut = lut.cloned();
+  pipeline.ops.lut.space = LutSpace::Linear;
+  pipeline.ops.lut.position = position;
+  pipeline.output_float(None).unwrap().data
+}
+
+#[test]
+fn lut_positions() {
+  let image_data: Vec<u8> = (0..16*16*3).map(|v| (v % 256) as u8).collect();
+  let image = ImageBuffer::from_raw(16, 16, image_data).unwrap();
+  let source = ImageSource::Other(DynamicImage::ImageRgb8(image));
+  let mut pipeline = Pipeline::new_from_source(source).unwrap();
+  let lut = Lut::from_cube_str("LUT_1D_SIZE 3\n0.0 0.0 0.0\n0.1 0.2 0.3\n1.0 1.0 1.0\n").unwrap();
+  let close = |a: &[f32], b: &[f32]| a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 0.002);
+
+  // With the default ops nothing changes the values between the positions
+  let after_filmic = lut_output(&mut pipeline, Some(&lut), LutPosition::AfterFilmic);
+  assert!(!close(&after_filmic, &lut_output(&mut pipeline, None, LutPosition::AfterFilmic)));
+  assert!(close(&after_filmic, &lut_output(&mut pipeline, Some(&lut), LutPosition::AfterToLab)));
+  assert!(close(&after_filmic, &lut_output(&mut pipeline, Some(&lut), LutPosition::AfterFromLab)));
+
+  // but before filmic the table gets scene referred values
+  pipeline.ops.filmic.enabled = true;
+  let after_filmic = lut_output(&mut pipeline, Some(&lut), LutPosition::AfterFilmic);
+  assert!(!close(&after_filmic, &lut_output(&mut pipeline, Some(&lut), LutPosition::AfterFromLab)));
+  pipeline.ops.filmic.enabled = false;
+
+  // The position is saved with the op
+  pipeline.ops.lut.position = LutPosition::AfterToLab;
+  let serial = pipeline.to_serial();
+  let source = pipeline.globals.image.clone();
+  assert_eq!(Pipeline::new_from_serial(source, serial).ops.lut.position, LutPosition::AfterToLab);
+}
+
+#[test]
+fn encoded_lut_on_pq() {
+  let image_data: Vec<u8> = (0..16*16*3).map(|v| (v % 256) as u8).collect();
+  let image = ImageBuffer::from_raw(16, 16, image_data).unwrap();
+  let source = ImageSource::Other(DynamicImage::ImageRgb8(image));
+  let mut pipeline = Pipeline::new_from_source(source).unwrap();
+  pipeline.globals.settings.output_encoding = OutputEncoding::Gamma;
+  pipeline.ops.gamma.transfer = TransferFunction::PQ;
+  let before = pipeline.output_float(None).unwrap();
+
+  // After the output encoding the table gets the PQ values as they are
+  let lut = Lut::from_cube_str("LUT_1D_SIZE 2\n1.0 0.8 0.6\n0.0 0.2 0.4\n").unwrap();
+  pipeline.ops.lut.lut = Some(lut.clone());
+  pipeline.ops.lut.position = LutPosition::Encoded;
+  let after = pipeline.output_float(None).unwrap();
+  for (pin, pout) in before.data.chunks_exact(3).zip(after.data.chunks_exact(3)) {
+    let expected = lut.apply(pin, pipeline.ops.lut.interpolation);
+    for c in 0..3 {
+      assert!((pout[c] - expected[c]).abs() < 0.001, "{:?} instead of {:?}", pout, expected);
+    }
+  }
+}
+
+#[test]
+fn parametric_mask() {
+  // Left half dark and right half bright
+  let image_data: Vec<u8> = (0..16*16).flat_map(|i| {
+    let v = if i % 16 < 8 {40} else {200};
+    vec![v, v, v]
+  }).collect();
+  let image = ImageBuffer::from_raw(16, 16, image_data).unwrap();
+  let source = ImageSource::Other(DynamicImage::ImageRgb8(image));
+  let mut pipeline = Pipeline::new_from_source(source).unwrap();
+  let cache = Pipeline::new_cache(100000000);
+
+  pipeline.ops.basecurve.exposure = 1.0;
+  pipeline.ops.basecurve.points = vec![(0.0, 0.0), (1.0, 1.0)];
+  let mask = ParametricMask {
+    lightness: Some(MaskRange::new(0.0, 30.0, 5.0)),
+    ..ParametricMask::default()
+  };
+  pipeline.ops.set_mask("basecurve", Some(mask)).unwrap();
+  assert!(pipeline.ops.set_mask("gamma", Some(mask)).is_err());
+
+  let out = pipeline.output_8bit(Some(&cache)).unwrap();
+  assert!(out.data[0] > 40);
+  assert_eq!(out.data[15*3], 200);
+
+  // Running again from the cache gives the same result and changing only the
+  // mask doesn't reuse the masked buffer
+  assert_eq!(pipeline.output_8bit(Some(&cache)).unwrap(), out);
+  pipeline.ops.set_mask("basecurve", None).unwrap();
+  let out = pipeline.output_8bit(Some(&cache)).unwrap();
+  assert!(out.data[15*3] > 200);
+}
+
+fn masked_serial() -> (ImageSource, String) {
+  let image = ImageBuffer::from_raw(16, 16, vec![128u8; 16*16*3]).unwrap();
+  let source = ImageSource::Other(DynamicImage::ImageRgb8(image));
+  let mut pipeline = Pipeline::new_from_source(source.clone()).unwrap();
+  let mask = ParametricMask {
+    lightness: Some(MaskRange::new(0.0, 30.0, 5.0)),
+    ..ParametricMask::default()
+  };
+  pipeline.ops.set_mask("basecurve", Some(mask)).unwrap();
+  (source, pipeline.to_serial())
+}
+
+#[test]
+fn parametric_mask_serial() {
+  let (source, serial) = masked_serial();
+  let pipeline = Pipeline::new_from_serial(source, serial);
+  assert!(pipeline.ops.mask("basecurve").is_some());
+}
+
+#[test]
+#[should_panic(expected = "op gamma can't be masked")]
+fn parametric_mask_serial_unmaskable() {
+  let (source, serial) = masked_serial();
+  let pos = serial.find("masks:").unwrap();
+  let serial = serial[..pos].to_string() + &serial[pos..].replacen("basecurve:", "gamma:", 1);
+  Pipeline::new_from_serial(source, serial);
+}